@@ -0,0 +1,61 @@
+;; Reference guest: answers every request synchronously with
+;; its own payload, without calling the host.
+(module
+  (import "wapc-host" "instance" (func $host_instance (result i32)))
+  (import "wapc-host" "wapc::on-guest-response" (func $on_guest_response (param i32 i32 i32 i32 i32)))
+  (import "canonical_abi" "resource_new_guest" (func $resource_new (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (global $base i32 (i32.const 1024))
+  (global $heap (mut i32) (i32.const 1024))
+  (global $host (mut i32) (i32.const -1))
+  (global $next_id (mut i32) (i32.const 0))
+
+  ;; Bump allocator, reset once each request has been answered.
+  (func $realloc (export "canonical_abi_realloc") (param i32 i32 i32 i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+    (if (i32.gt_u (global.get $heap) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (if (i32.eq
+              (memory.grow (i32.add (i32.shr_u (local.get 3) (i32.const 16)) (i32.const 1)))
+              (i32.const -1))
+          (then unreachable))))
+    (local.get $ptr))
+
+  (func (export "canonical_abi_drop_guest") (param i32))
+
+  (func (export "instance") (result i32)
+    (call $resource_new (i32.const 1)))
+
+  (func $host (result i32)
+    (if (i32.lt_s (global.get $host) (i32.const 0))
+      (then (global.set $host (call $host_instance))))
+    (global.get $host))
+
+  (func (export "guest::init-guest-request")
+    (param $self i32) (param $op i32) (param $op_len i32) (param $ptr i32) (param $len i32)
+    (param $metadata i32) (param $metadata_len i32)
+    (result i32)
+    (local $id i32)
+    (global.set $next_id (i32.add (global.get $next_id) (i32.const 1)))
+    (local.set $id (global.get $next_id))
+    (call $on_guest_response (call $host) (local.get $id) (i32.const 0) (local.get $ptr) (local.get $len))
+    (global.set $heap (global.get $base))
+    (local.get $id))
+
+  (func (export "guest::on-host-response") (param i32 i32 i32 i32 i32))
+  (func (export "guest::on-host-error") (param i32 i32 i32 i32))
+
+  ;; The host writes arguments into room taken from the heap
+  ;; like any other allocation.
+  (func (export "guest::scratch") (param i32) (result i32)
+    (call $realloc (i32.const 0) (i32.const 0) (i32.const 1) (local.get 0)))
+
+  (func (export "guest::cancel") (param i32 i32))
+  (func (export "guest::stream-chunk") (param i32 i32 i32 i32 i32))
+  (func (export "guest::stream-end") (param i32 i32))
+  (func (export "guest::stream-error") (param i32 i32 i32 i32))
+  (func (export "guest::stream-credit") (param i32 i32 i32 i32))
+)
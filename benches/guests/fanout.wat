@@ -0,0 +1,92 @@
+;; Reference guest: the first byte of each payload is a fan-out
+;; count N. The guest forwards its payload to the host N times
+;; and answers the request once all N host calls have been
+;; answered.
+(module
+  (import "wapc-host" "instance" (func $host_instance (result i32)))
+  (import "wapc-host" "wapc::init-host-request"
+    (func $init_host_request (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "wapc-host" "wapc::on-guest-response" (func $on_guest_response (param i32 i32 i32 i32 i32)))
+  (import "canonical_abi" "resource_new_guest" (func $resource_new (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  ;; binding, namespace and operation of the host calls
+  (data (i32.const 16) "benchnsop")
+  (global $base i32 (i32.const 1024))
+  (global $heap (mut i32) (i32.const 1024))
+  (global $host (mut i32) (i32.const -1))
+  (global $next_id (mut i32) (i32.const 0))
+  (global $current (mut i32) (i32.const 0))
+  (global $pending (mut i32) (i32.const 0))
+
+  (func $realloc (export "canonical_abi_realloc") (param i32 i32 i32 i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+    (if (i32.gt_u (global.get $heap) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (if (i32.eq
+              (memory.grow (i32.add (i32.shr_u (local.get 3) (i32.const 16)) (i32.const 1)))
+              (i32.const -1))
+          (then unreachable))))
+    (local.get $ptr))
+
+  (func (export "canonical_abi_drop_guest") (param i32))
+
+  (func (export "instance") (result i32)
+    (call $resource_new (i32.const 1)))
+
+  (func $host (result i32)
+    (if (i32.lt_s (global.get $host) (i32.const 0))
+      (then (global.set $host (call $host_instance))))
+    (global.get $host))
+
+  (func $finish
+    (call $on_guest_response (call $host) (global.get $current) (i32.const 0) (i32.const 0) (i32.const 0))
+    (global.set $heap (global.get $base)))
+
+  (func (export "guest::init-guest-request")
+    (param $self i32) (param $op i32) (param $op_len i32) (param $ptr i32) (param $len i32)
+    (param $metadata i32) (param $metadata_len i32)
+    (result i32)
+    (local $i i32)
+    (global.set $next_id (i32.add (global.get $next_id) (i32.const 1)))
+    (global.set $current (global.get $next_id))
+    (if (i32.gt_u (local.get $len) (i32.const 0))
+      (then (global.set $pending (i32.load8_u (local.get $ptr)))))
+    (block $done
+      (loop $calls
+        (br_if $done (i32.ge_u (local.get $i) (global.get $pending)))
+        (drop (call $init_host_request (call $host)
+          (i32.const 16) (i32.const 5)
+          (i32.const 21) (i32.const 2)
+          (i32.const 23) (i32.const 2)
+          (local.get $ptr) (local.get $len)
+          (i32.const 0) (i32.const 0)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $calls)))
+    (if (i32.eqz (global.get $pending))
+      (then (call $finish)))
+    (global.get $current))
+
+  (func $answered
+    (global.set $pending (i32.sub (global.get $pending) (i32.const 1)))
+    (if (i32.eqz (global.get $pending))
+      (then (call $finish))))
+
+  (func (export "guest::on-host-response") (param $self i32) (param i32 i32 i32 i32)
+    (call $answered))
+  (func (export "guest::on-host-error") (param $self i32) (param i32 i32 i32)
+    (call $answered))
+
+  ;; The host writes arguments into room taken from the heap
+  ;; like any other allocation.
+  (func (export "guest::scratch") (param i32) (result i32)
+    (call $realloc (i32.const 0) (i32.const 0) (i32.const 1) (local.get 0)))
+
+  (func (export "guest::cancel") (param i32 i32))
+  (func (export "guest::stream-chunk") (param i32 i32 i32 i32 i32))
+  (func (export "guest::stream-end") (param i32 i32))
+  (func (export "guest::stream-error") (param i32 i32 i32 i32))
+  (func (export "guest::stream-credit") (param i32 i32 i32 i32))
+)
@@ -0,0 +1,143 @@
+;; Test guest: relays each invocation to another guest, named by
+;; the first byte of the payload, through a host request for the
+;; `wapc` binding, and answers it with whatever that guest
+;; answers, response code and all.
+;;
+;; `relay` passes the rest of the payload on, and `loop` passes
+;; it on with the target's name moved to the end. An empty
+;; payload is answered with response code 7 and "leaf", and one
+;; starting with a dot is never answered. Every request carries
+;; an empty `wapc-call-chain`, which hosts must not believe, and
+;; every cancellation is logged.
+(module
+  (import "wapc-host" "instance" (func $host_instance (result i32)))
+  (import "wapc-host" "wapc::init-host-request"
+    (func $init_host_request (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "wapc-host" "wapc::on-guest-response" (func $on_guest_response (param i32 i32 i32 i32 i32)))
+  (import "wapc-host" "wapc::on-guest-error" (func $on_guest_error (param i32 i32 i32 i32)))
+  (import "wapc-host" "wapc::console-log" (func $console_log (param i32 i32 i32)))
+  (import "wapc-host" "wapc::cancel" (func $cancel (param i32 i32)))
+  (import "canonical_abi" "resource_new_guest" (func $resource_new (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "wapc")
+  (data (i32.const 8) "wapc-call-chain")
+  (data (i32.const 32) "cancelled")
+  ;; the metadata of every request: ("wapc-call-chain", "")
+  (data (i32.const 48) "\08\00\00\00\0f\00\00\00\00\00\00\00\00\00\00\00")
+  (data (i32.const 64) "leaf")
+  ;; (request, invocation) pairs of the requests made
+  (global $pairs i32 (i32.const 256))
+  (global $count (mut i32) (i32.const 0))
+  (global $heap (mut i32) (i32.const 1024))
+  (global $host (mut i32) (i32.const -1))
+  (global $next_id (mut i32) (i32.const 0))
+
+  (func $realloc (export "canonical_abi_realloc") (param i32 i32 i32 i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+    (local.get $ptr))
+
+  (func (export "canonical_abi_drop_guest") (param i32))
+
+  (func (export "instance") (result i32)
+    (call $resource_new (i32.const 1)))
+
+  (func $host (result i32)
+    (if (i32.lt_s (global.get $host) (i32.const 0))
+      (then (global.set $host (call $host_instance))))
+    (global.get $host))
+
+  (func $remember (param $request i32) (param $invocation i32)
+    (local $at i32)
+    (local.set $at (i32.add (global.get $pairs) (i32.mul (global.get $count) (i32.const 8))))
+    (i32.store (local.get $at) (local.get $request))
+    (i32.store offset=4 (local.get $at) (local.get $invocation))
+    (global.set $count (i32.add (global.get $count) (i32.const 1))))
+
+  ;; The pair whose request (at $field 0) or invocation (at
+  ;; $field 4) is $id, or 0.
+  (func $find (param $field i32) (param $id i32) (result i32)
+    (local $at i32)
+    (local $end i32)
+    (local.set $at (global.get $pairs))
+    (local.set $end (i32.add (global.get $pairs) (i32.mul (global.get $count) (i32.const 8))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $at) (local.get $end)))
+        (if (i32.eq (i32.load (i32.add (local.get $at) (local.get $field))) (local.get $id))
+          (then (return (local.get $at))))
+        (local.set $at (i32.add (local.get $at) (i32.const 8)))
+        (br $next)))
+    (i32.const 0))
+
+  (func (export "guest::init-guest-request")
+    (param $self i32) (param $op i32) (param $op_len i32) (param $ptr i32) (param $len i32)
+    (param $metadata i32) (param $metadata_len i32)
+    (result i32)
+    (local $id i32)
+    (local $rest i32)
+    (local $out i32)
+    (local $out_len i32)
+    (global.set $next_id (i32.add (global.get $next_id) (i32.const 1)))
+    (local.set $id (global.get $next_id))
+    (if (i32.eqz (local.get $len))
+      (then
+        (call $on_guest_response (call $host) (local.get $id) (i32.const 7) (i32.const 64) (i32.const 4))
+        (return (local.get $id))))
+    (if (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 46))
+      (then (return (local.get $id))))
+    (local.set $rest (i32.sub (local.get $len) (i32.const 1)))
+    (local.set $out_len (local.get $rest))
+    (if (i32.eq (local.get $op_len) (i32.const 4))
+      (then (local.set $out_len (local.get $len))))
+    (local.set $out (call $realloc (i32.const 0) (i32.const 0) (i32.const 1) (local.get $out_len)))
+    (memory.copy (local.get $out) (i32.add (local.get $ptr) (i32.const 1)) (local.get $rest))
+    (if (i32.eq (local.get $op_len) (i32.const 4))
+      (then
+        (i32.store8 (i32.add (local.get $out) (local.get $rest)) (i32.load8_u (local.get $ptr)))))
+    (call $remember
+      (call $init_host_request (call $host)
+        (i32.const 0) (i32.const 4)
+        (local.get $ptr) (i32.const 1)
+        (local.get $op) (local.get $op_len)
+        (local.get $out) (local.get $out_len)
+        (i32.const 48) (i32.const 1))
+      (local.get $id))
+    (local.get $id))
+
+  (func (export "guest::on-host-response")
+    (param $self i32) (param $request i32) (param $code i32) (param $ptr i32) (param $len i32)
+    (local $at i32)
+    (local.set $at (call $find (i32.const 0) (local.get $request)))
+    (if (local.get $at)
+      (then
+        (call $on_guest_response (call $host) (i32.load offset=4 (local.get $at))
+          (local.get $code) (local.get $ptr) (local.get $len)))))
+
+  (func (export "guest::on-host-error")
+    (param $self i32) (param $request i32) (param $ptr i32) (param $len i32)
+    (local $at i32)
+    (local.set $at (call $find (i32.const 0) (local.get $request)))
+    (if (local.get $at)
+      (then
+        (call $on_guest_error (call $host) (i32.load offset=4 (local.get $at))
+          (local.get $ptr) (local.get $len)))))
+
+  (func (export "guest::scratch") (param i32) (result i32)
+    (call $realloc (i32.const 0) (i32.const 0) (i32.const 1) (local.get 0)))
+
+  ;; Cancels the request made for the invocation, if any.
+  (func (export "guest::cancel") (param $self i32) (param $id i32)
+    (local $at i32)
+    (call $console_log (call $host) (i32.const 32) (i32.const 9))
+    (local.set $at (call $find (i32.const 4) (local.get $id)))
+    (if (local.get $at)
+      (then (call $cancel (call $host) (i32.load (local.get $at))))))
+
+  (func (export "guest::stream-chunk") (param i32 i32 i32 i32 i32))
+  (func (export "guest::stream-end") (param i32 i32))
+  (func (export "guest::stream-error") (param i32 i32 i32 i32))
+  (func (export "guest::stream-credit") (param i32 i32 i32 i32))
+)
@@ -0,0 +1,81 @@
+;; Test guest: answers every invocation with a stream of five
+;; one-byte chunks, "a" to "e", sent as the host grants credit,
+;; and then an empty response.
+;;
+;; The payload's length picks how well it keeps to its credit:
+;; with none it sends a chunk right away, with two it sends one
+;; chunk more than each grant allows, and with any other it
+;; keeps to its credit.
+(module
+  (import "wapc-host" "instance" (func $host_instance (result i32)))
+  (import "wapc-host" "wapc::on-guest-response" (func $on_guest_response (param i32 i32 i32 i32 i32)))
+  (import "wapc-host" "wapc::stream-chunk" (func $stream_chunk (param i32 i32 i32 i32 i32)))
+  (import "canonical_abi" "resource_new_guest" (func $resource_new (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "abcde")
+  (global $base i32 (i32.const 1024))
+  (global $heap (mut i32) (i32.const 1024))
+  (global $host (mut i32) (i32.const -1))
+  (global $next_id (mut i32) (i32.const 0))
+  (global $greedy (mut i32) (i32.const 0))
+  (global $sent (mut i32) (i32.const 0))
+
+  (func $realloc (export "canonical_abi_realloc") (param i32 i32 i32 i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+    (local.get $ptr))
+
+  (func (export "canonical_abi_drop_guest") (param i32))
+
+  (func (export "instance") (result i32)
+    (call $resource_new (i32.const 1)))
+
+  (func $host (result i32)
+    (if (i32.lt_s (global.get $host) (i32.const 0))
+      (then (global.set $host (call $host_instance))))
+    (global.get $host))
+
+  ;; Sends the next chunk of the answer to invocation $id.
+  (func $send (param $id i32)
+    (call $stream_chunk (call $host) (local.get $id) (i32.const 1) (global.get $sent) (i32.const 1))
+    (global.set $sent (i32.add (global.get $sent) (i32.const 1))))
+
+  (func (export "guest::init-guest-request")
+    (param $self i32) (param $op i32) (param $op_len i32) (param $ptr i32) (param $len i32)
+    (param $metadata i32) (param $metadata_len i32)
+    (result i32)
+    (global.set $next_id (i32.add (global.get $next_id) (i32.const 1)))
+    (global.set $greedy (i32.eq (local.get $len) (i32.const 2)))
+    (global.set $sent (i32.const 0))
+    (global.set $heap (global.get $base))
+    (if (i32.eqz (local.get $len))
+      (then (call $send (global.get $next_id))))
+    (global.get $next_id))
+
+  (func (export "guest::on-host-response") (param i32 i32 i32 i32 i32))
+  (func (export "guest::on-host-error") (param i32 i32 i32 i32))
+
+  (func (export "guest::scratch") (param i32) (result i32)
+    (call $realloc (i32.const 0) (i32.const 0) (i32.const 1) (local.get 0)))
+
+  (func (export "guest::cancel") (param i32 i32))
+  (func (export "guest::stream-chunk") (param i32 i32 i32 i32 i32))
+  (func (export "guest::stream-end") (param i32 i32))
+  (func (export "guest::stream-error") (param i32 i32 i32 i32))
+
+  (func (export "guest::stream-credit")
+    (param $self i32) (param $id i32) (param $kind i32) (param $chunks i32)
+    (local.set $chunks (i32.add (local.get $chunks) (global.get $greedy)))
+    (block $done
+      (loop $more
+        (br_if $done (i32.eqz (local.get $chunks)))
+        (if (i32.ge_u (global.get $sent) (i32.const 5))
+          (then
+            (call $on_guest_response (call $host) (local.get $id) (i32.const 0) (i32.const 0) (i32.const 0))
+            (return)))
+        (call $send (local.get $id))
+        (local.set $chunks (i32.sub (local.get $chunks) (i32.const 1)))
+        (br $more))))
+)
@@ -0,0 +1,80 @@
+//! Round-trip cost of invoking a guest operation and receiving
+//! its response, from `wapc_init_guest_request` through the
+//! `on-guest-response` callback.
+//!
+//! The reference guests live in `benches/guests` so results are
+//! comparable across releases: `echo` answers synchronously with
+//! its payload, `fanout` makes N host calls before answering.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use wapc_runtime::pool::Pool;
+use wapc_runtime::runtime::Instance;
+use wapc_runtime::stub::{self, Answer, StubHost};
+use wit_bindgen_wasmtime::wasmtime;
+
+const ECHO: &str = include_str!("guests/echo.wat");
+const FANOUT: &str = include_str!("guests/fanout.wat");
+
+fn instance(engine: &wasmtime::Engine, wat: &str) -> Instance<StubHost> {
+    let module = wasmtime::Module::new(engine, wat).unwrap();
+    Instance::new(&module, StubHost::new().on_log(|_| {})).unwrap()
+}
+
+fn empty_response(_: &stub::HostRequest) -> Answer {
+    Answer::Response {
+        code: 0,
+        bytes: Vec::new(),
+    }
+}
+
+fn payload_size(c: &mut Criterion) {
+    let engine = wasmtime::Engine::default();
+    let mut instance = instance(&engine, ECHO);
+    let mut group = c.benchmark_group("round_trip");
+    for size in [0, 64, 1024, 16 * 1024, 256 * 1024] {
+        let payload = vec![0xa5; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("payload", size), &payload, |b, payload| {
+            b.iter(|| stub::call(&mut instance, "echo", payload, empty_response).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn host_fanout(c: &mut Criterion) {
+    let engine = wasmtime::Engine::default();
+    let mut instance = instance(&engine, FANOUT);
+    let mut group = c.benchmark_group("host_fanout");
+    for calls in [0u8, 1, 4, 16] {
+        let mut payload = vec![0; 64];
+        payload[0] = calls;
+        group.bench_with_input(BenchmarkId::from_parameter(calls), &payload, |b, payload| {
+            b.iter(|| stub::call(&mut instance, "fanout", payload, empty_response).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Spreads calls round-robin over a pool of instances, which
+/// shows how much of the cost comes from cold instance state.
+fn pool_size(c: &mut Criterion) {
+    let engine = wasmtime::Engine::default();
+    let payload = vec![0xa5; 64];
+    let mut group = c.benchmark_group("pool");
+    for size in [1, 4, 16] {
+        let mut pool = Pool::new();
+        for _ in 0..size {
+            pool.push(instance(&engine, ECHO));
+        }
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                let instance = pool.next_instance().unwrap();
+                stub::call(instance, "echo", &payload, empty_response).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, payload_size, host_fanout, pool_size);
+criterion_main!(benches);
@@ -0,0 +1,222 @@
+//! Drives the host side of the ABI with arbitrary call sequences.
+//!
+//! A hand-written guest re-exports every `wapc-host` and
+//! `canonical_abi` import through a single `fuzz-call` export, so
+//! the fuzzer controls every pointer, length and handle the host
+//! closures linked by `Instance` see, the generated ones and
+//! those the runtime replaces them with. The guest's destructor can be
+//! made to re-enter the host, and host-to-guest calls are
+//! interleaved with the rest.
+//!
+//! Any outcome other than `Ok` or a trap is a bug: panics abort
+//! the target, and a final well-formed `console-log` call checks
+//! that the host is still in a usable state and that a normal
+//! invocation leaves the live handle counts unchanged.
+#![no_main]
+
+use std::sync::{Arc, Mutex};
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use wapc_runtime::runtime::Instance;
+use wapc_runtime::stub::StubHost;
+use wapc_runtime::wapc_host::StreamKind;
+use wit_bindgen_wasmtime::wasmtime;
+
+const GUEST: &str = r#"
+(module
+  (import "wapc-host" "instance" (func $host_instance (result i32)))
+  (import "wapc-host" "wapc::init-host-request"
+    (func $init_host_request (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "wapc-host" "wapc::on-guest-response" (func $on_guest_response (param i32 i32 i32 i32 i32)))
+  (import "wapc-host" "wapc::on-guest-error" (func $on_guest_error (param i32 i32 i32 i32)))
+  (import "wapc-host" "wapc::console-log" (func $console_log (param i32 i32 i32)))
+  (import "wapc-host" "wapc::cancel" (func $cancel (param i32 i32)))
+  (import "wapc-host" "wapc::stream-chunk" (func $stream_chunk (param i32 i32 i32 i32 i32)))
+  (import "wapc-host" "wapc::stream-end" (func $stream_end (param i32 i32)))
+  (import "wapc-host" "wapc::stream-error" (func $stream_error (param i32 i32 i32 i32)))
+  (import "wapc-host" "wapc::stream-credit" (func $stream_credit (param i32 i32 i32 i32)))
+  (import "canonical_abi" "resource_drop_wapc" (func $host_drop (param i32)))
+  (import "canonical_abi" "resource_drop_guest" (func $resource_drop (param i32)))
+  (import "canonical_abi" "resource_clone_guest" (func $resource_clone (param i32) (result i32)))
+  (import "canonical_abi" "resource_get_guest" (func $resource_get (param i32) (result i32)))
+  (import "canonical_abi" "resource_new_guest" (func $resource_new (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 16) "fuzz")
+  (global $heap (mut i32) (i32.const 1024))
+  (global $reenter (mut i32) (i32.const -1))
+
+  (func $realloc (export "canonical_abi_realloc") (param i32 i32 i32 i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+    (if (i32.gt_u (global.get $heap) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (if (i32.eq
+              (memory.grow (i32.add (i32.shr_u (local.get 3) (i32.const 16)) (i32.const 1)))
+              (i32.const -1))
+          (then unreachable))))
+    (local.get $ptr))
+
+  (func (export "canonical_abi_drop_guest") (param $val i32)
+    (local $op i32)
+    (local.set $op (global.get $reenter))
+    (if (i32.ge_s (local.get $op) (i32.const 0))
+      (then
+        (global.set $reenter (i32.const -1))
+        (drop (call $call (local.get $op) (local.get $val)
+          (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)
+          (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)
+          (i32.const 0) (i32.const 0))))))
+
+  (func (export "instance") (result i32)
+    (call $resource_new (i32.const 1)))
+
+  (func (export "guest::init-guest-request") (param i32 i32 i32 i32 i32 i32 i32) (result i32)
+    (i32.const 1))
+  (func (export "guest::on-host-response") (param i32 i32 i32 i32 i32))
+  (func (export "guest::on-host-error") (param i32 i32 i32 i32))
+  (func (export "guest::cancel") (param i32 i32))
+  (func (export "guest::scratch") (param i32) (result i32)
+    (call $realloc (i32.const 0) (i32.const 0) (i32.const 1) (local.get 0)))
+  (func (export "guest::stream-chunk") (param i32 i32 i32 i32 i32))
+  (func (export "guest::stream-end") (param i32 i32))
+  (func (export "guest::stream-error") (param i32 i32 i32 i32))
+  (func (export "guest::stream-credit") (param i32 i32 i32 i32))
+
+  (func $call (export "fuzz-call")
+    (param $op i32)
+    (param $a0 i32) (param $a1 i32) (param $a2 i32) (param $a3 i32) (param $a4 i32)
+    (param $a5 i32) (param $a6 i32) (param $a7 i32) (param $a8 i32) (param $a9 i32) (param $a10 i32)
+    (result i32)
+    (if (i32.eq (local.get $op) (i32.const 0))
+      (then (return (call $host_instance))))
+    (if (i32.eq (local.get $op) (i32.const 1))
+      (then (return (call $init_host_request
+        (local.get $a0) (local.get $a1) (local.get $a2) (local.get $a3) (local.get $a4)
+        (local.get $a5) (local.get $a6) (local.get $a7) (local.get $a8) (local.get $a9)
+        (local.get $a10)))))
+    (if (i32.eq (local.get $op) (i32.const 2))
+      (then (call $on_guest_response
+        (local.get $a0) (local.get $a1) (local.get $a2) (local.get $a3) (local.get $a4))))
+    (if (i32.eq (local.get $op) (i32.const 3))
+      (then (call $on_guest_error
+        (local.get $a0) (local.get $a1) (local.get $a2) (local.get $a3))))
+    (if (i32.eq (local.get $op) (i32.const 4))
+      (then (call $console_log (local.get $a0) (local.get $a1) (local.get $a2))))
+    (if (i32.eq (local.get $op) (i32.const 5))
+      (then (call $resource_drop (local.get $a0))))
+    (if (i32.eq (local.get $op) (i32.const 6))
+      (then (return (call $resource_clone (local.get $a0)))))
+    (if (i32.eq (local.get $op) (i32.const 7))
+      (then (return (call $resource_get (local.get $a0)))))
+    (if (i32.eq (local.get $op) (i32.const 8))
+      (then (return (call $resource_new (local.get $a0)))))
+    (if (i32.eq (local.get $op) (i32.const 9))
+      (then (global.set $reenter (local.get $a0))))
+    (if (i32.eq (local.get $op) (i32.const 10))
+      (then (call $cancel (local.get $a0) (local.get $a1))))
+    (if (i32.eq (local.get $op) (i32.const 11))
+      (then (call $stream_chunk
+        (local.get $a0) (local.get $a1) (local.get $a2) (local.get $a3) (local.get $a4))))
+    (if (i32.eq (local.get $op) (i32.const 12))
+      (then (call $stream_end (local.get $a0) (local.get $a1))))
+    (if (i32.eq (local.get $op) (i32.const 13))
+      (then (call $stream_error
+        (local.get $a0) (local.get $a1) (local.get $a2) (local.get $a3))))
+    (if (i32.eq (local.get $op) (i32.const 14))
+      (then (call $stream_credit
+        (local.get $a0) (local.get $a1) (local.get $a2) (local.get $a3))))
+    (if (i32.eq (local.get $op) (i32.const 15))
+      (then (call $host_drop (local.get $a0))))
+    (i32.const 0))
+)
+"#;
+
+#[derive(Arbitrary, Debug)]
+enum Action {
+    /// Calls an import through `fuzz-call` with raw arguments.
+    Guest { op: u8, args: [i32; 11] },
+    Invoke { operation: String, payload: Vec<u8>, metadata: Vec<(String, String)> },
+    Respond { id: u32, code: u32, payload: Vec<u8> },
+    Fail { id: u32, payload: Vec<u8> },
+    Cancel { id: u32 },
+    SendChunk { id: u32, response: bool, payload: Vec<u8> },
+    EndStream { id: u32 },
+    FailStream { id: u32, payload: Vec<u8> },
+    Grant { id: u32, response: bool, chunks: u32 },
+}
+
+fn kind(response: bool) -> StreamKind {
+    if response {
+        StreamKind::Response
+    } else {
+        StreamKind::Request
+    }
+}
+
+type FuzzCall =
+    wasmtime::TypedFunc<(i32, i32, i32, i32, i32, i32, i32, i32, i32, i32, i32, i32), i32>;
+
+fuzz_target!(|actions: Vec<Action>| {
+    let engine = wasmtime::Engine::default();
+    let module = wasmtime::Module::new(&engine, GUEST).unwrap();
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let sink = logs.clone();
+    let host = StubHost::new().on_log(move |m| sink.lock().unwrap().push(m.to_string()));
+    let mut instance = Instance::new(&module, host).unwrap();
+    let call: FuzzCall = instance
+        .instance()
+        .clone()
+        .get_typed_func(instance.store_mut(), "fuzz-call")
+        .unwrap();
+
+    for action in actions {
+        // Traps are the expected outcome for malformed calls, so
+        // only their absence of panics matters here.
+        match action {
+            Action::Guest { op, args } => {
+                let [a0, a1, a2, a3, a4, a5, a6, a7, a8, a9, a10] = args;
+                let params = (i32::from(op % 16), a0, a1, a2, a3, a4, a5, a6, a7, a8, a9, a10);
+                let _ = call.call(instance.store_mut(), params);
+            }
+            Action::Invoke { operation, payload, metadata } => {
+                let _ = instance.invoke_with(&operation, &payload, &metadata);
+            }
+            Action::Respond { id, code, payload } => {
+                let _ = instance.respond(id, code, &payload);
+            }
+            Action::Fail { id, payload } => {
+                let _ = instance.fail(id, &payload);
+            }
+            Action::Cancel { id } => {
+                let _ = instance.cancel(id);
+            }
+            Action::SendChunk { id, response, payload } => {
+                let _ = instance.send_chunk(id, kind(response), &payload);
+            }
+            Action::EndStream { id } => {
+                let _ = instance.end_stream(id);
+            }
+            Action::FailStream { id, payload } => {
+                let _ = instance.fail_stream(id, &payload);
+            }
+            Action::Grant { id, response, chunks } => {
+                let _ = instance.grant(id, kind(response), chunks);
+            }
+        }
+    }
+
+    // Whatever happened above, well-formed calls must still work
+    // and must not leak handles.
+    let counts = instance.handle_counts();
+    let _ = instance.invoke("ping", b"");
+    assert_eq!(instance.handle_counts(), counts);
+    let handle = call
+        .call(instance.store_mut(), (0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0))
+        .expect("host handle after fuzzing");
+    call.call(instance.store_mut(), (4, handle, 16, 4, 0, 0, 0, 0, 0, 0, 0, 0))
+        .expect("console-log after fuzzing");
+    assert_eq!(logs.lock().unwrap().last().map(|s| s.as_str()), Some("fuzz"));
+});
@@ -0,0 +1,26 @@
+//! Feeds arbitrary, structurally valid modules through
+//! validation, instantiation and a first invocation.
+//!
+//! Most generated modules are rejected by `validate`, which is
+//! the point: rejection must be an error, never a panic, and the
+//! rare module which passes must only ever trap.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wapc_runtime::runtime::Instance;
+use wapc_runtime::stub::StubHost;
+use wit_bindgen_wasmtime::wasmtime;
+
+fuzz_target!(|input: (wasm_smith::Module, Vec<u8>)| {
+    let (module, payload) = input;
+    let engine = wasmtime::Engine::default();
+    let module = match wasmtime::Module::new(&engine, module.to_bytes()) {
+        Ok(module) => module,
+        Err(_) => return,
+    };
+    let mut instance = match Instance::new(&module, StubHost::new().on_log(|_| {})) {
+        Ok(instance) => instance,
+        Err(_) => return,
+    };
+    let _ = instance.invoke("fuzz", &payload);
+});
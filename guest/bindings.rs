@@ -1,5 +1,23 @@
 #[allow(clippy::all)]
 mod wapc_host {
+  #[repr(u8)]
+  #[derive(Clone, Copy, PartialEq, Eq)]
+  pub enum StreamKind {
+    Request,
+    Response,
+  }
+  impl core::fmt::Debug for StreamKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+      match self {
+        StreamKind::Request => {
+          f.debug_tuple("StreamKind::Request").finish()
+        }
+        StreamKind::Response => {
+          f.debug_tuple("StreamKind::Response").finish()
+        }
+      }
+    }
+  }
   #[derive(Debug)]
   #[repr(transparent)]
   pub struct Wapc(i32);
@@ -43,7 +61,7 @@ mod wapc_host {
     }
   }
   impl Wapc {
-    pub fn init_host_request(&self,binding: & str,namespace: & str,operation: & str,bytes: &[u8],) -> u32{
+    pub fn init_host_request(&self,binding: & str,namespace: & str,operation: & str,bytes: &[u8],metadata: &[(& str,& str,)],) -> u32{
       unsafe {
         let vec0 = binding;
         let ptr0 = vec0.as_ptr() as i32;
@@ -57,189 +75,183 @@ mod wapc_host {
         let vec3 = bytes;
         let ptr3 = vec3.as_ptr() as i32;
         let len3 = vec3.len() as i32;
-        #[link(wasm_import_module = "wapc-host")]
-        extern "C" {
-          #[cfg_attr(target_arch = "wasm32", link_name = "wapc::init-host-request")]
-          #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::init-host-request")]
-          fn wit_import(_: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32, ) -> i32;
+        let vec7 = metadata;
+        let len7 = vec7.len() as i32;
+        let layout7 = core::alloc::Layout::from_size_align_unchecked(vec7.len() * 16, 4);
+        let result7 = std::alloc::alloc(layout7);
+        if result7.is_null() { std::alloc::handle_alloc_error(layout7); }
+        for (i, e) in vec7.into_iter().enumerate() {
+          let base = result7 as i32 + (i as i32) * 16;
+          {
+            let (t4_0, t4_1, ) = e;
+            let vec5 = t4_0;
+            let ptr5 = vec5.as_ptr() as i32;
+            let len5 = vec5.len() as i32;
+            *((base + 4) as *mut i32) = len5;
+            *((base + 0) as *mut i32) = ptr5;
+            let vec6 = t4_1;
+            let ptr6 = vec6.as_ptr() as i32;
+            let len6 = vec6.len() as i32;
+            *((base + 12) as *mut i32) = len6;
+            *((base + 8) as *mut i32) = ptr6;
+            
+          }}
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::init-host-request")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::init-host-request")]
+            fn wit_import(_: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32, ) -> i32;
+          }
+          let ret = wit_import(self.0, ptr0, len0, ptr1, len1, ptr2, len2, ptr3, len3, result7 as i32, len7);
+          std::alloc::dealloc(result7, layout7);
+          ret as u32
         }
-        let ret = wit_import(self.0, ptr0, len0, ptr1, len1, ptr2, len2, ptr3, len3);
-        ret as u32
       }
     }
-  }
-  impl Wapc {
-    pub fn on_guest_response(&self,id: u32,bytes: &[u8],) -> (){
-      unsafe {
-        let vec0 = bytes;
-        let ptr0 = vec0.as_ptr() as i32;
-        let len0 = vec0.len() as i32;
-        #[link(wasm_import_module = "wapc-host")]
-        extern "C" {
-          #[cfg_attr(target_arch = "wasm32", link_name = "wapc::on-guest-response")]
-          #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::on-guest-response")]
-          fn wit_import(_: i32, _: i32, _: i32, _: i32, );
+    impl Wapc {
+      pub fn on_guest_response(&self,id: u32,code: u32,bytes: &[u8],) -> (){
+        unsafe {
+          let vec0 = bytes;
+          let ptr0 = vec0.as_ptr() as i32;
+          let len0 = vec0.len() as i32;
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::on-guest-response")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::on-guest-response")]
+            fn wit_import(_: i32, _: i32, _: i32, _: i32, _: i32, );
+          }
+          wit_import(self.0, wit_bindgen_rust::rt::as_i32(id), wit_bindgen_rust::rt::as_i32(code), ptr0, len0);
+          ()
         }
-        wit_import(self.0, wit_bindgen_rust::rt::as_i32(id), ptr0, len0);
-        ()
       }
     }
-  }
-  impl Wapc {
-    pub fn on_guest_error(&self,id: u32,bytes: &[u8],) -> (){
-      unsafe {
-        let vec0 = bytes;
-        let ptr0 = vec0.as_ptr() as i32;
-        let len0 = vec0.len() as i32;
-        #[link(wasm_import_module = "wapc-host")]
-        extern "C" {
-          #[cfg_attr(target_arch = "wasm32", link_name = "wapc::on-guest-error")]
-          #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::on-guest-error")]
-          fn wit_import(_: i32, _: i32, _: i32, _: i32, );
+    impl Wapc {
+      pub fn on_guest_error(&self,id: u32,bytes: &[u8],) -> (){
+        unsafe {
+          let vec0 = bytes;
+          let ptr0 = vec0.as_ptr() as i32;
+          let len0 = vec0.len() as i32;
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::on-guest-error")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::on-guest-error")]
+            fn wit_import(_: i32, _: i32, _: i32, _: i32, );
+          }
+          wit_import(self.0, wit_bindgen_rust::rt::as_i32(id), ptr0, len0);
+          ()
         }
-        wit_import(self.0, wit_bindgen_rust::rt::as_i32(id), ptr0, len0);
-        ()
       }
     }
-  }
-  impl Wapc {
-    pub fn console_log(&self,message: & str,) -> (){
-      unsafe {
-        let vec0 = message;
-        let ptr0 = vec0.as_ptr() as i32;
-        let len0 = vec0.len() as i32;
-        #[link(wasm_import_module = "wapc-host")]
-        extern "C" {
-          #[cfg_attr(target_arch = "wasm32", link_name = "wapc::console-log")]
-          #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::console-log")]
-          fn wit_import(_: i32, _: i32, _: i32, );
+    impl Wapc {
+      pub fn console_log(&self,message: & str,) -> (){
+        unsafe {
+          let vec0 = message;
+          let ptr0 = vec0.as_ptr() as i32;
+          let len0 = vec0.len() as i32;
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::console-log")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::console-log")]
+            fn wit_import(_: i32, _: i32, _: i32, );
+          }
+          wit_import(self.0, ptr0, len0);
+          ()
         }
-        wit_import(self.0, ptr0, len0);
-        ()
       }
     }
-  }
-}
-#[allow(clippy::all)]
-mod wapc_guest {
-  
-  unsafe impl wit_bindgen_rust::HandleType for super::Wapc {
-    #[inline]
-    fn clone(_val: i32) -> i32 {
-      
-      #[cfg(not(target_arch = "wasm32"))]
-      {
-        panic!("handles can only be used on wasm32");
-      }
-      #[cfg(target_arch = "wasm32")]
-      
-      {
-        #[link(wasm_import_module = "canonical_abi")]
-        extern "C" {
-          #[link_name = "resource_clone_wapc"]
-          fn clone(val: i32) -> i32;
+    impl Wapc {
+      pub fn cancel(&self,id: u32,) -> (){
+        unsafe {
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::cancel")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::cancel")]
+            fn wit_import(_: i32, _: i32, );
+          }
+          wit_import(self.0, wit_bindgen_rust::rt::as_i32(id));
+          ()
         }
-        unsafe { clone(_val) }
       }
     }
-    
-    #[inline]
-    fn drop(_val: i32) {
-      
-      #[cfg(not(target_arch = "wasm32"))]
-      {
-        panic!("handles can only be used on wasm32");
-      }
-      #[cfg(target_arch = "wasm32")]
-      
-      {
-        #[link(wasm_import_module = "canonical_abi")]
-        extern "C" {
-          #[link_name = "resource_drop_wapc"]
-          fn drop(val: i32);
+    impl Wapc {
+      pub fn stream_chunk(&self,id: u32,kind: StreamKind,bytes: &[u8],) -> (){
+        unsafe {
+          let vec0 = bytes;
+          let ptr0 = vec0.as_ptr() as i32;
+          let len0 = vec0.len() as i32;
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::stream-chunk")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::stream-chunk")]
+            fn wit_import(_: i32, _: i32, _: i32, _: i32, _: i32, );
+          }
+          wit_import(self.0, wit_bindgen_rust::rt::as_i32(id), match kind {
+            StreamKind::Request => 0,
+            StreamKind::Response => 1,
+          }, ptr0, len0);
+          ()
         }
-        unsafe { drop(_val) }
       }
     }
-  }
-  
-  unsafe impl wit_bindgen_rust::LocalHandle for super::Wapc {
-    #[inline]
-    fn new(_val: i32) -> i32 {
-      
-      #[cfg(not(target_arch = "wasm32"))]
-      {
-        panic!("handles can only be used on wasm32");
+    impl Wapc {
+      pub fn stream_end(&self,id: u32,) -> (){
+        unsafe {
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::stream-end")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::stream-end")]
+            fn wit_import(_: i32, _: i32, );
+          }
+          wit_import(self.0, wit_bindgen_rust::rt::as_i32(id));
+          ()
+        }
       }
-      #[cfg(target_arch = "wasm32")]
-      
-      {
-        #[link(wasm_import_module = "canonical_abi")]
-        extern "C" {
-          #[link_name = "resource_new_wapc"]
-          fn new(val: i32) -> i32;
+    }
+    impl Wapc {
+      pub fn stream_error(&self,id: u32,bytes: &[u8],) -> (){
+        unsafe {
+          let vec0 = bytes;
+          let ptr0 = vec0.as_ptr() as i32;
+          let len0 = vec0.len() as i32;
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::stream-error")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::stream-error")]
+            fn wit_import(_: i32, _: i32, _: i32, _: i32, );
+          }
+          wit_import(self.0, wit_bindgen_rust::rt::as_i32(id), ptr0, len0);
+          ()
         }
-        unsafe { new(_val) }
       }
     }
-    
-    #[inline]
-    fn get(_val: i32) -> i32 {
-      
-      #[cfg(not(target_arch = "wasm32"))]
-      {
-        panic!("handles can only be used on wasm32");
+    impl Wapc {
+      pub fn stream_credit(&self,id: u32,kind: StreamKind,chunks: u32,) -> (){
+        unsafe {
+          #[link(wasm_import_module = "wapc-host")]
+          extern "C" {
+            #[cfg_attr(target_arch = "wasm32", link_name = "wapc::stream-credit")]
+            #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_wapc::stream-credit")]
+            fn wit_import(_: i32, _: i32, _: i32, _: i32, );
+          }
+          wit_import(self.0, wit_bindgen_rust::rt::as_i32(id), match kind {
+            StreamKind::Request => 0,
+            StreamKind::Response => 1,
+          }, wit_bindgen_rust::rt::as_i32(chunks));
+          ()
+        }
       }
-      #[cfg(target_arch = "wasm32")]
-      
-      {
-        #[link(wasm_import_module = "canonical_abi")]
+    }
+    pub fn instance() -> Wapc{
+      unsafe {
+        #[link(wasm_import_module = "wapc-host")]
         extern "C" {
-          #[link_name = "resource_get_wapc"]
-          fn get(val: i32) -> i32;
+          #[cfg_attr(target_arch = "wasm32", link_name = "instance")]
+          #[cfg_attr(not(target_arch = "wasm32"), link_name = "wapc-host_instance")]
+          fn wit_import() -> i32;
         }
-        unsafe { get(_val) }
+        let ret = wit_import();
+        Wapc(ret)
       }
     }
   }
-  
-  const _: () = {
-    #[export_name = "canonical_abi_drop_wapc"]
-    extern "C" fn drop(ty: Box<super::Wapc>) {
-      <super::WapcGuest as WapcGuest>::drop_wapc(*ty)
-    }
-  };
-  #[export_name = "wapc::init-guest-request"]
-  unsafe extern "C" fn __wit_bindgen_wapc_guest_wapc_init_guest_request(arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32, ) -> i32{
-    let len0 = arg2 as usize;
-    let len1 = arg4 as usize;
-    let result = <super::Wapc as Wapc>::init_guest_request(&wit_bindgen_rust::Handle::from_raw(arg0), String::from_utf8(Vec::from_raw_parts(arg1 as *mut _, len0, len0)).unwrap(), Vec::from_raw_parts(arg3 as *mut _, len1, len1));
-    wit_bindgen_rust::rt::as_i32(result)
-  }
-  #[export_name = "wapc::on-host-response"]
-  unsafe extern "C" fn __wit_bindgen_wapc_guest_wapc_on_host_response(arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32, ){
-    let len0 = arg4 as usize;
-    let result = <super::Wapc as Wapc>::on_host_response(&wit_bindgen_rust::Handle::from_raw(arg0), arg1 as u32, arg2 as u32, Vec::from_raw_parts(arg3 as *mut _, len0, len0));
-    let () = result;
-  }
-  #[export_name = "wapc::on-host-error"]
-  unsafe extern "C" fn __wit_bindgen_wapc_guest_wapc_on_host_error(arg0: i32, arg1: i32, arg2: i32, arg3: i32, ){
-    let len0 = arg3 as usize;
-    let result = <super::Wapc as Wapc>::on_host_error(&wit_bindgen_rust::Handle::from_raw(arg0), arg1 as u32, Vec::from_raw_parts(arg2 as *mut _, len0, len0));
-    let () = result;
-  }
-  pub trait WapcGuest {
-    
-    /// An optional callback invoked when a handle is finalized
-    /// and destroyed.
-    fn drop_wapc(val: super::Wapc) {
-      drop(val);
-    }
-    
-  }
-  pub trait Wapc {
-    fn init_guest_request(&self,operation: String,payload: Vec<u8>,) -> u32;
-    fn on_host_response(&self,id: u32,code: u32,bytes: Vec<u8>,) -> ();
-    fn on_host_error(&self,id: u32,bytes: Vec<u8>,) -> ();
-  }
-}
+  
\ No newline at end of file
@@ -0,0 +1,786 @@
+//! The guest side of the waPC ABI.
+//!
+//! A guest registers a handler per operation from its
+//! `wapc_init` function, which the SDK calls once when the host
+//! first asks for the guest's handle:
+//!
+//! ```ignore
+//! #[no_mangle]
+//! pub extern "C" fn wapc_init() {
+//!     wapc_guest::register("echo", |request| {
+//!         let payload = request.payload.clone();
+//!         request.respond(&payload)
+//!     });
+//! }
+//! ```
+//!
+//! Handlers may answer right away or hold on to the `Request`
+//! and answer from the continuation of a host `call`.
+//!
+//! Either side may cancel a request it made. A host `call` is
+//! cancelled with `cancel`, which drops its continuation unrun.
+//! When the host cancels an invocation, the continuations of
+//! every call made for it are dropped, those calls are
+//! cancelled in turn, and the invocation is failed with
+//! `CANCELLED` to acknowledge it.
+//!
+//! A panic in a handler or continuation is reported to the
+//! host through `console-log` and fails the request being
+//! handled with `on-guest-error`, before the guest traps.
+//!
+//! Payloads and answers may also be streamed in chunks.
+//! Whoever makes a request announces a streamed payload, or
+//! accepts a streamed answer, with `STREAM` metadata. Streams
+//! are read with `Request::stream` and `call_streamed`, or
+//! pulled from with `Request::pull`, and written with a
+//! `Sink`. The receiver of a stream grants credit for the
+//! chunks it is ready to take, and a `Sink` holds chunks back
+//! until it has credit for them.
+//!
+//! Invocations and host calls carry metadata: key-value pairs
+//! such as a tenant, the caller's identity, a W3C trace context
+//! or a content type. Keys are matched ignoring ASCII case.
+//! Host calls made while handling an invocation inherit the
+//! invocation's `PROPAGATED` entries, unless the call sets
+//! them itself; other entries are never passed on.
+//!
+//! Guests call each other with `call_guest`, a host call for
+//! the `GUESTS` binding which the host routes to the named
+//! guest.
+
+// The generated imports of `wapc-host.wit`, only some of
+// which the SDK uses.
+#[allow(dead_code)]
+mod bindings {
+    include!("bindings.rs");
+    pub(crate) use self::wapc_host::*;
+}
+use bindings as wapc_host;
+
+mod wapc_guest;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Once;
+
+use wapc_host::StreamKind;
+
+// Streams are kept in maps keyed by their kind, which the
+// generated enum has no `Hash` for.
+impl Hash for StreamKind {
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        (*self as u8).hash(state)
+    }
+}
+
+/// Handles one invocation of an operation.
+pub type Handler = fn(Request);
+
+/// The outcome of a host call: the response code and bytes, or
+/// the error bytes.
+pub type Reply = Result<(u32, Vec<u8>), Vec<u8>>;
+
+/// The metadata key carrying the W3C trace context.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// The error an invocation the host cancelled fails with.
+pub const CANCELLED: &[u8] = b"cancelled";
+
+/// The metadata key with which whoever makes a request announces
+/// that its payload is streamed (`request`), that it takes a
+/// streamed answer (`response`), or both (`request,response`).
+pub const STREAM: &str = "stream";
+
+/// The binding of host calls the host routes to another guest,
+/// named by the call's namespace.
+pub const GUESTS: &str = "wapc";
+
+/// The metadata key listing the guests a routed invocation
+/// passed through, which the host sends for the guest's
+/// information. The host keeps track of call chains itself, so
+/// guests need not pass it on.
+pub const CALL_CHAIN: &str = "wapc-call-chain";
+
+/// The metadata keys host calls inherit from the invocation
+/// they are made for.
+pub const PROPAGATED: &[&str] = &[TRACEPARENT, "tracestate", "tenant", "caller", "deadline"];
+
+/// The invocation a handler or continuation runs on behalf of.
+#[derive(Clone)]
+struct Current {
+    id: u32,
+    /// The invocation's metadata with a `PROPAGATED` key.
+    propagated: Vec<(String, String)>,
+}
+
+struct Pending {
+    invocation: Option<Current>,
+    then: Box<dyn FnOnce(Reply)>,
+}
+
+/// Runs with every chunk of a stream as it is read.
+type Each = Box<dyn FnMut(&[u8])>;
+
+/// Runs with how a payload stream ended.
+type Finish = Box<dyn FnOnce(Result<(), Vec<u8>>)>;
+
+/// A stream the guest reads.
+struct Reader {
+    invocation: Option<Current>,
+    window: u32,
+    /// Chunks read since credit was last granted.
+    read: u32,
+    each: Each,
+    /// Runs once a payload stream is over; answer streams end
+    /// with the reply instead.
+    then: Option<Finish>,
+}
+
+/// A piece of a stream the guest pulls, see `Chunks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    Data(Vec<u8>),
+    /// The stream is complete.
+    End,
+    /// The stream was aborted with these bytes.
+    Error(Vec<u8>),
+}
+
+/// Runs whenever chunks of a pulled stream arrive.
+type Ready = Box<dyn FnMut(&mut Chunks)>;
+
+/// A payload stream the guest pulls chunks from.
+struct Puller {
+    invocation: Option<Current>,
+    /// The request to answer, until `Chunks::take_request`.
+    request: Option<Request>,
+    buffered: VecDeque<Chunk>,
+    /// Taken out while it runs.
+    ready: Option<Ready>,
+}
+
+/// A stream the guest writes.
+#[derive(Default)]
+struct Outbox {
+    credit: u32,
+    queued: VecDeque<Vec<u8>>,
+    /// How the stream ends, once its queued chunks are sent.
+    end: Option<Result<(), Vec<u8>>>,
+}
+
+thread_local! {
+    static HOST: wapc_host::Wapc = wapc_host::instance();
+    static HANDLERS: RefCell<HashMap<String, Handler>> = RefCell::new(HashMap::new());
+    static PENDING: RefCell<HashMap<u32, Pending>> = RefCell::new(HashMap::new());
+    static NEXT_ID: Cell<u32> = const { Cell::new(0) };
+    /// The invocations not answered yet.
+    static OPEN: RefCell<HashSet<u32>> = RefCell::new(HashSet::new());
+    /// The invocation whose handler or continuation is running.
+    static CURRENT: RefCell<Option<Current>> = const { RefCell::new(None) };
+    static READERS: RefCell<HashMap<(u32, StreamKind), Reader>> = RefCell::new(HashMap::new());
+    static OUTBOXES: RefCell<HashMap<(u32, StreamKind), Outbox>> = RefCell::new(HashMap::new());
+    /// The pulled payload streams, by invocation.
+    static PULLERS: RefCell<HashMap<u32, Puller>> = RefCell::new(HashMap::new());
+}
+
+extern "C" {
+    /// Defined by the guest to register its handlers.
+    fn wapc_init();
+}
+
+/// An invocation of an operation, to be answered exactly once.
+pub struct Request {
+    id: u32,
+    pub payload: Vec<u8>,
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The value of metadata `key`, if the host sent it.
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        lookup(&self.metadata, key)
+    }
+
+    /// Whether the host streams the payload, which then follows
+    /// `payload` and is read with `stream`.
+    pub fn is_streamed(&self) -> bool {
+        announces(&self.metadata, "request")
+    }
+
+    /// Whether the host takes a streamed answer, see
+    /// `answer_stream`.
+    pub fn accepts_stream(&self) -> bool {
+        announces(&self.metadata, "response")
+    }
+
+    pub fn respond(self, bytes: &[u8]) {
+        self.respond_with_code(0, bytes)
+    }
+
+    /// Like `respond`, with a response code for the caller other
+    /// than 0, such as one passed on from a reply.
+    pub fn respond_with_code(self, code: u32, bytes: &[u8]) {
+        if forget_invocation(self.id) {
+            HOST.with(|host| host.on_guest_response(self.id, code, bytes))
+        }
+    }
+
+    pub fn fail(self, bytes: &[u8]) {
+        if forget_invocation(self.id) {
+            HOST.with(|host| host.on_guest_error(self.id, bytes))
+        }
+    }
+
+    /// Reads the streamed payload, running `each` with every
+    /// chunk as it arrives and `then` once the stream is over,
+    /// with the request to answer and how the stream ended. The
+    /// host is kept granted credit for up to `window` unread
+    /// chunks.
+    pub fn stream(
+        self,
+        window: u32,
+        each: impl FnMut(&[u8]) + 'static,
+        then: impl FnOnce(Request, Result<(), Vec<u8>>) + 'static,
+    ) {
+        let id = self.id;
+        let each = Box::new(each);
+        let finish = Box::new(move |end| then(self, end));
+        read(id, StreamKind::Request, window, each, Some(finish))
+    }
+
+    /// Reads the streamed payload by pulling its chunks from
+    /// `Chunks`, rather than having them pushed to a callback.
+    /// `ready` runs whenever chunks arrive. The ones it leaves
+    /// unread stay buffered, and the host is only granted credit
+    /// back as chunks are read, for up to `window` chunks which
+    /// were not.
+    pub fn pull(self, window: u32, ready: impl FnMut(&mut Chunks) + 'static) {
+        let id = self.id;
+        let puller = Puller {
+            invocation: CURRENT.with(|current| current.borrow().clone()),
+            request: Some(self),
+            buffered: VecDeque::new(),
+            ready: Some(Box::new(ready)),
+        };
+        PULLERS.with(|p| p.borrow_mut().insert(id, puller));
+        HOST.with(|host| host.stream_credit(id, StreamKind::Request, window.max(1)))
+    }
+
+    /// Answers with a stream of chunks, if the host
+    /// `accepts_stream`.
+    pub fn answer_stream(self) -> Sink {
+        Sink::open(self.id, StreamKind::Response)
+    }
+}
+
+/// The chunks of a pulled payload stream which arrived and were
+/// not read yet, see `Request::pull`.
+///
+/// As an `Iterator`, every `next` reads the oldest of them and
+/// grants the host credit for another. It returns `None` when
+/// none is left for now, and once the `End` or `Error` chunk the
+/// stream is over with was read.
+pub struct Chunks {
+    id: u32,
+}
+
+impl Chunks {
+    /// Takes the request whose payload this is, to answer it, or
+    /// `None` once it was taken. Answering it drops whatever is
+    /// left of the stream.
+    pub fn take_request(&mut self) -> Option<Request> {
+        PULLERS.with(|p| p.borrow_mut().get_mut(&self.id)?.request.take())
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Chunk> {
+        let chunk = PULLERS.with(|p| p.borrow_mut().get_mut(&self.id)?.buffered.pop_front())?;
+        if let Chunk::Data(_) = chunk {
+            HOST.with(|host| host.stream_credit(self.id, StreamKind::Request, 1));
+        }
+        Some(chunk)
+    }
+}
+
+/// Buffers `chunk` of payload stream `id` if it is pulled, and
+/// runs its `ready`. Returns whether it was pulled.
+fn buffer_pulled(id: u32, chunk: Chunk) -> bool {
+    let pulled = PULLERS.with(|p| {
+        let mut pullers = p.borrow_mut();
+        let puller = pullers.get_mut(&id)?;
+        puller.buffered.push_back(chunk);
+        Some((puller.invocation.clone(), puller.ready.take()))
+    });
+    let (invocation, ready) = match pulled {
+        Some(pulled) => pulled,
+        None => return false,
+    };
+    if let Some(mut ready) = ready {
+        with_current(invocation, || ready(&mut Chunks { id }));
+        PULLERS.with(|p| {
+            if let Some(puller) = p.borrow_mut().get_mut(&id) {
+                puller.ready = Some(ready);
+            }
+        });
+    }
+    true
+}
+
+/// Sends a stream to the host in chunks, holding them back until
+/// the host grants credit for them.
+pub struct Sink {
+    id: u32,
+    kind: StreamKind,
+}
+
+impl Sink {
+    /// Streams the payload of host call `id`, made with `STREAM`
+    /// metadata announcing it.
+    pub fn payload(id: u32) -> Sink {
+        Sink::open(id, StreamKind::Request)
+    }
+
+    fn open(id: u32, kind: StreamKind) -> Sink {
+        // The host may have granted credit already.
+        OUTBOXES.with(|o| {
+            o.borrow_mut().entry((id, kind)).or_default();
+        });
+        Sink { id, kind }
+    }
+
+    /// Sends `bytes` as the next chunk, as soon as the host has
+    /// granted credit for it.
+    pub fn send(&mut self, bytes: &[u8]) {
+        OUTBOXES.with(|o| {
+            if let Some(outbox) = o.borrow_mut().get_mut(&(self.id, self.kind)) {
+                outbox.queued.push_back(bytes.to_vec());
+            }
+        });
+        flush(self.id, self.kind)
+    }
+
+    /// The chunks held back for lack of credit, for a producer to
+    /// pace itself by.
+    pub fn queued(&self) -> usize {
+        OUTBOXES.with(|o| {
+            o.borrow()
+                .get(&(self.id, self.kind))
+                .map_or(0, |o| o.queued.len())
+        })
+    }
+
+    /// Completes the stream once every chunk is sent: ends a
+    /// payload, or answers with nothing more.
+    pub fn end(self) {
+        self.finish(Ok(()))
+    }
+
+    /// Aborts the stream with `bytes` once every chunk is sent:
+    /// a payload through `stream-error`, or an answer by failing
+    /// the request.
+    pub fn fail(self, bytes: &[u8]) {
+        self.finish(Err(bytes.to_vec()))
+    }
+
+    fn finish(self, end: Result<(), Vec<u8>>) {
+        OUTBOXES.with(|o| {
+            if let Some(outbox) = o.borrow_mut().get_mut(&(self.id, self.kind)) {
+                outbox.end = Some(end);
+            }
+        });
+        flush(self.id, self.kind)
+    }
+}
+
+/// What a `Sink` sends next.
+enum Out {
+    Chunk(Vec<u8>),
+    End(Result<(), Vec<u8>>),
+}
+
+/// Sends what the credit for stream `(id, kind)` allows, and
+/// ends it once nothing is held back.
+fn flush(id: u32, kind: StreamKind) {
+    loop {
+        let next = OUTBOXES.with(|o| {
+            let mut outboxes = o.borrow_mut();
+            let outbox = outboxes.get_mut(&(id, kind))?;
+            if outbox.credit > 0 && !outbox.queued.is_empty() {
+                outbox.credit -= 1;
+                return outbox.queued.pop_front().map(Out::Chunk);
+            }
+            if outbox.queued.is_empty() && outbox.end.is_some() {
+                let end = outbox.end.take().map(Out::End);
+                outboxes.remove(&(id, kind));
+                return end;
+            }
+            None
+        });
+        let end = match next {
+            Some(Out::Chunk(chunk)) => {
+                HOST.with(|host| host.stream_chunk(id, kind, &chunk));
+                continue;
+            }
+            Some(Out::End(end)) => end,
+            None => return,
+        };
+        if kind == StreamKind::Response && !forget_invocation(id) {
+            return;
+        }
+        return HOST.with(|host| match (kind, end) {
+            (StreamKind::Request, Ok(())) => host.stream_end(id),
+            (StreamKind::Request, Err(bytes)) => host.stream_error(id, &bytes),
+            (StreamKind::Response, Ok(())) => host.on_guest_response(id, 0, &[]),
+            (StreamKind::Response, Err(bytes)) => host.on_guest_error(id, &bytes),
+        });
+    }
+}
+
+/// Starts reading stream `(id, kind)`, granting the host credit
+/// for `window` chunks.
+fn read(
+    id: u32,
+    kind: StreamKind,
+    window: u32,
+    each: Each,
+    then: Option<Finish>,
+) {
+    let window = window.max(1);
+    let reader = Reader {
+        invocation: CURRENT.with(|current| current.borrow().clone()),
+        window,
+        read: 0,
+        each,
+        then,
+    };
+    READERS.with(|r| r.borrow_mut().insert((id, kind), reader));
+    HOST.with(|host| host.stream_credit(id, kind, window))
+}
+
+/// Ends payload stream `id`, running its reader's `then`.
+fn end_stream(id: u32, end: Result<(), Vec<u8>>) {
+    let reader = READERS.with(|r| r.borrow_mut().remove(&(id, StreamKind::Request)));
+    if let Some(Reader {
+        invocation,
+        then: Some(then),
+        ..
+    }) = reader
+    {
+        with_current(invocation, || then(end));
+    }
+}
+
+/// Forgets invocation `id` and its streams as it is answered.
+/// Returns whether it was still open, i.e. whether the answer is
+/// the first.
+fn forget_invocation(id: u32) -> bool {
+    READERS.with(|r| r.borrow_mut().remove(&(id, StreamKind::Request)));
+    OUTBOXES.with(|o| o.borrow_mut().remove(&(id, StreamKind::Response)));
+    PULLERS.with(|p| p.borrow_mut().remove(&id));
+    OPEN.with(|open| open.borrow_mut().remove(&id))
+}
+
+/// Forgets the streams of host call `id` once it is settled.
+fn forget_call(id: u32) {
+    READERS.with(|r| r.borrow_mut().remove(&(id, StreamKind::Response)));
+    OUTBOXES.with(|o| o.borrow_mut().remove(&(id, StreamKind::Request)));
+}
+
+fn announces(metadata: &[(String, String)], wanted: &str) -> bool {
+    metadata
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case(STREAM))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(wanted))
+}
+
+/// Registers `handler` for invocations of `operation`.
+pub fn register(operation: &str, handler: Handler) {
+    HANDLERS.with(|handlers| handlers.borrow_mut().insert(operation.to_string(), handler));
+}
+
+/// The `traceparent` of the invocation being handled, if the
+/// host sent one.
+pub fn trace_context() -> Option<String> {
+    propagated(TRACEPARENT)
+}
+
+/// The value of `key`, one of the `PROPAGATED` keys, in the
+/// metadata of the invocation being handled.
+pub fn propagated(key: &str) -> Option<String> {
+    CURRENT.with(|current| lookup(&current.borrow().as_ref()?.propagated, key).map(String::from))
+}
+
+fn lookup<'a>(metadata: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    metadata
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value.as_str())
+}
+
+fn is_propagated(key: &str) -> bool {
+    PROPAGATED.iter().any(|p| p.eq_ignore_ascii_case(key))
+}
+
+pub fn console_log(message: &str) {
+    HOST.with(|host| host.console_log(message))
+}
+
+/// Makes a request of the host, running `then` with its reply
+/// once the host answers. Returns the host's id for the request.
+///
+/// The request carries the propagated metadata of the
+/// invocation being handled.
+pub fn call(
+    binding: &str,
+    namespace: &str,
+    operation: &str,
+    payload: &[u8],
+    then: impl FnOnce(Reply) + 'static,
+) -> u32 {
+    call_with(binding, namespace, operation, payload, &[], then)
+}
+
+/// Like `call`, sending `metadata` along with the request. Its
+/// entries take the place of propagated ones with the same key.
+pub fn call_with(
+    binding: &str,
+    namespace: &str,
+    operation: &str,
+    payload: &[u8],
+    metadata: &[(&str, &str)],
+    then: impl FnOnce(Reply) + 'static,
+) -> u32 {
+    let invocation = CURRENT.with(|current| current.borrow().clone());
+    let mut sent = metadata.to_vec();
+    if let Some(invocation) = &invocation {
+        for (key, value) in &invocation.propagated {
+            if !metadata.iter().any(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                sent.push((key.as_str(), value.as_str()));
+            }
+        }
+    }
+    let id =
+        HOST.with(|host| host.init_host_request(binding, namespace, operation, payload, &sent));
+    let pending = Pending {
+        invocation,
+        then: Box::new(then),
+    };
+    PENDING.with(|p| p.borrow_mut().insert(id, pending));
+    id
+}
+
+/// Calls `operation` of another guest, `guest`, which the host
+/// routes the call to, running `then` with its reply.
+pub fn call_guest(
+    guest: &str,
+    operation: &str,
+    payload: &[u8],
+    then: impl FnOnce(Reply) + 'static,
+) -> u32 {
+    call(GUESTS, guest, operation, payload, then)
+}
+
+/// Like `call`, taking a streamed answer: `each` runs with every
+/// chunk of it as it arrives, and `then` with the reply, whose
+/// bytes come last. The host is kept granted credit for up to
+/// `window` unread chunks.
+pub fn call_streamed(
+    binding: &str,
+    namespace: &str,
+    operation: &str,
+    payload: &[u8],
+    window: u32,
+    each: impl FnMut(&[u8]) + 'static,
+    then: impl FnOnce(Reply) + 'static,
+) -> u32 {
+    let metadata = [(STREAM, "response")];
+    let id = call_with(binding, namespace, operation, payload, &metadata, then);
+    read(id, StreamKind::Response, window, Box::new(each), None);
+    id
+}
+
+/// Cancels host call `id`, made with `call`, dropping its
+/// continuation unrun. Does nothing once the host has answered
+/// it.
+pub fn cancel(id: u32) {
+    let pending = PENDING.with(|p| p.borrow_mut().remove(&id));
+    if pending.is_some() {
+        forget_call(id);
+        HOST.with(|host| host.cancel(id));
+    }
+}
+
+/// Runs `f` on behalf of `invocation`, which is the request a
+/// panic fails.
+fn with_current<R>(invocation: Option<Current>, f: impl FnOnce() -> R) -> R {
+    let previous = CURRENT.with(|current| current.replace(invocation));
+    let result = f();
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+fn settle(id: u32, reply: Reply) {
+    // Replies to requests which are not pending, e.g. a second
+    // answer to the same request, are ignored.
+    if let Some(pending) = PENDING.with(|p| p.borrow_mut().remove(&id)) {
+        forget_call(id);
+        let Pending { invocation, then } = pending;
+        with_current(invocation, || then(reply));
+    }
+}
+
+/// Installs a panic hook which reports the panic, with its
+/// location, to the host and fails the current invocation
+/// unless it was answered already, then runs whichever hook was
+/// installed before.
+fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = format!("guest {}", info);
+        // The panic may come from within the SDK itself, so
+        // nothing here may panic again.
+        let _ = HOST.try_with(|host| {
+            host.console_log(&message);
+            let id = CURRENT.try_with(|current| {
+                current.try_borrow().ok().and_then(|c| c.as_ref().map(|c| c.id))
+            });
+            let unanswered = |id| {
+                OPEN.try_with(|open| open.try_borrow_mut().is_ok_and(|mut o| o.remove(&id)))
+                    .unwrap_or(false)
+            };
+            match id {
+                Ok(Some(id)) if unanswered(id) => host.on_guest_error(id, message.as_bytes()),
+                _ => {}
+            }
+        });
+        previous(info)
+    }));
+}
+
+pub struct Guest;
+
+pub struct WapcGuest;
+
+impl wapc_guest::WapcGuest for WapcGuest {
+    fn instance() -> wit_bindgen_rust::Handle<Guest> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            install_panic_hook();
+            unsafe { wapc_init() }
+        });
+        wit_bindgen_rust::Handle::new(Guest)
+    }
+}
+
+impl wapc_guest::Guest for Guest {
+    fn init_guest_request(
+        &self,
+        operation: &str,
+        payload: &[u8],
+        metadata: &[(&str, &str)],
+    ) -> u32 {
+        let id = NEXT_ID.with(|next| {
+            let id = next.get().wrapping_add(1);
+            next.set(id);
+            id
+        });
+        let metadata = metadata
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        let current = Current {
+            id,
+            propagated: metadata
+                .iter()
+                .filter(|(key, _)| is_propagated(key))
+                .cloned()
+                .collect(),
+        };
+        let request = Request {
+            id,
+            payload: payload.to_vec(),
+            metadata,
+        };
+        OPEN.with(|open| open.borrow_mut().insert(id));
+        match HANDLERS.with(|handlers| handlers.borrow().get(operation).copied()) {
+            Some(handler) => with_current(Some(current), || handler(request)),
+            None => request.fail(format!("unknown operation `{}`", operation).as_bytes()),
+        }
+        id
+    }
+
+    fn on_host_response(&self, id: u32, code: u32, bytes: &[u8]) {
+        settle(id, Ok((code, bytes.to_vec())))
+    }
+
+    fn on_host_error(&self, id: u32, bytes: &[u8]) {
+        settle(id, Err(bytes.to_vec()))
+    }
+
+    fn cancel(&self, id: u32) {
+        let calls = PENDING.with(|p| {
+            p.borrow()
+                .iter()
+                .filter(|(_, pending)| pending.invocation.as_ref().map(|c| c.id) == Some(id))
+                .map(|(&call, _)| call)
+                .collect::<Vec<_>>()
+        });
+        for call in calls {
+            cancel(call);
+        }
+        if forget_invocation(id) {
+            HOST.with(|host| host.on_guest_error(id, CANCELLED))
+        }
+    }
+
+    fn stream_chunk(&self, id: u32, kind: StreamKind, bytes: &[u8]) {
+        if kind == StreamKind::Request && buffer_pulled(id, Chunk::Data(bytes.to_vec())) {
+            return;
+        }
+        // Chunks for streams nobody reads are dropped.
+        let mut reader = match READERS.with(|r| r.borrow_mut().remove(&(id, kind))) {
+            Some(reader) => reader,
+            None => return,
+        };
+        with_current(reader.invocation.clone(), || (reader.each)(bytes));
+        reader.read += 1;
+        let mut grant = 0;
+        if reader.read >= (reader.window / 2).max(1) {
+            grant = std::mem::take(&mut reader.read);
+        }
+        READERS.with(|r| r.borrow_mut().insert((id, kind), reader));
+        if grant > 0 {
+            HOST.with(|host| host.stream_credit(id, kind, grant))
+        }
+    }
+
+    fn stream_end(&self, id: u32) {
+        if !buffer_pulled(id, Chunk::End) {
+            end_stream(id, Ok(()))
+        }
+    }
+
+    fn stream_error(&self, id: u32, bytes: &[u8]) {
+        if !buffer_pulled(id, Chunk::Error(bytes.to_vec())) {
+            end_stream(id, Err(bytes.to_vec()))
+        }
+    }
+
+    fn stream_credit(&self, id: u32, kind: StreamKind, chunks: u32) {
+        OUTBOXES.with(|o| {
+            let mut outboxes = o.borrow_mut();
+            let outbox = outboxes.entry((id, kind)).or_default();
+            outbox.credit = outbox.credit.saturating_add(chunks);
+        });
+        flush(id, kind)
+    }
+}
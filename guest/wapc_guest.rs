@@ -0,0 +1,290 @@
+//! The exports `wapc-guest.wit` describes.
+//!
+//! This side of the ABI is written by hand rather than generated
+//! with `wit-bindgen`, as its calling convention differs from
+//! the generated one in ways the WIT cannot express:
+//!
+//! - The handle passed to each call is lent by the host, which
+//!   keeps it, so it is never dropped here.
+//! - Arguments may be borrowed from the buffer `guest::scratch`
+//!   hands the host, and are only valid for the duration of the
+//!   call. Arguments outside of it were allocated with
+//!   `canonical_abi_realloc` and are owned as usual.
+//!
+//! The exports, their names and their signatures are otherwise
+//! those of `wapc-guest.wit`, so the two must be kept in sync.
+
+use std::cell::RefCell;
+
+use super::wapc_host::StreamKind;
+
+fn lift_stream_kind(val: i32) -> StreamKind {
+    match val {
+        0 => StreamKind::Request,
+        1 => StreamKind::Response,
+        _ => panic!("invalid enum discriminant"),
+    }
+}
+
+unsafe impl wit_bindgen_rust::HandleType for super::Guest {
+    #[inline]
+    fn clone(_val: i32) -> i32 {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            panic!("handles can only be used on wasm32");
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            #[link(wasm_import_module = "canonical_abi")]
+            extern "C" {
+                #[link_name = "resource_clone_guest"]
+                fn clone(val: i32) -> i32;
+            }
+            unsafe { clone(_val) }
+        }
+    }
+
+    #[inline]
+    fn drop(_val: i32) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            panic!("handles can only be used on wasm32");
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            #[link(wasm_import_module = "canonical_abi")]
+            extern "C" {
+                #[link_name = "resource_drop_guest"]
+                fn drop(val: i32);
+            }
+            unsafe { drop(_val) }
+        }
+    }
+}
+
+unsafe impl wit_bindgen_rust::LocalHandle for super::Guest {
+    #[inline]
+    fn new(_val: i32) -> i32 {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            panic!("handles can only be used on wasm32");
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            #[link(wasm_import_module = "canonical_abi")]
+            extern "C" {
+                #[link_name = "resource_new_guest"]
+                fn new(val: i32) -> i32;
+            }
+            unsafe { new(_val) }
+        }
+    }
+
+    #[inline]
+    fn get(_val: i32) -> i32 {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            panic!("handles can only be used on wasm32");
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            #[link(wasm_import_module = "canonical_abi")]
+            extern "C" {
+                #[link_name = "resource_get_guest"]
+                fn get(val: i32) -> i32;
+            }
+            unsafe { get(_val) }
+        }
+    }
+}
+
+const _: () = {
+    #[export_name = "canonical_abi_drop_guest"]
+    extern "C" fn drop(ty: Box<super::Guest>) {
+        <super::WapcGuest as WapcGuest>::drop_guest(*ty)
+    }
+};
+
+/// The handle the host lends to a call.
+unsafe fn lent(handle: i32) -> core::mem::ManuallyDrop<wit_bindgen_rust::Handle<super::Guest>> {
+    core::mem::ManuallyDrop::new(wit_bindgen_rust::Handle::from_raw(handle))
+}
+
+#[export_name = "instance"]
+unsafe extern "C" fn __wapc_guest_instance() -> i32 {
+    let result = <super::WapcGuest as WapcGuest>::instance();
+    wit_bindgen_rust::Handle::into_raw(result)
+}
+
+#[export_name = "guest::init-guest-request"]
+unsafe extern "C" fn __wapc_guest_init_guest_request(
+    arg0: i32,
+    arg1: i32,
+    arg2: i32,
+    arg3: i32,
+    arg4: i32,
+    arg5: i32,
+    arg6: i32,
+) -> i32 {
+    let operation = scratch::Arg::new(arg1, arg2);
+    let payload = scratch::Arg::new(arg3, arg4);
+    // The list of records is always owned; the strings it points
+    // to may be borrowed from the scratch buffer.
+    let records = if arg6 == 0 {
+        Vec::new()
+    } else {
+        Vec::from_raw_parts(arg5 as *mut [i32; 4], arg6 as usize, arg6 as usize)
+    };
+    let entries = records
+        .iter()
+        .map(|r| (scratch::Arg::new(r[0], r[1]), scratch::Arg::new(r[2], r[3])))
+        .collect::<Vec<_>>();
+    let metadata = entries
+        .iter()
+        .map(|(key, value)| {
+            (
+                core::str::from_utf8(key.as_slice()).unwrap(),
+                core::str::from_utf8(value.as_slice()).unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let result = <super::Guest as Guest>::init_guest_request(
+        &lent(arg0),
+        core::str::from_utf8(operation.as_slice()).unwrap(),
+        payload.as_slice(),
+        &metadata,
+    );
+    wit_bindgen_rust::rt::as_i32(result)
+}
+
+#[export_name = "guest::on-host-response"]
+unsafe extern "C" fn __wapc_guest_on_host_response(
+    arg0: i32,
+    arg1: i32,
+    arg2: i32,
+    arg3: i32,
+    arg4: i32,
+) {
+    let bytes = scratch::Arg::new(arg3, arg4);
+    <super::Guest as Guest>::on_host_response(&lent(arg0), arg1 as u32, arg2 as u32, bytes.as_slice())
+}
+
+#[export_name = "guest::on-host-error"]
+unsafe extern "C" fn __wapc_guest_on_host_error(arg0: i32, arg1: i32, arg2: i32, arg3: i32) {
+    let bytes = scratch::Arg::new(arg2, arg3);
+    <super::Guest as Guest>::on_host_error(&lent(arg0), arg1 as u32, bytes.as_slice())
+}
+
+#[export_name = "guest::cancel"]
+unsafe extern "C" fn __wapc_guest_cancel(arg0: i32, arg1: i32) {
+    <super::Guest as Guest>::cancel(&lent(arg0), arg1 as u32)
+}
+
+#[export_name = "guest::stream-chunk"]
+unsafe extern "C" fn __wapc_guest_stream_chunk(arg0: i32, arg1: i32, arg2: i32, arg3: i32, arg4: i32) {
+    let bytes = scratch::Arg::new(arg3, arg4);
+    <super::Guest as Guest>::stream_chunk(
+        &lent(arg0),
+        arg1 as u32,
+        lift_stream_kind(arg2),
+        bytes.as_slice(),
+    )
+}
+
+#[export_name = "guest::stream-end"]
+unsafe extern "C" fn __wapc_guest_stream_end(arg0: i32, arg1: i32) {
+    <super::Guest as Guest>::stream_end(&lent(arg0), arg1 as u32)
+}
+
+#[export_name = "guest::stream-error"]
+unsafe extern "C" fn __wapc_guest_stream_error(arg0: i32, arg1: i32, arg2: i32, arg3: i32) {
+    let bytes = scratch::Arg::new(arg2, arg3);
+    <super::Guest as Guest>::stream_error(&lent(arg0), arg1 as u32, bytes.as_slice())
+}
+
+#[export_name = "guest::stream-credit"]
+unsafe extern "C" fn __wapc_guest_stream_credit(arg0: i32, arg1: i32, arg2: i32, arg3: i32) {
+    <super::Guest as Guest>::stream_credit(
+        &lent(arg0),
+        arg1 as u32,
+        lift_stream_kind(arg2),
+        arg3 as u32,
+    )
+}
+
+/// A reusable buffer the host writes call arguments into instead
+/// of allocating each one with `canonical_abi_realloc`.
+///
+/// Arguments in the buffer are only borrowed for the duration of
+/// the export call; the host overwrites them on the next call.
+mod scratch {
+    use super::RefCell;
+
+    thread_local! {
+        static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[export_name = "guest::scratch"]
+    extern "C" fn reserve(len: i32) -> i32 {
+        BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            // The buffer is never filled, so this reserves room
+            // for `len` bytes in total.
+            buffer.reserve_exact(len as u32 as usize);
+            buffer.as_mut_ptr() as i32
+        })
+    }
+
+    /// An export argument, either borrowed from the scratch
+    /// buffer or allocated by `canonical_abi_realloc` and now
+    /// owned by the export.
+    pub enum Arg {
+        Borrowed(*const u8, usize),
+        Owned(Vec<u8>),
+    }
+
+    impl Arg {
+        pub unsafe fn new(ptr: i32, len: i32) -> Self {
+            let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+            let (start, capacity) = BUFFER.with(|buffer| {
+                let buffer = buffer.borrow();
+                (buffer.as_ptr() as usize, buffer.capacity())
+            });
+            let borrowed = ptr >= start
+                && ptr
+                    .checked_add(len)
+                    .is_some_and(|end| end <= start + capacity);
+            if borrowed {
+                Arg::Borrowed(ptr as *const u8, len)
+            } else {
+                Arg::Owned(Vec::from_raw_parts(ptr as *mut u8, len, len))
+            }
+        }
+
+        pub fn as_slice(&self) -> &[u8] {
+            match self {
+                Arg::Borrowed(ptr, len) => unsafe { core::slice::from_raw_parts(*ptr, *len) },
+                Arg::Owned(vec) => vec,
+            }
+        }
+    }
+}
+
+pub trait WapcGuest {
+    fn instance() -> wit_bindgen_rust::Handle<super::Guest>;
+
+    /// An optional callback invoked when a handle is finalized
+    /// and destroyed.
+    fn drop_guest(_val: super::Guest) {}
+}
+
+pub trait Guest {
+    fn init_guest_request(&self, operation: &str, payload: &[u8], metadata: &[(&str, &str)]) -> u32;
+    fn on_host_response(&self, id: u32, code: u32, bytes: &[u8]);
+    fn on_host_error(&self, id: u32, bytes: &[u8]);
+    fn cancel(&self, id: u32);
+    fn stream_chunk(&self, id: u32, kind: StreamKind, bytes: &[u8]);
+    fn stream_end(&self, id: u32);
+    fn stream_error(&self, id: u32, bytes: &[u8]);
+    fn stream_credit(&self, id: u32, kind: StreamKind, chunks: u32);
+}
@@ -0,0 +1,661 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wit_bindgen_wasmtime::anyhow;
+
+use crate::diagnostics::CallError;
+use crate::payload::encode_hex;
+use crate::runtime::{self, Answer, Hooks};
+use crate::stub::StubHost;
+use crate::wapc_host::{StreamKind, WapcHost};
+
+/// One audited interaction between a guest and the host.
+///
+/// Payloads are only ever recorded as their SHA-256.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub guest: String,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum AuditEvent {
+    /// The host invoked an operation; `id` is `None` when the
+    /// guest trapped before returning one.
+    Invoke {
+        id: Option<u32>,
+        operation: String,
+        payload_sha256: String,
+        status: InvokeStatus,
+    },
+    GuestResponse {
+        id: u32,
+        #[serde(default)]
+        code: u32,
+        payload_sha256: String,
+    },
+    GuestError {
+        id: u32,
+        payload_sha256: String,
+    },
+    /// The guest made a host request, recorded before the
+    /// wrapped host is given it.
+    HostRequest {
+        binding: String,
+        namespace: String,
+        operation: String,
+        payload_sha256: String,
+    },
+    /// The id the wrapped host gave the host request recorded
+    /// before it.
+    HostRequestId {
+        id: u32,
+    },
+    HostResponse {
+        id: u32,
+        code: u32,
+        payload_sha256: String,
+    },
+    HostError {
+        id: u32,
+        payload_sha256: String,
+    },
+    /// The host cancelled an invocation.
+    CancelInvocation {
+        id: u32,
+    },
+    /// The guest cancelled a host request.
+    CancelHostRequest {
+        id: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvokeStatus {
+    Started,
+    Trapped,
+}
+
+/// Where audit records go.
+pub trait AuditSink: Send {
+    fn record(&mut self, record: &Record) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Where the log ends, for sinks which chain their records.
+    fn head(&self) -> Option<Head> {
+        None
+    }
+}
+
+impl<S: AuditSink + ?Sized> AuditSink for Box<S> {
+    fn record(&mut self, record: &Record) -> io::Result<()> {
+        (**self).record(record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn head(&self) -> Option<Head> {
+        (**self).head()
+    }
+}
+
+/// A sink which drops every record.
+pub struct Discard;
+
+impl AuditSink for Discard {
+    fn record(&mut self, _record: &Record) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A line of a `JsonLines` audit log: the record, its position
+/// in the log and the MAC of the previous line.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    seq: u64,
+    prev: String,
+    #[serde(flatten)]
+    record: Record,
+}
+
+/// Where a `JsonLines` log ends: the number of records in it
+/// and the MAC of its last line.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Head {
+    pub records: u64,
+    pub last: String,
+}
+
+/// An append-only audit log of JSON lines.
+///
+/// Every line carries an HMAC-SHA256 of the line before it,
+/// keyed with a secret shared by the log's writer and its
+/// verifiers. Without the key, no line can be changed, removed
+/// or inserted without breaking the chain. Lines cut off the
+/// end leave a shorter chain which is still valid, so the
+/// log's `head` must be kept where the log's writer cannot
+/// change it for `verify` to detect that.
+pub struct JsonLines {
+    out: File,
+    key: Vec<u8>,
+    head: Head,
+}
+
+impl JsonLines {
+    /// Opens the log at `path`, continuing the chain of any
+    /// records already in it, which must have been written with
+    /// the same `key`.
+    pub fn open(path: impl AsRef<Path>, key: &[u8]) -> anyhow::Result<Self> {
+        if key.is_empty() {
+            anyhow::bail!("the audit log key is empty");
+        }
+        let path = path.as_ref();
+        let head = match File::open(path) {
+            Ok(file) => chain_end(file, key)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Head::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let out = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonLines {
+            out,
+            key: key.to_vec(),
+            head,
+        })
+    }
+
+    /// Where the log currently ends.
+    pub fn head(&self) -> &Head {
+        &self.head
+    }
+}
+
+impl AuditSink for JsonLines {
+    fn record(&mut self, record: &Record) -> io::Result<()> {
+        let line = Line {
+            seq: self.head.records,
+            prev: self.head.last.clone(),
+            record: record.clone(),
+        };
+        let mut bytes = serde_json::to_vec(&line)?;
+        let last = mac(&self.key, &bytes);
+        bytes.push(b'\n');
+        self.out.write_all(&bytes)?;
+        self.head = Head {
+            records: self.head.records + 1,
+            last,
+        };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn head(&self) -> Option<Head> {
+        Some(self.head.clone())
+    }
+}
+
+/// Checks the chain of the audit log at `path`, written with
+/// `key`, and that it ends at `head`.
+pub fn verify(path: impl AsRef<Path>, key: &[u8], head: &Head) -> anyhow::Result<()> {
+    let end = chain_end(File::open(path)?, key)?;
+    if end != *head {
+        anyhow::bail!(
+            "audit log ends after {} records, not at the expected head after {}",
+            end.records,
+            head.records
+        );
+    }
+    Ok(())
+}
+
+/// Walks the chain of a log, returning where it ends.
+fn chain_end(file: File, key: &[u8]) -> anyhow::Result<Head> {
+    let mut head = Head::default();
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        let parsed: Line = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("audit record {}: {}", head.records, e))?;
+        if parsed.seq != head.records || parsed.prev != head.last {
+            anyhow::bail!("audit log chain is broken at record {}", head.records);
+        }
+        head = Head {
+            records: head.records + 1,
+            last: mac(key, line.as_bytes()),
+        };
+    }
+    Ok(head)
+}
+
+fn mac(key: &[u8], line: &[u8]) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+    mac.update(line);
+    encode_hex(&mac.finalize().into_bytes())
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    encode_hex(&Sha256::digest(bytes))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// A `WapcHost` wrapper which writes an audit record for every
+/// request in either direction and its outcome.
+///
+/// It fails closed: once a record cannot be written, the
+/// guest's host requests are no longer passed on but failed,
+/// and its answers to invocations are replaced with an error,
+/// so nothing that went unaudited takes effect. Host requests
+/// are recorded before the wrapped host is given them; those
+/// the guest makes while an invocation starts are written with
+/// the invocation's record, once the guest has returned its
+/// id.
+pub struct Audited<H> {
+    inner: H,
+    guest: String,
+    sink: Box<dyn AuditSink>,
+    /// The invocation being started, and the records the guest
+    /// produced before it returned its id.
+    starting: Option<(Record, Vec<Record>)>,
+    error: Option<io::Error>,
+    refused: VecDeque<u32>,
+}
+
+impl<H> Audited<H> {
+    pub fn new(inner: H, guest: impl Into<String>, sink: impl AuditSink + 'static) -> Self {
+        Audited {
+            inner,
+            guest: guest.into(),
+            sink: Box::new(sink),
+            starting: None,
+            error: None,
+            refused: VecDeque::new(),
+        }
+    }
+
+    /// Flushes the sink and returns the wrapped host, or the
+    /// first error encountered while writing records.
+    pub fn finish(mut self) -> io::Result<H> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.sink.flush()?;
+        Ok(self.inner)
+    }
+
+    fn record(&mut self, event: AuditEvent) {
+        let record = Record {
+            timestamp: now(),
+            guest: self.guest.clone(),
+            event,
+        };
+        match &mut self.starting {
+            // Keep the log in causal order: the invocation
+            // comes before whatever the guest did while starting
+            // it.
+            Some((_, early)) => early.push(record),
+            None => self.write(&record),
+        }
+    }
+
+    fn write(&mut self, record: &Record) {
+        if self.error.is_some() {
+            return;
+        }
+        self.error = self.sink.record(record).err();
+    }
+
+    /// Whether a record could not be written.
+    pub fn failed(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Where the sink's log ends, if it chains its records.
+    pub fn head(&self) -> Option<Head> {
+        self.sink.head()
+    }
+}
+
+const UNAUDITED: &[u8] = b"audit log unavailable";
+
+impl<H> AsMut<StubHost> for Audited<H>
+where
+    H: AsMut<StubHost>,
+{
+    fn as_mut(&mut self) -> &mut StubHost {
+        self.inner.as_mut()
+    }
+}
+
+impl<H: Hooks> Hooks for Audited<H> {
+    fn on_invoke(&mut self, operation: &str, payload: &[u8], metadata: &mut Vec<(String, String)>) {
+        let record = Record {
+            timestamp: now(),
+            guest: self.guest.clone(),
+            event: AuditEvent::Invoke {
+                id: None,
+                operation: operation.to_string(),
+                payload_sha256: sha256(payload),
+                status: InvokeStatus::Started,
+            },
+        };
+        self.starting = Some((record, Vec::new()));
+        self.inner.on_invoke(operation, payload, metadata)
+    }
+
+    fn on_invoked(&mut self, operation: &str, result: Result<u32, &CallError>) {
+        if let Some((mut record, early)) = self.starting.take() {
+            if let AuditEvent::Invoke { id, status, .. } = &mut record.event {
+                match result {
+                    Ok(started) => *id = Some(started),
+                    Err(_) => *status = InvokeStatus::Trapped,
+                }
+            }
+            self.write(&record);
+            for record in &early {
+                self.write(record);
+            }
+        }
+        self.inner.on_invoked(operation, result)
+    }
+
+    fn on_respond(&mut self, id: u32, code: u32, bytes: &[u8]) {
+        self.record(AuditEvent::HostResponse {
+            id,
+            code,
+            payload_sha256: sha256(bytes),
+        });
+        self.inner.on_respond(id, code, bytes)
+    }
+
+    fn on_fail(&mut self, id: u32, bytes: &[u8]) {
+        self.record(AuditEvent::HostError {
+            id,
+            payload_sha256: sha256(bytes),
+        });
+        self.inner.on_fail(id, bytes)
+    }
+
+    fn on_cancel(&mut self, id: u32) {
+        self.record(AuditEvent::CancelInvocation { id });
+        self.inner.on_cancel(id)
+    }
+
+    fn on_trap(&mut self, error: &CallError) {
+        self.refused.clear();
+        self.inner.on_trap(error)
+    }
+
+    fn take_settled(&mut self) -> Option<(u32, Answer)> {
+        match self.refused.pop_front() {
+            Some(id) => Some((id, Answer::Error(UNAUDITED.to_vec()))),
+            None => self.inner.take_settled(),
+        }
+    }
+}
+
+impl<H: WapcHost> WapcHost for Audited<H> {
+    type Wapc = H::Wapc;
+
+    fn instance(&mut self) -> Self::Wapc {
+        self.inner.instance()
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        self_: &Self::Wapc,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        self.record(AuditEvent::HostRequest {
+            binding: binding.to_string(),
+            namespace: namespace.to_string(),
+            operation: operation.to_string(),
+            payload_sha256: sha256(bytes),
+        });
+        if self.failed() {
+            let id = runtime::settled_id();
+            self.refused.push_back(id);
+            return id;
+        }
+        let id = self
+            .inner
+            .wapc_init_host_request(self_, binding, namespace, operation, bytes, metadata);
+        self.record(AuditEvent::HostRequestId { id });
+        if self.failed() {
+            // Withdrawn, as the log cannot tell its answer apart
+            // from those of the other requests.
+            self.inner.wapc_cancel(self_, id);
+            self.refused.push_back(id);
+        }
+        id
+    }
+
+    fn wapc_on_guest_response(&mut self, self_: &Self::Wapc, id: u32, code: u32, bytes: &[u8]) {
+        self.record(AuditEvent::GuestResponse {
+            id,
+            code,
+            payload_sha256: sha256(bytes),
+        });
+        if self.failed() {
+            return self.inner.wapc_on_guest_error(self_, id, UNAUDITED);
+        }
+        self.inner.wapc_on_guest_response(self_, id, code, bytes)
+    }
+
+    fn wapc_on_guest_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.record(AuditEvent::GuestError {
+            id,
+            payload_sha256: sha256(bytes),
+        });
+        if self.failed() {
+            return self.inner.wapc_on_guest_error(self_, id, UNAUDITED);
+        }
+        self.inner.wapc_on_guest_error(self_, id, bytes)
+    }
+
+    fn wapc_console_log(&mut self, self_: &Self::Wapc, message: &str) {
+        self.inner.wapc_console_log(self_, message)
+    }
+
+    fn wapc_cancel(&mut self, self_: &Self::Wapc, id: u32) {
+        self.record(AuditEvent::CancelHostRequest { id });
+        match self.refused.iter().position(|&refused| refused == id) {
+            // Never passed on, or already withdrawn.
+            Some(index) => {
+                self.refused.remove(index);
+            }
+            None => self.inner.wapc_cancel(self_, id),
+        }
+    }
+
+    fn wapc_stream_chunk(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, bytes: &[u8]) {
+        self.inner.wapc_stream_chunk(self_, id, kind, bytes)
+    }
+
+    fn wapc_stream_end(&mut self, self_: &Self::Wapc, id: u32) {
+        self.inner.wapc_stream_end(self_, id)
+    }
+
+    fn wapc_stream_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.inner.wapc_stream_error(self_, id, bytes)
+    }
+
+    fn wapc_stream_credit(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, chunks: u32) {
+        self.inner.wapc_stream_credit(self_, id, kind, chunks)
+    }
+
+    fn drop_wapc(&mut self, state: Self::Wapc) {
+        self.inner.drop_wapc(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::stub::Handle;
+
+    const KEY: &[u8] = b"audit key";
+
+    fn scratch_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wapc-audit-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn record(id: u32) -> Record {
+        Record {
+            timestamp: 0,
+            guest: "guest".to_string(),
+            event: AuditEvent::CancelInvocation { id },
+        }
+    }
+
+    /// Writes three records to a fresh log and returns its head.
+    fn write_log(path: &Path) -> Head {
+        let mut log = JsonLines::open(path, KEY).unwrap();
+        for id in 0..3 {
+            log.record(&record(id)).unwrap();
+        }
+        log.head().clone()
+    }
+
+    #[test]
+    fn intact_logs_verify_and_continue_their_chain() {
+        let path = scratch_log("intact");
+        write_log(&path);
+        let mut log = JsonLines::open(&path, KEY).unwrap();
+        assert_eq!(log.head().records, 3);
+        log.record(&record(3)).unwrap();
+        verify(&path, KEY, log.head()).unwrap();
+        assert!(verify(&path, b"another key", log.head()).is_err());
+    }
+
+    #[test]
+    fn edited_logs_fail_verification() {
+        let path = scratch_log("edited");
+        let head = write_log(&path);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, log.replacen("\"id\":1", "\"id\":9", 1)).unwrap();
+        let error = verify(&path, KEY, &head).unwrap_err();
+        assert!(
+            error.to_string().contains("broken at record 2"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn truncated_logs_fail_verification() {
+        let path = scratch_log("truncated");
+        let head = write_log(&path);
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+
+        // Cut off after a whole line, which leaves a valid chain.
+        std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+        assert!(verify(&path, KEY, &head).is_err());
+        // Cut off in the middle of a line.
+        std::fs::write(&path, &log[..log.len() - 10]).unwrap();
+        assert!(verify(&path, KEY, &head).is_err());
+    }
+
+    struct Broken;
+
+    impl AuditSink for Broken {
+        fn record(&mut self, _record: &Record) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+
+    /// Writes `left` records, then fails.
+    struct Filling {
+        records: Arc<Mutex<Vec<AuditEvent>>>,
+        left: usize,
+    }
+
+    impl AuditSink for Filling {
+        fn record(&mut self, record: &Record) -> io::Result<()> {
+            if self.left == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            self.left -= 1;
+            self.records.lock().unwrap().push(record.event.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn host_requests_are_recorded_before_the_wrapped_host_sees_them() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = Filling {
+            records: records.clone(),
+            left: 1,
+        };
+        let mut host = Audited::new(StubHost::new(), "guest", sink);
+        let id = host.wapc_init_host_request(&Handle, "kv", "app", "get", b"", Vec::new());
+        assert!(host.failed());
+        // Given to the wrapped host, then withdrawn once its id
+        // could not be recorded.
+        assert_eq!(host.as_mut().take_cancelled(), Some(id));
+        assert!(host.as_mut().take_request().is_none());
+        assert_eq!(
+            host.take_settled(),
+            Some((id, Answer::Error(UNAUDITED.to_vec())))
+        );
+        assert_eq!(
+            records.lock().unwrap().as_slice(),
+            &[AuditEvent::HostRequest {
+                binding: "kv".to_string(),
+                namespace: "app".to_string(),
+                operation: "get".to_string(),
+                payload_sha256: sha256(b""),
+            }]
+        );
+    }
+
+    #[test]
+    fn unaudited_requests_and_answers_are_refused() {
+        let mut host = Audited::new(StubHost::new(), "guest", Broken);
+        let id = host.wapc_init_host_request(&Handle, "kv", "app", "get", b"", Vec::new());
+        assert!(host.failed());
+        // Never passed on.
+        assert_eq!(host.as_mut().take_cancelled(), None);
+        assert!(host.as_mut().take_request().is_none());
+        assert_eq!(
+            host.take_settled(),
+            Some((id, Answer::Error(UNAUDITED.to_vec())))
+        );
+
+        host.wapc_on_guest_response(&Handle, 7, 0, b"hello");
+        assert_eq!(host.as_mut().take_result(7), Some(Err(UNAUDITED.to_vec())));
+        assert!(host.finish().is_err());
+    }
+}
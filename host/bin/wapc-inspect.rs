@@ -0,0 +1,45 @@
+use std::process;
+
+use wapc_runtime::inspect;
+use wit_bindgen_wasmtime::{anyhow, wasmtime};
+
+const USAGE: &str = "usage: wapc-inspect [--json] <module.wasm>";
+
+fn main() {
+    match run() {
+        Ok(valid) => process::exit(if valid { 0 } else { 1 }),
+        Err(e) => {
+            eprintln!("error: {:?}", e);
+            process::exit(2);
+        }
+    }
+}
+
+fn run() -> anyhow::Result<bool> {
+    let mut json = false;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(true);
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => anyhow::bail!("{}", USAGE),
+        }
+    }
+    let path = path.ok_or_else(|| anyhow::anyhow!("{}", USAGE))?;
+
+    let wasm = std::fs::read(&path)?;
+    let engine = wasmtime::Engine::default();
+    let report = inspect::inspect(&engine, &wasm)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("module: {}", path);
+        print!("{}", report);
+    }
+    Ok(report.issues.is_empty())
+}
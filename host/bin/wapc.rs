@@ -0,0 +1,517 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process;
+
+use wapc_runtime::audit::{self, AuditSink, Audited};
+use wapc_runtime::cache::ModuleCache;
+use wapc_runtime::claims::{self, Claims, Verifier};
+use wapc_runtime::golden;
+use wapc_runtime::metrics::{self, Measured, Metrics};
+use wapc_runtime::payload;
+use wapc_runtime::policy::{Policy, Restricted};
+use wapc_runtime::repl::Repl;
+use wapc_runtime::runtime::{Hooks, Instance, Limit, Limits};
+use wapc_runtime::stub::{self, Fixtures, GuestResult, StubHost};
+use wapc_runtime::telemetry::{self, Traced};
+use wapc_runtime::trace::{self, Recorder, Replayer};
+use wapc_runtime::wapc_host::WapcHost;
+use wit_bindgen_wasmtime::{anyhow, wasmtime};
+
+const USAGE: &str = "\
+usage: wapc run <module.wasm> <operation> [options]
+       wapc repl <module.wasm> [options]
+       wapc replay <module.wasm> <trace.jsonl> [options]
+       wapc test <module.wasm> <cases-dir> [--bless] [options]
+       wapc sign <module.wasm> <claims.json> --key <path> [--output <path>]
+       wapc audit-verify <audit.jsonl> --audit-key <path> [--audit-head <path>]
+
+options:
+    --payload-file <path>   read the payload from <path> ('-' for stdin, the default)
+    --metadata <key=value>  send metadata with the invocation (repeatable)
+    --fixtures <path>       answer host requests from a JSON fixtures file
+    --cache <dir>           cache compiled modules in <dir>
+    --record <path>         write a trace of every host/guest interaction to <path>
+    --dump-dir <dir>        write a dump of the guest into <dir> when it traps
+    --policy <path>         refuse host requests not allowed by a JSON capability policy
+    --trust <public-key>    only load modules signed by this hex key (repeatable); the
+                            module's claimed capabilities then restrict its host requests
+    --key <path>            sign with the hex Ed25519 secret key in <path>
+    --output <path>         write the signed module to <path> instead of in place
+    --audit <path>          append a hash-chained audit record of every request to <path>
+    --audit-key <path>      key the audit log's chain with the hex secret in <path>
+    --audit-head <path>     verify the audit log ends at the head in <path>
+                            (default <audit.jsonl>.head, written by every run)
+    --otlp <endpoint>       export trace spans to an OTLP/HTTP collector at <endpoint>
+    --metrics <port>        serve Prometheus metrics at http://127.0.0.1:<port>/metrics
+                            while `repl` runs
+    --max-in-flight <n>     let at most <n> invocations and <n> host requests await an answer
+    --queue <n>             queue up to <n> requests beyond --max-in-flight in each direction
+                            before failing them as unavailable (default 0)
+    --quiet                 do not print console-log output
+    --bless                 rewrite the expected output of failing test cases";
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.first().map(|s| s.as_str()) {
+        Some("run") => run(&args[1..]),
+        Some("repl") => repl(&args[1..]),
+        Some("replay") => replay(&args[1..]),
+        Some("test") => test(&args[1..]),
+        Some("sign") => sign(&args[1..]),
+        Some("audit-verify") => audit_verify(&args[1..]),
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            Ok(2)
+        }
+    };
+    telemetry::shutdown();
+    match result {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("error: {:?}", e);
+            process::exit(2);
+        }
+    }
+}
+
+/// The host every subcommand running a guest uses.
+type Host = Traced<Measured<Audited<Restricted<StubHost>>>>;
+
+/// Options shared by every subcommand which loads a module.
+struct Options {
+    positional: Vec<String>,
+    payload_file: Option<String>,
+    metadata: Vec<(String, String)>,
+    fixtures: Option<String>,
+    cache: Option<String>,
+    record: Option<String>,
+    dump_dir: Option<String>,
+    policy: Option<String>,
+    trust: Vec<String>,
+    key: Option<String>,
+    output: Option<String>,
+    audit: Option<String>,
+    audit_key: Option<String>,
+    audit_head: Option<String>,
+    otlp: Option<String>,
+    metrics_port: Option<u16>,
+    metrics: Metrics,
+    max_in_flight: Option<usize>,
+    queue: usize,
+    quiet: bool,
+    bless: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = Options {
+            positional: Vec::new(),
+            payload_file: None,
+            metadata: Vec::new(),
+            fixtures: None,
+            cache: None,
+            record: None,
+            dump_dir: None,
+            policy: None,
+            trust: Vec::new(),
+            key: None,
+            output: None,
+            audit: None,
+            audit_key: None,
+            audit_head: None,
+            otlp: None,
+            metrics_port: None,
+            metrics: Metrics::new(),
+            max_in_flight: None,
+            queue: 0,
+            quiet: false,
+            bless: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("`{}` requires a value\n\n{}", arg, USAGE))
+            };
+            match arg.as_str() {
+                "--payload-file" => options.payload_file = Some(value()?),
+                "--metadata" => {
+                    let entry = value()?;
+                    let (key, value) = entry.split_once('=').ok_or_else(|| {
+                        anyhow::anyhow!("`--metadata` expects <key=value>, got `{}`", entry)
+                    })?;
+                    options.metadata.push((key.to_string(), value.to_string()));
+                }
+                "--fixtures" => options.fixtures = Some(value()?),
+                "--cache" => options.cache = Some(value()?),
+                "--record" => options.record = Some(value()?),
+                "--dump-dir" => options.dump_dir = Some(value()?),
+                "--policy" => options.policy = Some(value()?),
+                "--trust" => options.trust.push(value()?),
+                "--key" => options.key = Some(value()?),
+                "--output" => options.output = Some(value()?),
+                "--audit" => options.audit = Some(value()?),
+                "--audit-key" => options.audit_key = Some(value()?),
+                "--audit-head" => options.audit_head = Some(value()?),
+                "--otlp" => options.otlp = Some(value()?),
+                "--metrics" => {
+                    let port = value()?;
+                    let port = port.parse().map_err(|_| {
+                        anyhow::anyhow!("`--metrics` expects a port, got `{}`", port)
+                    })?;
+                    options.metrics_port = Some(port);
+                }
+                "--max-in-flight" => {
+                    let n = value()?;
+                    match n.parse() {
+                        Ok(n) if n > 0 => options.max_in_flight = Some(n),
+                        _ => anyhow::bail!(
+                            "`--max-in-flight` expects a positive count, got `{}`",
+                            n
+                        ),
+                    }
+                }
+                "--queue" => {
+                    let n = value()?;
+                    options.queue = n
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("`--queue` expects a count, got `{}`", n))?;
+                }
+                "--quiet" => options.quiet = true,
+                "--bless" => options.bless = true,
+                flag if flag.starts_with("--") => {
+                    anyhow::bail!("unknown option `{}`\n\n{}", flag, USAGE)
+                }
+                _ => options.positional.push(arg.clone()),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Loads the module at `path`, along with its verified
+    /// claims when `--trust` was given.
+    fn load_module(&self, path: &str) -> anyhow::Result<(wasmtime::Module, Option<Claims>)> {
+        let engine = wasmtime::Engine::default();
+        let wasm = std::fs::read(path)?;
+        let claims = if self.trust.is_empty() {
+            None
+        } else {
+            let claims = Verifier::from_hex(&self.trust)?
+                .verify(&wasm)
+                .map_err(|e| anyhow::anyhow!("refusing to load {}: {}", path, e))?;
+            Some(claims)
+        };
+        let module = match &self.cache {
+            Some(dir) => ModuleCache::new(&engine, dir)?.load(&engine, &wasm)?,
+            None => wasmtime::Module::new(&engine, &wasm)?,
+        };
+        Ok((module, claims))
+    }
+
+    fn instance<H: WapcHost + Hooks + 'static>(
+        &self,
+        module: &wasmtime::Module,
+        host: H,
+        guest: &str,
+    ) -> anyhow::Result<Instance<H>> {
+        let instance = Instance::new(module, host)?
+            .limits(self.limits())
+            .metrics(&self.metrics, guest);
+        Ok(match &self.dump_dir {
+            Some(dir) => instance.dump_dir(dir),
+            None => instance,
+        })
+    }
+
+    fn limits(&self) -> Limits {
+        let limit = self.max_in_flight.map(|max_in_flight| Limit {
+            max_in_flight,
+            queue: self.queue,
+        });
+        Limits {
+            invocations: limit,
+            host_requests: limit,
+        }
+    }
+
+    fn fixtures(&self) -> anyhow::Result<Fixtures> {
+        match &self.fixtures {
+            Some(path) => Fixtures::load(path),
+            None => Ok(Fixtures::default()),
+        }
+    }
+
+    /// The name of the module at `path` in audit records and
+    /// metrics: the subject of its claims, or else its file name.
+    fn guest(&self, path: &str, claims: Option<&Claims>) -> String {
+        match claims {
+            Some(claims) => claims.subject.clone(),
+            None => Path::new(path)
+                .file_stem()
+                .map_or_else(|| path.to_string(), |s| s.to_string_lossy().into_owned()),
+        }
+    }
+
+    /// The policies host requests must satisfy: the one given
+    /// with `--policy` and the capabilities of the module's
+    /// claims.
+    fn policies(&self, claims: Option<&Claims>) -> anyhow::Result<Vec<Policy>> {
+        let mut policies = Vec::new();
+        if let Some(path) = &self.policy {
+            policies.push(Policy::load(path)?);
+        }
+        policies.extend(claims.map(Claims::policy));
+        Ok(policies)
+    }
+
+    /// The host for `guest`, along with the exporters its
+    /// telemetry was asked for.
+    fn host(&self, guest: &str, claims: Option<&Claims>) -> anyhow::Result<Host> {
+        if let Some(endpoint) = &self.otlp {
+            telemetry::install_otlp(endpoint)?;
+        }
+        if let Some(port) = self.metrics_port {
+            let addr = metrics::serve(self.metrics.clone(), port)?;
+            eprintln!("serving metrics at http://{}/metrics", addr);
+        }
+        let host = if self.quiet {
+            StubHost::new().on_log(|_| {})
+        } else {
+            StubHost::new().on_log(|message| eprintln!("[guest] {}", message))
+        };
+        let policies = self.policies(claims)?;
+        let host = policies
+            .iter()
+            .cloned()
+            .fold(Restricted::new(host, Policy::allow_all()), Restricted::and)
+            .on_denied(|denial| {
+                eprintln!(
+                    "denied host request {} ({}/{}/{})",
+                    denial.id, denial.binding, denial.namespace, denial.operation
+                )
+            });
+
+        let sink: Box<dyn AuditSink> = match &self.audit {
+            Some(log) => Box::new(audit::JsonLines::open(log, &self.audit_key("--audit")?)?),
+            None => Box::new(audit::Discard),
+        };
+        let host = Audited::new(host, guest, sink);
+        // Requests the policies refuse are not worth a label of
+        // their own.
+        let host = policies.into_iter().fold(
+            Measured::new(host, guest, self.metrics.clone()),
+            Measured::labels,
+        );
+        Ok(Traced::new(host))
+    }
+
+    /// The key of the audit log, which `option` requires.
+    fn audit_key(&self, option: &str) -> anyhow::Result<Vec<u8>> {
+        match &self.audit_key {
+            Some(path) => payload::decode_hex(std::fs::read_to_string(path)?.trim()),
+            None => anyhow::bail!("`{}` requires --audit-key\n\n{}", option, USAGE),
+        }
+    }
+
+    /// Flushes the audit log of `host`, failing if any record of
+    /// the run could not be written, then reports where the log
+    /// ends and saves it for `audit-verify`.
+    fn finish(&self, host: Host) -> anyhow::Result<()> {
+        let audited = host.into_inner().into_inner();
+        let head = audited.head();
+        audited
+            .finish()
+            .map_err(|e| anyhow::anyhow!("writing the audit log failed: {}", e))?;
+        if let (Some(log), Some(head)) = (&self.audit, head) {
+            eprintln!(
+                "audit log head: {} records, last {}",
+                head.records, head.last
+            );
+            std::fs::write(head_path(log), serde_json::to_vec(&head)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the head of the audit log at `log` is saved.
+fn head_path(log: &str) -> String {
+    format!("{}.head", log)
+}
+
+fn run(args: &[String]) -> anyhow::Result<i32> {
+    let options = Options::parse(args)?;
+    let (path, operation) = match options.positional.as_slice() {
+        [path, operation] => (path, operation),
+        _ => anyhow::bail!("{}", USAGE),
+    };
+    if options.metrics_port.is_some() {
+        anyhow::bail!("`--metrics` needs a command which keeps running, such as `repl`");
+    }
+
+    let payload = match options.payload_file.as_deref() {
+        None | Some("-") => {
+            let mut payload = Vec::new();
+            std::io::stdin().read_to_end(&mut payload)?;
+            payload
+        }
+        Some(path) => std::fs::read(path)?,
+    };
+    let fixtures = options.fixtures()?;
+
+    let (module, claims) = options.load_module(path)?;
+    let guest = options.guest(path, claims.as_ref());
+    let result = match &options.record {
+        Some(record) => {
+            let trace = std::io::BufWriter::new(std::fs::File::create(record)?);
+            let host = Recorder::new(options.host(&guest, claims.as_ref())?, trace);
+            let mut instance = options.instance(&module, host, &guest)?;
+            let metadata = &options.metadata;
+            let result = stub::call_with(&mut instance, operation, &payload, metadata, |r| {
+                fixtures.answer(r)
+            })?;
+            options.finish(instance.into_host().finish()?)?;
+            result
+        }
+        None => {
+            let host = options.host(&guest, claims.as_ref())?;
+            let mut instance = options.instance(&module, host, &guest)?;
+            let metadata = &options.metadata;
+            let result = stub::call_with(&mut instance, operation, &payload, metadata, |r| {
+                fixtures.answer(r)
+            })?;
+            options.finish(instance.into_host())?;
+            result
+        }
+    };
+    print_result(result)
+}
+
+fn print_result(result: GuestResult) -> anyhow::Result<i32> {
+    match result {
+        Ok(bytes) => {
+            std::io::stdout().write_all(&bytes)?;
+            Ok(0)
+        }
+        Err(bytes) => {
+            eprintln!("guest error: {}", String::from_utf8_lossy(&bytes));
+            Ok(1)
+        }
+    }
+}
+
+fn repl(args: &[String]) -> anyhow::Result<i32> {
+    let options = Options::parse(args)?;
+    let path = match options.positional.as_slice() {
+        [path] => path,
+        _ => anyhow::bail!("{}", USAGE),
+    };
+
+    let (module, claims) = options.load_module(path)?;
+    let guest = options.guest(path, claims.as_ref());
+    let host = options.host(&guest, claims.as_ref())?;
+    let instance = options.instance(&module, host, &guest)?;
+    let stdin = std::io::stdin();
+    let mut repl = Repl::new(instance);
+    repl.run(stdin.lock(), std::io::stdout())?;
+    options.finish(repl.into_instance().into_host())?;
+    Ok(0)
+}
+
+fn replay(args: &[String]) -> anyhow::Result<i32> {
+    let options = Options::parse(args)?;
+    let (module, trace) = match options.positional.as_slice() {
+        [module, trace] => (module, trace),
+        _ => anyhow::bail!("{}", USAGE),
+    };
+
+    let (module, claims) = options.load_module(module)?;
+    let replayer = options
+        .policies(claims.as_ref())?
+        .into_iter()
+        .fold(Replayer::new(trace::read(trace)?), Replayer::restrict);
+    let divergences = trace::replay(&module, replayer)?;
+    if divergences.is_empty() {
+        println!("replay matched the trace");
+        return Ok(0);
+    }
+    for divergence in &divergences {
+        println!("{}", divergence);
+    }
+    Ok(1)
+}
+
+fn test(args: &[String]) -> anyhow::Result<i32> {
+    let options = Options::parse(args)?;
+    let (module, cases) = match options.positional.as_slice() {
+        [module, cases] => (module, cases),
+        _ => anyhow::bail!("{}", USAGE),
+    };
+
+    let (module, claims) = options.load_module(module)?;
+    let policies = options.policies(claims.as_ref())?;
+    let outcomes = golden::run_dir(&module, cases, &policies, options.bless)?;
+    for outcome in &outcomes {
+        println!("{}", outcome);
+    }
+    let failed = outcomes.iter().filter(|o| !o.is_success()).count();
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        if failed == 0 { "ok" } else { "FAILED" },
+        outcomes.len() - failed,
+        failed
+    );
+    Ok(if failed == 0 { 0 } else { 1 })
+}
+
+fn sign(args: &[String]) -> anyhow::Result<i32> {
+    let options = Options::parse(args)?;
+    let (module, claims) = match options.positional.as_slice() {
+        [module, claims] => (module, claims),
+        _ => anyhow::bail!("{}", USAGE),
+    };
+    let key = match &options.key {
+        Some(key) => key,
+        None => anyhow::bail!("`sign` requires --key\n\n{}", USAGE),
+    };
+
+    let secret = payload::decode_hex(std::fs::read_to_string(key)?.trim())?;
+    let secret = ed25519_dalek::SecretKey::from_bytes(&secret)
+        .map_err(|e| anyhow::anyhow!("invalid secret key: {}", e))?;
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    let keypair = ed25519_dalek::Keypair { secret, public };
+
+    let claims: Claims = serde_json::from_slice(&std::fs::read(claims)?)?;
+    let signed = claims::sign(&std::fs::read(module)?, claims, &keypair)?;
+    std::fs::write(options.output.as_deref().unwrap_or(module), signed)?;
+    println!("signed by {}", payload::encode_hex(keypair.public.as_bytes()));
+    Ok(0)
+}
+
+fn audit_verify(args: &[String]) -> anyhow::Result<i32> {
+    let options = Options::parse(args)?;
+    let log = match options.positional.as_slice() {
+        [log] => log,
+        _ => anyhow::bail!("{}", USAGE),
+    };
+    let key = options.audit_key("audit-verify")?;
+    let head = match &options.audit_head {
+        Some(path) => std::fs::read(path)?,
+        None => std::fs::read(head_path(log))?,
+    };
+    let head: audit::Head = serde_json::from_slice(&head)?;
+    match audit::verify(log, &key, &head) {
+        Ok(()) => {
+            println!("audit log verified: {} records", head.records);
+            Ok(0)
+        }
+        Err(e) => {
+            println!("audit log failed verification: {}", e);
+            Ok(1)
+        }
+    }
+}
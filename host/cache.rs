@@ -0,0 +1,444 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use wit_bindgen_wasmtime::{anyhow, wasmtime};
+
+use crate::payload::encode_hex;
+
+const MAGIC: &[u8; 8] = b"WAPCMOD2";
+const HEADER_LEN: usize = MAGIC.len() + 32 + 8;
+const EXTENSION: &str = "cwasm";
+/// The file in the cache directory holding the key entries are
+/// authenticated with.
+const KEY_FILE: &str = "key";
+
+/// Temporary files created by this process so far, which keeps
+/// the names of concurrent writes apart.
+static TEMPORARIES: AtomicU64 = AtomicU64::new(0);
+
+/// An on-disk cache of compiled guest modules.
+///
+/// Entries are keyed by the hash of the module bytes and a
+/// fingerprint of the `wasmtime::Engine` they were compiled
+/// with, so a change of wasmtime version or engine settings
+/// never resolves to a stale artifact.
+///
+/// `Module::deserialize` runs whatever machine code an entry
+/// holds, so every entry carries an HMAC of its name and
+/// payload, under a random key kept in the cache directory,
+/// which is verified first. The directory is created accessible
+/// to its owner only and refused when others may write to it.
+/// Whoever can write to it anyway, e.g. the owner or root, is
+/// trusted: they could replace the key as well.
+pub struct ModuleCache {
+    dir: PathBuf,
+    max_size: u64,
+    fingerprint: [u8; 32],
+    key: [u8; 32],
+}
+
+impl ModuleCache {
+    /// The default upper bound on the total size of the cache
+    /// directory, in bytes.
+    pub const DEFAULT_MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+    /// Opens (creating if necessary) a cache rooted at `dir`
+    /// for modules compiled with `engine`.
+    pub fn new(engine: &wasmtime::Engine, dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        create_private_dir(&dir)?;
+        let key = load_key(&dir.join(KEY_FILE))?;
+        Ok(ModuleCache {
+            dir,
+            max_size: Self::DEFAULT_MAX_SIZE,
+            fingerprint: engine_fingerprint(engine)?,
+            key,
+        })
+    }
+
+    /// Sets the size bound after which the least recently
+    /// used entries are evicted.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Returns the compiled form of `wasm`, deserializing it
+    /// from the cache when a valid entry exists and compiling
+    /// (and storing) it otherwise.
+    ///
+    /// Corrupt, truncated or incompatible entries are treated
+    /// as misses: they are removed and the module is compiled
+    /// from scratch.
+    pub fn load(&self, engine: &wasmtime::Engine, wasm: &[u8]) -> anyhow::Result<wasmtime::Module> {
+        let path = self.entry_path(wasm);
+        if let Some(module) = self.read_entry(engine, &path) {
+            return Ok(module);
+        }
+        let module = wasmtime::Module::new(engine, wasm)?;
+        // A failure to populate the cache must never fail the
+        // load itself; the next start simply compiles again.
+        if self.write_entry(&path, &module.serialize()?).is_ok() {
+            let _ = self.evict();
+        }
+        Ok(module)
+    }
+
+    /// Removes every entry from the cache, along with the
+    /// temporary files of unfinished writes.
+    pub fn clear(&self) -> anyhow::Result<()> {
+        for (path, _, _) in self.entries()? {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn entry_path(&self, wasm: &[u8]) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(self.fingerprint);
+        hasher.update(wasm);
+        self.dir
+            .join(encode_hex(&hasher.finalize()))
+            .with_extension(EXTENSION)
+    }
+
+    /// The MAC of an entry, which binds its artifact to its name
+    /// so that one entry cannot stand in for another either.
+    fn mac(&self, path: &Path, artifact: &[u8]) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("any key length");
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        mac.update(name.unwrap_or_default().as_bytes());
+        mac.update(&(artifact.len() as u64).to_le_bytes());
+        mac.update(artifact);
+        mac
+    }
+
+    fn read_entry(&self, engine: &wasmtime::Engine, path: &Path) -> Option<wasmtime::Module> {
+        let bytes = fs::read(path).ok()?;
+        let artifact = match self.verify(path, &bytes) {
+            Some(artifact) => artifact,
+            None => {
+                let _ = fs::remove_file(path);
+                return None;
+            }
+        };
+        // SAFETY: the artifact was produced by `Module::serialize`
+        // and its MAC matches what this cache's key gives, so it
+        // was written by this cache and has not been truncated or
+        // modified since.
+        match unsafe { wasmtime::Module::deserialize(engine, artifact) } {
+            Ok(module) => {
+                touch(path);
+                Some(module)
+            }
+            Err(_) => {
+                let _ = fs::remove_file(path);
+                None
+            }
+        }
+    }
+
+    fn write_entry(&self, path: &Path, artifact: &[u8]) -> anyhow::Result<()> {
+        // Write to a sibling and rename so concurrent readers
+        // never observe a partially written entry.
+        let tmp = temporary(path);
+        let result = self
+            .write_temporary(&tmp, path, artifact)
+            .and_then(|()| fs::rename(&tmp, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        Ok(result?)
+    }
+
+    fn write_temporary(&self, tmp: &Path, path: &Path, artifact: &[u8]) -> io::Result<()> {
+        let mut file = fs::File::create(tmp)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&self.mac(path, artifact).finalize().into_bytes())?;
+        file.write_all(&(artifact.len() as u64).to_le_bytes())?;
+        file.write_all(artifact)?;
+        file.sync_all()
+    }
+
+    fn verify<'a>(&self, path: &Path, bytes: &'a [u8]) -> Option<&'a [u8]> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let tag = &bytes[MAGIC.len()..MAGIC.len() + 32];
+        let mut len = [0; 8];
+        len.copy_from_slice(&bytes[MAGIC.len() + 32..HEADER_LEN]);
+        let artifact = &bytes[HEADER_LEN..];
+        if u64::from_le_bytes(len) != artifact.len() as u64 {
+            return None;
+        }
+        self.mac(path, artifact).verify_slice(tag).ok()?;
+        Some(artifact)
+    }
+
+    /// Evicts the least recently used files until the cache fits
+    /// in `max_size`. Temporary files count towards the size, and
+    /// as they are never touched, those left behind by writes
+    /// which never finished go first.
+    fn evict(&self) -> anyhow::Result<()> {
+        let mut entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(_, _, used)| *used);
+        for (path, size, _) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => total -= size,
+            }
+        }
+        Ok(())
+    }
+
+    /// The entries and temporary files in the cache, with their
+    /// sizes and last use. Files removed while listing them, e.g.
+    /// by another process evicting them, are skipped.
+    fn entries(&self) -> anyhow::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !is_entry(&path) && !is_temporary(&path) {
+                continue;
+            }
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            entries.push((path, meta.len(), meta.modified()?));
+        }
+        Ok(entries)
+    }
+}
+
+/// Identifies the wasmtime version and compilation settings of
+/// `engine` by hashing the artifact it produces for an empty
+/// module, which embeds both.
+fn engine_fingerprint(engine: &wasmtime::Engine) -> anyhow::Result<[u8; 32]> {
+    let empty = engine.precompile_module(b"\0asm\x01\0\0\0")?;
+    Ok(Sha256::digest(&empty).into())
+}
+
+fn is_entry(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some(EXTENSION)
+}
+
+/// A name next to `path` for a file to be renamed or linked
+/// into place, unique to this write: `<path>.<pid>-<n>`.
+fn temporary(path: &Path) -> PathBuf {
+    let n = TEMPORARIES.fetch_add(1, Ordering::Relaxed);
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}-{}", std::process::id(), n));
+    PathBuf::from(name)
+}
+
+/// Whether `path` is the `<entry>.cwasm.<pid>-<n>` file of a
+/// write.
+fn is_temporary(path: &Path) -> bool {
+    let numbers = |ext: &str| {
+        let (pid, n) = match ext.split_once('-') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        [pid, n]
+            .iter()
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let ext = path.extension().and_then(|e| e.to_str());
+    let stem = path.file_stem();
+    ext.is_some_and(numbers) && stem.is_some_and(|stem| is_entry(Path::new(stem)))
+}
+
+/// Creates `dir` accessible to its owner only, and refuses an
+/// existing one others may write to.
+fn create_private_dir(dir: &Path) -> anyhow::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        builder.mode(0o700);
+        builder.create(dir)?;
+        let mode = fs::metadata(dir)?.permissions().mode();
+        if mode & 0o022 != 0 {
+            anyhow::bail!(
+                "module cache {} is writable by other users (mode {:o})",
+                dir.display(),
+                mode & 0o777
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    builder.create(dir)?;
+    Ok(())
+}
+
+/// Reads the cache's key from `path`, creating a random one the
+/// first time.
+///
+/// A new key is written in full to a temporary file and then
+/// linked into place, which fails if another process got there
+/// first, so the key is never seen partly written nor replaced.
+fn load_key(path: &Path) -> anyhow::Result<[u8; 32]> {
+    match fs::read(path) {
+        Ok(bytes) => return parse_key(path, &bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let mut key = [0; 32];
+    getrandom::getrandom(&mut key).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let tmp = temporary(path);
+    let result = write_key(&tmp, &key).and_then(|()| fs::hard_link(&tmp, path));
+    let _ = fs::remove_file(&tmp);
+    match result {
+        Ok(()) => Ok(key),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => parse_key(path, &fs::read(path)?),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_key(path: &Path, key: &[u8; 32]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(key)?;
+    file.sync_all()
+}
+
+fn parse_key(path: &Path, bytes: &[u8]) -> anyhow::Result<[u8; 32]> {
+    <[u8; 32]>::try_from(bytes)
+        .map_err(|_| anyhow::anyhow!("malformed module cache key {}", path.display()))
+}
+
+/// Bumps the modification time of a cache hit so eviction
+/// removes the least recently used entries first.
+fn touch(path: &Path) {
+    if let Ok(file) = fs::File::options().write(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAT: &str = r#"(module (func (export "f") (result i32) (i32.const 7)))"#;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wapc-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(cache: &ModuleCache) -> PathBuf {
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        entries[0].0.clone()
+    }
+
+    #[test]
+    fn tampered_entries_are_recompiled() {
+        let dir = scratch_dir("tampered");
+        let engine = wasmtime::Engine::default();
+        let cache = ModuleCache::new(&engine, &dir).unwrap();
+        let wasm = wat::parse_str(WAT).unwrap();
+        cache.load(&engine, &wasm).unwrap();
+        let path = entry(&cache);
+        let good = fs::read(&path).unwrap();
+        assert!(cache.verify(&path, &good).is_some());
+
+        let mut bad = good.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        assert!(cache.verify(&path, &bad).is_none());
+        // A checksum alone would not catch a forged entry; the MAC
+        // needs the key.
+        let other = ModuleCache::new(&engine, scratch_dir("other")).unwrap();
+        assert!(other.verify(&path, &good).is_none());
+        // Nor may one entry stand in for another.
+        assert!(cache.verify(&path.with_file_name("x.cwasm"), &good).is_none());
+
+        fs::write(&path, &bad).unwrap();
+        cache.load(&engine, &wasm).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), good);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&other.dir).unwrap();
+    }
+
+    #[test]
+    fn temporary_files_count_towards_the_size() {
+        let dir = scratch_dir("temporary");
+        let engine = wasmtime::Engine::default();
+        let cache = ModuleCache::new(&engine, &dir).unwrap();
+        let stale = dir.join("0123.cwasm.42-7");
+        fs::write(&stale, vec![0; 1024]).unwrap();
+        fs::write(dir.join("notes.txt"), b"not ours").unwrap();
+        assert_eq!(cache.entries().unwrap().len(), 1);
+
+        let cache = cache.max_size(0);
+        cache.evict().unwrap();
+        assert!(!stale.exists());
+        assert!(dir.join("notes.txt").exists());
+        assert!(dir.join(KEY_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writers_share_the_key_and_leave_no_temporary_files() {
+        let dir = scratch_dir("concurrent");
+        let engine = wasmtime::Engine::default();
+        let wasm = wat::parse_str(WAT).unwrap();
+        let threads = (0..8)
+            .map(|_| {
+                let (engine, dir, wasm) = (engine.clone(), dir.clone(), wasm.clone());
+                std::thread::spawn(move || {
+                    let cache = ModuleCache::new(&engine, &dir).unwrap();
+                    cache.load(&engine, &wasm).unwrap();
+                    cache.key
+                })
+            })
+            .collect::<Vec<_>>();
+        let keys = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        assert!(keys.iter().all(|key| *key == keys[0]));
+
+        let cache = ModuleCache::new(&engine, &dir).unwrap();
+        let path = entry(&cache);
+        assert!(is_entry(&path));
+        assert!(cache.verify(&path, &fs::read(&path).unwrap()).is_some());
+        let files = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, 2, "only the key and the entry are left");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directories_others_can_write_to_are_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("shared");
+        let engine = wasmtime::Engine::default();
+        ModuleCache::new(&engine, &dir).unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(ModuleCache::new(&engine, &dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use wit_bindgen_wasmtime::{anyhow, wasmtime};
+
+use crate::payload::{decode_hex, encode_hex};
+use crate::policy::{Policy, Rule};
+
+/// The custom section holding a module's signed claims. It is
+/// always the last section of the module.
+pub const CLAIMS_SECTION: &str = "wapc-claims";
+
+/// What the signer of a module asserts about it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    /// The signer's Ed25519 public key, hex encoded.
+    #[serde(default)]
+    pub issuer: String,
+    /// What the module is, e.g. its name.
+    pub subject: String,
+    /// The host requests the module may make.
+    #[serde(default)]
+    pub capabilities: Vec<Rule>,
+    /// When the claims stop being valid, in seconds since the
+    /// Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// The SHA-256 of the module without its claims section,
+    /// hex encoded.
+    #[serde(default)]
+    pub module_hash: String,
+}
+
+impl Claims {
+    /// The policy allowing exactly the claimed capabilities.
+    pub fn policy(&self) -> Policy {
+        Policy {
+            allow: self.capabilities.clone(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+/// The contents of the claims section. The signature covers
+/// the bytes of `claims` exactly as they are embedded, so they
+/// are never parsed before being verified.
+#[derive(Serialize, Deserialize)]
+struct Token {
+    claims: Box<RawValue>,
+    signature: String,
+}
+
+/// Embeds `claims`, signed with `keypair`, into `wasm`,
+/// replacing any claims it already carries. The issuer and
+/// module hash are filled in.
+pub fn sign(wasm: &[u8], mut claims: Claims, keypair: &Keypair) -> anyhow::Result<Vec<u8>> {
+    let (module, _) = split(wasm)?;
+    claims.issuer = encode_hex(keypair.public.as_bytes());
+    claims.module_hash = encode_hex(&Sha256::digest(module));
+    let claims = RawValue::from_string(serde_json::to_string(&claims)?)?;
+    let signature = keypair.sign(claims.get().as_bytes());
+    let token = Token {
+        claims,
+        signature: encode_hex(&signature.to_bytes()),
+    };
+
+    let mut signed = module.to_vec();
+    signed.extend(custom_section(CLAIMS_SECTION, &serde_json::to_vec(&token)?));
+    Ok(signed)
+}
+
+/// Returns the claims embedded in `wasm` without verifying
+/// them.
+pub fn read(wasm: &[u8]) -> anyhow::Result<Option<Claims>> {
+    match split(wasm)?.1 {
+        Some(token) => Ok(Some(serde_json::from_str(token.claims.get())?)),
+        None => Ok(None),
+    }
+}
+
+/// Checks the signed claims of modules before they are loaded.
+/// Only modules signed by one of its trusted issuers are
+/// accepted.
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    trusted: Vec<PublicKey>,
+}
+
+impl Verifier {
+    pub fn new(issuer: PublicKey) -> Self {
+        Verifier {
+            trusted: vec![issuer],
+        }
+    }
+
+    /// Trusts the issuers with the given hex encoded public
+    /// keys, of which there must be at least one.
+    pub fn from_hex<S: AsRef<str>>(issuers: &[S]) -> anyhow::Result<Self> {
+        let (first, rest) = issuers
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("no trusted issuers given"))?;
+        rest.iter().try_fold(
+            Verifier::new(parse_key(first.as_ref())?),
+            |verifier, issuer| verifier.trust_hex(issuer.as_ref()),
+        )
+    }
+
+    /// Also accepts modules signed by `issuer`.
+    pub fn trust(mut self, issuer: PublicKey) -> Self {
+        self.trusted.push(issuer);
+        self
+    }
+
+    /// Trusts the issuer with the given hex encoded public key.
+    pub fn trust_hex(self, issuer: &str) -> anyhow::Result<Self> {
+        Ok(self.trust(parse_key(issuer)?))
+    }
+
+    /// Returns the claims of `wasm`, failing if it is unsigned,
+    /// was changed after signing, is signed by an untrusted
+    /// issuer, or its claims have expired.
+    pub fn verify(&self, wasm: &[u8]) -> anyhow::Result<Claims> {
+        let (module, token) = split(wasm)?;
+        let token = token.ok_or_else(|| anyhow::anyhow!("module is not signed"))?;
+        let signature = Signature::from_bytes(&decode_hex(&token.signature)?)
+            .map_err(|e| anyhow::anyhow!("invalid signature: {}", e))?;
+        let signed = token.claims.get().as_bytes();
+        // The claims name their issuer, but only a trusted key may
+        // vouch for them.
+        let issuer = self
+            .trusted
+            .iter()
+            .find(|issuer| issuer.verify_strict(signed, &signature).is_ok())
+            .ok_or_else(|| anyhow::anyhow!("claims signature does not match a trusted issuer"))?;
+
+        let claims: Claims = serde_json::from_str(token.claims.get())?;
+        if claims.issuer != encode_hex(issuer.as_bytes()) {
+            anyhow::bail!(
+                "claims name issuer {} but were signed by another",
+                claims.issuer
+            );
+        }
+        if claims.module_hash != encode_hex(&Sha256::digest(module)) {
+            anyhow::bail!("module was changed after it was signed");
+        }
+        if let Some(expires) = claims.expires {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            if now >= expires {
+                anyhow::bail!("module claims expired at {}", expires);
+            }
+        }
+        Ok(claims)
+    }
+
+    /// Verifies `wasm` and compiles it.
+    pub fn load(
+        &self,
+        engine: &wasmtime::Engine,
+        wasm: &[u8],
+    ) -> anyhow::Result<(wasmtime::Module, Claims)> {
+        let claims = self.verify(wasm)?;
+        Ok((wasmtime::Module::new(engine, wasm)?, claims))
+    }
+}
+
+fn parse_key(hex: &str) -> anyhow::Result<PublicKey> {
+    PublicKey::from_bytes(&decode_hex(hex.trim())?)
+        .map_err(|e| anyhow::anyhow!("invalid public key: {}", e))
+}
+
+/// Splits `wasm` into the module proper and its claims.
+fn split(wasm: &[u8]) -> anyhow::Result<(&[u8], Option<Token>)> {
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::CustomSection(reader) = payload? {
+            let (name, data) = (reader.name(), reader.data());
+            if name != CLAIMS_SECTION {
+                continue;
+            }
+            // Anything after the claims would not be covered by
+            // the module hash.
+            let section = custom_section(name, data);
+            if !wasm.ends_with(&section) {
+                anyhow::bail!("`{}` is not the last section of the module", CLAIMS_SECTION);
+            }
+            let token = serde_json::from_slice(data)?;
+            return Ok((&wasm[..wasm.len() - section.len()], Some(token)));
+        }
+    }
+    Ok((wasm, None))
+}
+
+fn custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut contents = Vec::new();
+    leb128(&mut contents, name.len());
+    contents.extend(name.as_bytes());
+    contents.extend(data);
+
+    let mut section = vec![0];
+    leb128(&mut section, contents.len());
+    section.extend(contents);
+    section
+}
+
+fn leb128(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SecretKey;
+
+    use super::*;
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            issuer: String::new(),
+            subject: "greeter".to_string(),
+            capabilities: Vec::new(),
+            expires: None,
+            module_hash: String::new(),
+        }
+    }
+
+    fn module() -> Vec<u8> {
+        wat::parse_str(r#"(module (@custom "note" "hello"))"#).unwrap()
+    }
+
+    fn replace(wasm: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+        let at = wasm
+            .windows(from.len())
+            .position(|window| window == from)
+            .unwrap();
+        [&wasm[..at], to, &wasm[at + from.len()..]].concat()
+    }
+
+    #[test]
+    fn accepts_modules_signed_by_a_trusted_issuer() {
+        let issuer = keypair(1);
+        let signed = sign(&module(), claims(), &issuer).unwrap();
+
+        let claims = Verifier::new(keypair(2).public)
+            .trust(issuer.public)
+            .verify(&signed)
+            .unwrap();
+        assert_eq!(claims.subject, "greeter");
+        assert_eq!(claims.issuer, encode_hex(issuer.public.as_bytes()));
+    }
+
+    #[test]
+    fn refuses_tampered_modules_and_claims() {
+        let issuer = keypair(1);
+        let verifier = Verifier::new(issuer.public);
+        let signed = sign(&module(), claims(), &issuer).unwrap();
+
+        let module = replace(&signed, b"hello", b"jello");
+        let error = verifier.verify(&module).unwrap_err();
+        assert!(
+            error.to_string().contains("changed after it was signed"),
+            "{}",
+            error
+        );
+
+        let claims = replace(&signed, b"greeter", b"greeted");
+        let error = verifier.verify(&claims).unwrap_err();
+        assert!(
+            error.to_string().contains("signature does not match"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn refuses_unsigned_and_untrusted_modules() {
+        let verifier = Verifier::new(keypair(1).public);
+        let error = verifier.verify(&module()).unwrap_err();
+        assert!(error.to_string().contains("not signed"), "{}", error);
+
+        let signed = sign(&module(), claims(), &keypair(2)).unwrap();
+        let error = verifier.verify(&signed).unwrap_err();
+        assert!(
+            error.to_string().contains("signature does not match"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn requires_a_trusted_issuer() {
+        assert!(Verifier::from_hex::<&str>(&[]).is_err());
+        let key = encode_hex(keypair(1).public.as_bytes());
+        assert!(Verifier::from_hex(&[key]).is_ok());
+    }
+}
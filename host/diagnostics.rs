@@ -0,0 +1,253 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use wit_bindgen_wasmtime::wasmtime;
+
+/// A host request made by the guest, as seen when the guest
+/// asked for it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct HostCall {
+    pub binding: String,
+    pub namespace: String,
+    pub operation: String,
+}
+
+/// The call into the guest which failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "call", rename_all = "kebab-case")]
+pub enum Call {
+    Invoke {
+        operation: String,
+    },
+    Respond {
+        id: u32,
+        request: Option<Box<HostCall>>,
+    },
+    Fail {
+        id: u32,
+        request: Option<Box<HostCall>>,
+    },
+    Cancel {
+        id: u32,
+    },
+    Stream {
+        id: u32,
+    },
+}
+
+/// One frame of a wasm backtrace, innermost first.
+///
+/// `symbols` are only filled in from DWARF when the engine was
+/// configured with `Config::wasm_backtrace_details`, while
+/// `function` comes from the name section when there is one.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Frame {
+    pub module: Option<String>,
+    pub function_index: u32,
+    pub function: Option<String>,
+    pub module_offset: Option<usize>,
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Symbol {
+    pub name: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl From<&wasmtime::FrameInfo> for Frame {
+    fn from(frame: &wasmtime::FrameInfo) -> Self {
+        Frame {
+            module: frame.module_name().map(String::from),
+            function_index: frame.func_index(),
+            function: frame.func_name().map(demangle),
+            module_offset: frame.module_offset(),
+            symbols: frame
+                .symbols()
+                .iter()
+                .map(|symbol| Symbol {
+                    name: symbol.name().map(demangle),
+                    file: symbol.file().map(String::from),
+                    line: symbol.line(),
+                    column: symbol.column(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn demangle(name: &str) -> String {
+    rustc_demangle::demangle(name).to_string()
+}
+
+/// The error returned by calls into a guest through an
+/// `Instance`, carrying what the runtime knew about the call
+/// alongside the trap itself.
+#[derive(Debug)]
+pub struct CallError {
+    pub call: Call,
+    pub trap: wasmtime::Trap,
+    pub frames: Vec<Frame>,
+    /// Invocations which were failed because the guest trapped.
+    pub abandoned: Vec<u32>,
+    /// Where a dump of the guest was written, if it was.
+    pub dump: Option<PathBuf>,
+    /// Set when the guest is waiting out the backoff of its
+    /// restart policy: the call may be retried after this long.
+    pub retry_after: Option<Duration>,
+}
+
+impl CallError {
+    pub(crate) fn new(call: Call, trap: wasmtime::Trap) -> Self {
+        let frames = trap.trace().unwrap_or(&[]).iter().map(Frame::from).collect();
+        CallError {
+            call,
+            trap,
+            frames,
+            abandoned: Vec::new(),
+            dump: None,
+            retry_after: None,
+        }
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.call {
+            Call::Invoke { operation } => write!(f, "guest failed invoking `{}`", operation)?,
+            Call::Respond { id, request } | Call::Fail { id, request } => {
+                let verb = match self.call {
+                    Call::Respond { .. } => "answering",
+                    _ => "failing",
+                };
+                write!(f, "guest failed {} host request {}", verb, id)?;
+                if let Some(r) = request {
+                    write!(f, " ({}/{}/{})", r.binding, r.namespace, r.operation)?;
+                }
+            }
+            Call::Cancel { id } => write!(f, "guest failed cancelling invocation {}", id)?,
+            Call::Stream { id } => write!(f, "guest failed handling a stream of request {}", id)?,
+        }
+        if !self.abandoned.is_empty() {
+            write!(f, ", abandoning invocations {:?}", self.abandoned)?;
+        }
+        write!(f, ": {}", self.trap)?;
+        if let Some(dump) = &self.dump {
+            write!(f, "\nguest dump written to {}", dump.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.trap)
+    }
+}
+
+#[derive(Serialize)]
+struct Dump<'a> {
+    call: &'a Call,
+    trap: String,
+    frames: &'a [Frame],
+    abandoned: &'a [u32],
+    memory: Option<&'a str>,
+}
+
+/// Counts the dumps written by this process, keeping their names
+/// apart.
+static DUMPS: AtomicU64 = AtomicU64::new(0);
+
+/// Writes a post-mortem dump of a trapped guest into `dir`.
+/// Returns the path of its `.json` file.
+///
+/// This is not a wasm core dump, which the wasmtime release in
+/// use cannot produce, but two files named
+/// `wapc-<pid>-<millis>-<n>`:
+///
+/// - `.json`: an object with the failed `call` (as in
+///   `CallError::call`), the `trap` message, the backtrace
+///   `frames`, the `abandoned` invocation ids and the file name
+///   of the `memory` dump, or `null`.
+/// - `.memory`: when the guest exports a memory, its raw
+///   contents from address 0.
+///
+/// Existing files are never overwritten.
+pub(crate) fn write_dump(
+    dir: &Path,
+    error: &CallError,
+    memory: Option<&[u8]>,
+) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    let (stem, path, mut json) = loop {
+        let n = DUMPS.fetch_add(1, Ordering::Relaxed);
+        let stem = format!("wapc-{}-{}-{}", std::process::id(), millis, n);
+        let path = dir.join(format!("{}.json", stem));
+        match create_new(&path) {
+            Ok(json) => break (stem, path, json),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+
+    let memory_name = format!("{}.memory", stem);
+    if let Some(memory) = memory {
+        create_new(&dir.join(&memory_name))?.write_all(memory)?;
+    }
+    let dump = Dump {
+        call: &error.call,
+        trap: error.trap.to_string(),
+        frames: &error.frames,
+        abandoned: &error.abandoned,
+        memory: memory.map(|_| memory_name.as_str()),
+    };
+    let mut out = serde_json::to_string_pretty(&dump)?;
+    out.push('\n');
+    json.write_all(out.as_bytes())?;
+    Ok(path)
+}
+
+fn create_new(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_never_overwrite_each_other() {
+        let dir = std::env::temp_dir().join(format!("wapc-dumps-{}", std::process::id()));
+        let error = CallError {
+            call: Call::Invoke {
+                operation: "echo".to_string(),
+            },
+            trap: wasmtime::Trap::new("unreachable"),
+            frames: Vec::new(),
+            abandoned: vec![1],
+            dump: None,
+            retry_after: None,
+        };
+        let first = write_dump(&dir, &error, Some(b"first")).unwrap();
+        let second = write_dump(&dir, &error, Some(b"second")).unwrap();
+        assert_ne!(first, second);
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&second).unwrap()).unwrap();
+        assert_eq!(json["call"]["operation"], "echo");
+        assert_eq!(json["abandoned"], serde_json::json!([1]));
+        let memory = dir.join(json["memory"].as_str().unwrap());
+        assert_eq!(std::fs::read(memory).unwrap(), b"second");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use wit_bindgen_wasmtime::{anyhow, wasmtime};
+
+use crate::payload::{Codec, Payload};
+use crate::policy::{Policy, Restricted};
+use crate::runtime::Instance;
+use crate::stub::{self, Fixtures, GuestResult, StubHost};
+
+/// A single regression case, stored as a JSON file:
+///
+/// ```json
+/// {
+///   "operation": "greet",
+///   "codec": "msgpack",
+///   "input": { "json": { "name": "world" } },
+///   "host": [{ "binding": "kv", "operation": "get", "response": "hello" }],
+///   "expected": { "response": { "json": { "greeting": "hello world" } } }
+/// }
+/// ```
+///
+/// `{"json": ...}` payloads, including those of the scripted
+/// host responses, are encoded with `codec` (JSON by default)
+/// on the way into the guest, and the guest's answer is decoded
+/// with it before being compared.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Case {
+    pub operation: String,
+    #[serde(default)]
+    pub codec: Codec,
+    #[serde(default)]
+    pub input: Payload,
+    #[serde(default)]
+    pub host: Fixtures,
+    pub expected: Option<Expected>,
+}
+
+/// The answer a case expects from the guest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Expected {
+    Response(Payload),
+    Error(Payload),
+}
+
+/// The result of running one case.
+#[derive(Debug)]
+pub struct Outcome {
+    pub path: PathBuf,
+    pub status: Status,
+}
+
+#[derive(Debug)]
+pub enum Status {
+    Passed,
+    /// The expected output was (re)written in bless mode.
+    Blessed,
+    Failed {
+        expected: Option<Expected>,
+        actual: Expected,
+    },
+    /// The case could not be run at all.
+    Errored(anyhow::Error),
+}
+
+impl Outcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self.status, Status::Passed | Status::Blessed)
+    }
+}
+
+/// Runs every `*.json` case in `dir` against `module`, in file
+/// name order, each on a fresh instance whose host requests
+/// must be allowed by every one of `policies`.
+///
+/// With `bless` set, cases whose output differs from (or lacks)
+/// an `expected` entry have it rewritten to the actual output.
+pub fn run_dir(
+    module: &wasmtime::Module,
+    dir: impl AsRef<Path>,
+    policies: &[Policy],
+    bless: bool,
+) -> anyhow::Result<Vec<Outcome>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            let status = run_case(module, &path, policies, bless).unwrap_or_else(Status::Errored);
+            Outcome { path, status }
+        })
+        .collect())
+}
+
+fn run_case(
+    module: &wasmtime::Module,
+    path: &Path,
+    policies: &[Policy],
+    bless: bool,
+) -> anyhow::Result<Status> {
+    let case: Case = serde_json::from_slice(&std::fs::read(path)?)?;
+    let input = case.input.encode(case.codec)?;
+
+    let host = Restricted::new(StubHost::new().on_log(|_| {}), Policy::allow_all());
+    let host = policies.iter().cloned().fold(host, Restricted::and);
+    let mut instance = Instance::new(module, host)?;
+    let result = stub::call(&mut instance, &case.operation, &input, |request| {
+        case.host.answer_with(request, case.codec)
+    })?;
+
+    if let Some(expected) = &case.expected {
+        if passes(expected, &result, case.codec)? {
+            return Ok(Status::Passed);
+        }
+    }
+    let actual = actual(case.expected.as_ref(), result, case.codec);
+    if bless {
+        bless_case(path, &actual)?;
+        return Ok(Status::Blessed);
+    }
+    Ok(Status::Failed {
+        expected: case.expected,
+        actual,
+    })
+}
+
+/// Compares JSON expectations as documents, so key order and
+/// formatting do not matter, and anything else byte for byte.
+fn passes(expected: &Expected, result: &GuestResult, codec: Codec) -> anyhow::Result<bool> {
+    let (payload, bytes) = match (expected, result) {
+        (Expected::Response(payload), Ok(bytes)) => (payload, bytes),
+        (Expected::Error(payload), Err(bytes)) => (payload, bytes),
+        _ => return Ok(false),
+    };
+    Ok(match payload {
+        Payload::Json { json } => codec.decode(bytes).is_ok_and(|actual| actual == *json),
+        other => other.encode(codec)? == *bytes,
+    })
+}
+
+/// Renders the guest's answer in the same form as the case's
+/// expectation, for diffs and for blessing.
+fn actual(expected: Option<&Expected>, result: GuestResult, codec: Codec) -> Expected {
+    let expected_json = match expected {
+        Some(Expected::Response(p)) | Some(Expected::Error(p)) => {
+            matches!(p, Payload::Json { .. })
+        }
+        None => true,
+    };
+    let render = |bytes: Vec<u8>| {
+        if expected_json {
+            Payload::decode(&bytes, codec)
+        } else {
+            Payload::from_bytes(&bytes)
+        }
+    };
+    match result {
+        Ok(bytes) => Expected::Response(render(bytes)),
+        Err(bytes) => Expected::Error(render(bytes)),
+    }
+}
+
+/// Rewrites the `expected` entry of the case at `path`, leaving
+/// everything else in the file as it was.
+fn bless_case(path: &Path, actual: &Expected) -> anyhow::Result<()> {
+    let mut case: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    case.as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("case is not a JSON object"))?
+        .insert("expected".to_string(), serde_json::to_value(actual)?);
+    let mut out = serde_json::to_string_pretty(&case)?;
+    out.push('\n');
+    std::fs::write(path, out)?;
+    Ok(())
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.path.display();
+        match &self.status {
+            Status::Passed => write!(f, "{} ... ok", name),
+            Status::Blessed => write!(f, "{} ... blessed", name),
+            Status::Errored(e) => write!(f, "{} ... ERROR\n  {:?}", name, e),
+            Status::Failed { expected, actual } => {
+                writeln!(f, "{} ... FAILED", name)?;
+                write!(f, "{}", diff(&show(expected.as_ref()), &show(Some(actual))))
+            }
+        }
+    }
+}
+
+fn show(expected: Option<&Expected>) -> String {
+    match expected {
+        Some(e) => serde_json::to_string_pretty(e).unwrap_or_default(),
+        None => "(no expected output)".to_string(),
+    }
+}
+
+/// A minimal line diff: lines only in `expected` are prefixed
+/// with `-` and lines only in `actual` with `+`.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected = expected.lines().collect::<Vec<_>>();
+    let actual = actual.lines().collect::<Vec<_>>();
+
+    // Longest common subsequence table, filled from the end.
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            out.push_str(&format!("    {}\n", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j < actual.len() && (i == expected.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out.push_str(&format!("  + {}\n", actual[j]));
+            j += 1;
+        } else {
+            out.push_str(&format!("  - {}\n", expected[i]));
+            i += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo() -> wasmtime::Module {
+        let wasm = wat::parse_str(include_str!("../benches/guests/echo.wat")).unwrap();
+        wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap()
+    }
+
+    /// A fresh case directory holding `cases`, by file name.
+    fn case_dir(name: &str, cases: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wapc-golden-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, case) in cases {
+            std::fs::write(dir.join(file), case).unwrap();
+        }
+        dir
+    }
+
+    fn statuses(outcomes: &[Outcome]) -> Vec<(String, &Status)> {
+        outcomes
+            .iter()
+            .map(|o| {
+                (
+                    o.path.file_name().unwrap().to_string_lossy().into_owned(),
+                    &o.status,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_marks_removed_and_added_lines() {
+        assert_eq!(diff("a\nb\nc", "a\nb\nc"), "    a\n    b\n    c\n");
+        assert_eq!(diff("a\nb\nc", "a\nc"), "    a\n  - b\n    c\n");
+        assert_eq!(diff("a\nc", "a\nb\nc"), "    a\n  + b\n    c\n");
+        assert_eq!(diff("a\nb\nc", "a\nx\nc"), "    a\n  + x\n  - b\n    c\n");
+        assert_eq!(diff("", "a"), "  + a\n");
+        assert_eq!(diff("a", ""), "  - a\n");
+    }
+
+    #[test]
+    fn diff_keeps_the_longest_common_lines() {
+        assert_eq!(
+            diff("x\na\nb\nc", "a\nb\nc\nx"),
+            "  - x\n    a\n    b\n    c\n  + x\n"
+        );
+    }
+
+    #[test]
+    fn runs_passing_and_failing_cases() {
+        let dir = case_dir(
+            "run",
+            &[
+                (
+                    "json.json",
+                    r#"{"operation": "echo", "input": {"json": {"b": 1, "a": [2]}},
+                        "expected": {"response": {"json": {"a": [2], "b": 1}}}}"#,
+                ),
+                (
+                    "hex.json",
+                    r#"{"operation": "echo", "input": {"hex": "ff00"},
+                        "expected": {"response": {"hex": "ff00"}}}"#,
+                ),
+                (
+                    "text.json",
+                    r#"{"operation": "echo", "input": "hello",
+                        "expected": {"response": "goodbye"}}"#,
+                ),
+                (
+                    "wrong-json.json",
+                    r#"{"operation": "echo", "input": {"json": [1, 2]},
+                        "expected": {"response": {"json": [1]}}}"#,
+                ),
+                ("broken.json", r#"{"operation": "#),
+                ("notes.txt", "not a case"),
+            ],
+        );
+        let outcomes = run_dir(&echo(), &dir, &[], false).unwrap();
+        let statuses = statuses(&outcomes);
+        let names = statuses
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "broken.json",
+                "hex.json",
+                "json.json",
+                "text.json",
+                "wrong-json.json"
+            ]
+        );
+        assert!(matches!(statuses[0].1, Status::Errored(_)));
+        assert!(matches!(statuses[1].1, Status::Passed));
+        assert!(matches!(statuses[2].1, Status::Passed));
+        // Rendered in the form the case expects, bytes or JSON.
+        assert!(matches!(
+            statuses[3].1,
+            Status::Failed { actual: Expected::Response(Payload::Text(text)), .. } if text == "hello"
+        ));
+        assert!(matches!(
+            statuses[4].1,
+            Status::Failed { actual: Expected::Response(Payload::Json { json }), .. }
+                if *json == serde_json::json!([1, 2])
+        ));
+        let report = outcomes[4].to_string();
+        assert!(
+            report.contains("wrong-json.json ... FAILED\n"),
+            "{}",
+            report
+        );
+        assert!(
+            report.contains("  +       1,\n  +       2\n  -       1\n"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn blessing_rewrites_only_the_expected_output() {
+        let dir = case_dir(
+            "bless",
+            &[
+                (
+                    "new.json",
+                    r#"{"operation": "echo", "input": {"json": {"a": 1}}}"#,
+                ),
+                (
+                    "stale.json",
+                    r#"{"operation": "echo", "input": "hello", "expected": {"error": "nope"}}"#,
+                ),
+                (
+                    "passing.json",
+                    r#"{"operation": "echo", "input": "hi", "expected": {"response": "hi"}}"#,
+                ),
+            ],
+        );
+        let passing = std::fs::read_to_string(dir.join("passing.json")).unwrap();
+        let outcomes = run_dir(&echo(), &dir, &[], true).unwrap();
+        let statuses = statuses(&outcomes);
+        assert!(matches!(statuses[0].1, Status::Blessed));
+        assert!(matches!(statuses[1].1, Status::Passed));
+        assert!(matches!(statuses[2].1, Status::Blessed));
+        assert!(outcomes.iter().all(Outcome::is_success));
+
+        let read = |file: &str| -> serde_json::Value {
+            serde_json::from_slice(&std::fs::read(dir.join(file)).unwrap()).unwrap()
+        };
+        assert_eq!(
+            read("new.json"),
+            serde_json::json!({
+                "operation": "echo",
+                "input": {"json": {"a": 1}},
+                "expected": {"response": {"json": {"a": 1}}},
+            })
+        );
+        assert_eq!(
+            read("stale.json")["expected"],
+            serde_json::json!({"response": "hello"})
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("passing.json")).unwrap(),
+            passing
+        );
+
+        let outcomes = run_dir(&echo(), &dir, &[], false).unwrap();
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome.status, Status::Passed)));
+    }
+}
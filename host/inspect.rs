@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use wit_bindgen_wasmtime::{anyhow, wasmtime};
+
+use crate::validate::{self, describe};
+
+/// Name of the custom section guests may embed to describe
+/// themselves. Its contents are a JSON encoded `Metadata`.
+pub const METADATA_SECTION: &str = "wapc-metadata";
+
+/// The host/guest ABI a module targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Abi {
+    /// The component-style ABI generated from `wapc-guest.wit`
+    /// and `wapc-host.wit`.
+    Wit,
+    /// The classic waPC ABI (`__guest_call`, `wapc::__host_call`).
+    Classic,
+    Unknown,
+}
+
+/// Self-description embedded by a guest in `METADATA_SECTION`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sdk: Option<String>,
+    #[serde(default)]
+    pub operations: Vec<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Item {
+    pub module: Option<String>,
+    pub name: String,
+    pub ty: String,
+}
+
+/// Everything `wapc-inspect` knows about a module.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub abi: Abi,
+    pub imports: Vec<Item>,
+    pub exports: Vec<Item>,
+    pub issues: Vec<String>,
+    pub metadata: Option<Metadata>,
+}
+
+/// Compiles `wasm` with `engine` and describes its ABI, its
+/// imports and exports, and any embedded metadata.
+///
+/// Modules targeting the WIT ABI are also run through
+/// `validate::check`, whose findings are listed in `issues`, as
+/// is a malformed `METADATA_SECTION`.
+pub fn inspect(engine: &wasmtime::Engine, wasm: &[u8]) -> anyhow::Result<Report> {
+    let module = wasmtime::Module::new(engine, wasm)?;
+
+    let imports = module
+        .imports()
+        .map(|i| Item {
+            module: Some(i.module().to_string()),
+            name: i.name().to_string(),
+            ty: describe(&i.ty()),
+        })
+        .collect::<Vec<_>>();
+    let exports = module
+        .exports()
+        .map(|e| Item {
+            module: None,
+            name: e.name().to_string(),
+            ty: describe(&e.ty()),
+        })
+        .collect::<Vec<_>>();
+
+    let abi = detect_abi(&imports, &exports);
+    let mut issues = match abi {
+        Abi::Classic => Vec::new(),
+        Abi::Wit | Abi::Unknown => validate::check(&module)
+            .iter()
+            .map(|i| i.to_string())
+            .collect(),
+    };
+    let metadata = metadata(wasm).unwrap_or_else(|e| {
+        issues.push(format!("malformed `{}` section: {}", METADATA_SECTION, e));
+        None
+    });
+
+    Ok(Report {
+        abi,
+        imports,
+        exports,
+        issues,
+        metadata,
+    })
+}
+
+/// Reads and decodes the `METADATA_SECTION` of `wasm`, if any.
+pub fn metadata(wasm: &[u8]) -> anyhow::Result<Option<Metadata>> {
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::CustomSection(reader) = payload? {
+            if reader.name() == METADATA_SECTION {
+                return Ok(Some(serde_json::from_slice(reader.data())?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn detect_abi(imports: &[Item], exports: &[Item]) -> Abi {
+    let exports_any = |names: &[&str]| exports.iter().any(|e| names.contains(&e.name.as_str()));
+    let imports_from = |module: &str| imports.iter().any(|i| i.module.as_deref() == Some(module));
+
+    if exports_any(&["guest::init-guest-request"]) || imports_from("wapc-host") {
+        Abi::Wit
+    } else if exports_any(&["__guest_call"]) || imports_from("wapc") {
+        Abi::Classic
+    } else {
+        Abi::Unknown
+    }
+}
+
+impl fmt::Display for Abi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Abi::Wit => write!(f, "wapc (wit: wapc-guest / wapc-host)"),
+            Abi::Classic => write!(f, "wapc (classic)"),
+            Abi::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "abi: {}", self.abi)?;
+        if let Some(metadata) = &self.metadata {
+            if let Some(sdk) = &metadata.sdk {
+                writeln!(f, "sdk: {}", sdk)?;
+            }
+            writeln!(f, "operations:")?;
+            for op in &metadata.operations {
+                writeln!(f, "  {}", op)?;
+            }
+            for (key, value) in &metadata.extra {
+                writeln!(f, "{}: {}", key, value)?;
+            }
+        }
+        writeln!(f, "imports:")?;
+        for i in &self.imports {
+            writeln!(f, "  {}::{} {}", i.module.as_deref().unwrap_or(""), i.name, i.ty)?;
+        }
+        writeln!(f, "exports:")?;
+        for e in &self.exports {
+            writeln!(f, "  {} {}", e.name, e.ty)?;
+        }
+        if self.abi == Abi::Classic && self.issues.is_empty() {
+            return Ok(());
+        }
+        if self.issues.is_empty() {
+            writeln!(f, "validation: ok")
+        } else {
+            writeln!(f, "validation: {} issue(s)", self.issues.len())?;
+            for issue in &self.issues {
+                writeln!(f, "  - {}", issue)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(wat: &str) -> Report {
+        let wasm = wat::parse_str(wat).unwrap();
+        inspect(&wasmtime::Engine::default(), &wasm).unwrap()
+    }
+
+    #[test]
+    fn reads_embedded_metadata() {
+        let report = report(
+            r#"(module (@custom "wapc-metadata" "{\"sdk\": \"rust\", \"operations\": [\"echo\"]}"))"#,
+        );
+        let metadata = report.metadata.unwrap();
+        assert_eq!(metadata.sdk.as_deref(), Some("rust"));
+        assert_eq!(metadata.operations, ["echo"]);
+    }
+
+    #[test]
+    fn malformed_metadata_is_an_issue() {
+        let report = report(
+            r#"(module
+                (import "wapc" "__host_call" (func))
+                (@custom "wapc-metadata" "{\"operations\": "))"#,
+        );
+        assert_eq!(report.abi, Abi::Classic);
+        assert!(report.metadata.is_none());
+        assert_eq!(report.issues.len(), 1);
+        assert!(report.issues[0].starts_with("malformed `wapc-metadata` section"));
+        assert!(report.to_string().contains("validation: 1 issue(s)"));
+    }
+}
@@ -0,0 +1,29 @@
+mod bindings;
+
+pub mod audit;
+pub mod cache;
+pub mod claims;
+pub mod diagnostics;
+pub mod golden;
+pub mod inspect;
+pub mod mesh;
+pub mod metrics;
+pub mod payload;
+pub mod policy;
+pub mod pool;
+pub mod repl;
+pub mod runtime;
+pub mod stream;
+pub mod stub;
+pub mod telemetry;
+pub mod trace;
+pub mod validate;
+pub mod wapc_guest;
+
+/// The host side of `wapc-host.wit`. The generated
+/// `add_to_linker` is left out: its `init-host-request` sizes a
+/// `Vec` by a length the guest controls, so `Instance` links a
+/// checked import in its place.
+pub mod wapc_host {
+    pub use crate::bindings::wapc_host::{StreamKind, WapcHost};
+}
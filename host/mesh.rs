@@ -0,0 +1,834 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use wit_bindgen_wasmtime::anyhow;
+
+use crate::diagnostics::CallError;
+use crate::policy::Policy;
+use crate::runtime::{Answer, Hooks, Instance};
+use crate::stream::STREAM;
+use crate::stub::{HostRequest, StubHost};
+use crate::wapc_host::{StreamKind, WapcHost};
+
+/// The binding of host requests for another guest: the
+/// namespace names the guest, and the operation is invoked
+/// there.
+pub const BINDING: &str = "wapc";
+
+/// The metadata key listing the guests a routed call passed
+/// through, comma-separated, the target last. It is only there
+/// for the guests' information: the mesh keeps track of call
+/// chains itself, and drops the entry from the requests it
+/// routes.
+pub const CALL_CHAIN: &str = "wapc-call-chain";
+
+/// The default for `Mesh::max_depth`.
+pub const MAX_DEPTH: usize = 8;
+
+/// A host request for `BINDING`, waiting to be routed.
+struct Call {
+    id: u32,
+    to: String,
+    operation: String,
+    payload: Vec<u8>,
+    metadata: Vec<(String, String)>,
+    /// The guests the call passed through, the caller last.
+    chain: Vec<String>,
+}
+
+/// A host request the guest is waiting on.
+struct Request {
+    chain: Vec<String>,
+    /// Whether it is for `BINDING`, which the mesh answers in
+    /// place of the wrapped host.
+    routed: bool,
+}
+
+/// A `WapcHost` wrapper which takes a guest's host requests for
+/// `BINDING` for a `Mesh` to route, and the answers to the
+/// invocations the mesh routed to the guest.
+///
+/// Routed requests and invocations go through the wrapped host
+/// like any other, so that its policy, audit log, traces and
+/// metrics see them, and routed requests get their ids from it.
+/// Only then does the wrapper take them back from the
+/// `StubHost` underneath, which would otherwise answer them. A
+/// request the wrapped host settles on its own, such as one its
+/// policy refuses, is not routed.
+///
+/// The wrapper keeps the chain of guests each call passed
+/// through. A routed invocation's chain is the one of the call
+/// the mesh routed, and any other invocation's is the guest
+/// alone. A host request gets the chain of the invocation whose
+/// call into the guest made it: the one being started or
+/// cancelled, or the one which made the host request being
+/// answered. Requests made at any other time, such as those
+/// held back for lack of room, get the longest chain of the
+/// invocations in flight. Chains guests send as `CALL_CHAIN`
+/// are dropped.
+pub struct Routing<H: WapcHost> {
+    inner: H,
+    /// The name the mesh knows the guest by.
+    name: String,
+    /// The chain of the call the guest is handling, while known.
+    current: Option<Vec<String>>,
+    /// The chain of the invocation the mesh is about to route.
+    expected: Option<Vec<String>>,
+    /// The chain of the routed invocation being started, until
+    /// the guest returns its id.
+    starting: Option<Vec<String>>,
+    /// The chains of routed invocations, by id.
+    invocations: HashMap<u32, Vec<String>>,
+    requests: HashMap<u32, Request>,
+    calls: VecDeque<Call>,
+    /// Routed requests the guest cancelled, or forgot by
+    /// trapping.
+    cancelled: VecDeque<u32>,
+    /// The guest's answers to routed invocations.
+    answers: VecDeque<(u32, Answer)>,
+}
+
+impl<H: WapcHost> Routing<H> {
+    pub fn new(inner: H) -> Self {
+        Routing {
+            inner,
+            name: String::new(),
+            current: None,
+            expected: None,
+            starting: None,
+            invocations: HashMap::new(),
+            requests: HashMap::new(),
+            calls: VecDeque::new(),
+            cancelled: VecDeque::new(),
+            answers: VecDeque::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H> Routing<H>
+where
+    H: WapcHost + AsMut<StubHost>,
+{
+    /// The chain of a host request made now.
+    fn context(&self) -> Vec<String> {
+        if let Some(chain) = &self.current {
+            return chain.clone();
+        }
+        self.invocations
+            .values()
+            .chain(&self.starting)
+            .max_by_key(|chain| chain.len())
+            .cloned()
+            .unwrap_or_else(|| vec![self.name.clone()])
+    }
+
+    /// Takes the guest's answer to routed invocation `id` back
+    /// from the `StubHost`, once the wrapped host has passed it
+    /// on. Answers given while the invocation is being started
+    /// are taken once the guest returns its id.
+    fn answered(&mut self, id: u32) {
+        if !self.invocations.contains_key(&id) {
+            return;
+        }
+        if let Some(answer) = self.inner.as_mut().take_answer(id) {
+            self.invocations.remove(&id);
+            self.answers.push_back((id, answer));
+        }
+    }
+
+    /// Whether host request `id` is one for the mesh to route.
+    fn routes(&self, id: u32) -> bool {
+        self.requests.get(&id).is_some_and(|request| request.routed)
+    }
+}
+
+impl<H> AsMut<StubHost> for Routing<H>
+where
+    H: WapcHost + AsMut<StubHost>,
+{
+    fn as_mut(&mut self) -> &mut StubHost {
+        self.inner.as_mut()
+    }
+}
+
+impl<H> Hooks for Routing<H>
+where
+    H: WapcHost + Hooks + AsMut<StubHost>,
+{
+    fn on_invoke(&mut self, operation: &str, payload: &[u8], metadata: &mut Vec<(String, String)>) {
+        match self.expected.take() {
+            Some(chain) => {
+                self.current = Some(chain.clone());
+                self.starting = Some(chain);
+            }
+            None => self.current = Some(vec![self.name.clone()]),
+        }
+        self.inner.on_invoke(operation, payload, metadata)
+    }
+
+    fn on_invoked(&mut self, operation: &str, result: Result<u32, &CallError>) {
+        self.inner.on_invoked(operation, result);
+        if let (Some(chain), Ok(id)) = (self.starting.take(), result) {
+            self.invocations.insert(id, chain);
+            self.answered(id);
+        }
+    }
+
+    fn on_respond(&mut self, id: u32, code: u32, bytes: &[u8]) {
+        let request = self.requests.remove(&id);
+        self.current = request.map(|request| request.chain);
+        self.inner.on_respond(id, code, bytes)
+    }
+
+    fn on_fail(&mut self, id: u32, bytes: &[u8]) {
+        let request = self.requests.remove(&id);
+        self.current = request.map(|request| request.chain);
+        self.inner.on_fail(id, bytes)
+    }
+
+    fn on_cancel(&mut self, id: u32) {
+        self.current = match self.invocations.remove(&id) {
+            Some(chain) => Some(chain),
+            None => Some(vec![self.name.clone()]),
+        };
+        self.inner.on_cancel(id)
+    }
+
+    fn on_trap(&mut self, error: &CallError) {
+        // The routed invocations were failed already, and the
+        // routed calls still in flight are of no use anymore.
+        self.current = None;
+        self.invocations.clear();
+        self.calls.clear();
+        for (id, request) in self.requests.drain() {
+            if request.routed {
+                self.cancelled.push_back(id);
+            }
+        }
+        self.inner.on_trap(error)
+    }
+
+    fn take_settled(&mut self) -> Option<(u32, Answer)> {
+        // The call into the guest is over.
+        self.current = None;
+        self.inner.take_settled()
+    }
+}
+
+impl<H> WapcHost for Routing<H>
+where
+    H: WapcHost + AsMut<StubHost>,
+{
+    type Wapc = H::Wapc;
+
+    fn instance(&mut self) -> Self::Wapc {
+        self.inner.instance()
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        self_: &Self::Wapc,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        let chain = self.context();
+        let id = self
+            .inner
+            .wapc_init_host_request(self_, binding, namespace, operation, bytes, metadata);
+        let routed = match binding {
+            BINDING => self.inner.as_mut().take_request_by_id(id),
+            _ => None,
+        };
+        let request = Request {
+            chain: chain.clone(),
+            routed: routed.is_some(),
+        };
+        self.requests.insert(id, request);
+        let request = match routed {
+            Some(request) => request,
+            None => return id,
+        };
+        let metadata = request
+            .metadata
+            .into_iter()
+            .filter(|(key, _)| {
+                !key.eq_ignore_ascii_case(CALL_CHAIN) && !key.eq_ignore_ascii_case(STREAM)
+            })
+            .collect();
+        self.calls.push_back(Call {
+            id,
+            to: request.namespace,
+            operation: request.operation,
+            payload: request.payload,
+            metadata,
+            chain,
+        });
+        id
+    }
+
+    fn wapc_on_guest_response(&mut self, self_: &Self::Wapc, id: u32, code: u32, bytes: &[u8]) {
+        self.inner.wapc_on_guest_response(self_, id, code, bytes);
+        self.answered(id)
+    }
+
+    fn wapc_on_guest_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.inner.wapc_on_guest_error(self_, id, bytes);
+        self.answered(id)
+    }
+
+    fn wapc_console_log(&mut self, self_: &Self::Wapc, message: &str) {
+        self.inner.wapc_console_log(self_, message)
+    }
+
+    fn wapc_cancel(&mut self, self_: &Self::Wapc, id: u32) {
+        self.inner.wapc_cancel(self_, id);
+        if let Some(Request { routed: true, .. }) = self.requests.remove(&id) {
+            // The `StubHost` never had it to cancel.
+            self.inner.as_mut().take_cancelled_by_id(id);
+            let waiting = self.calls.len();
+            self.calls.retain(|call| call.id != id);
+            // Calls routed already are cancelled in the target.
+            if self.calls.len() == waiting {
+                self.cancelled.push_back(id);
+            }
+        }
+    }
+
+    fn wapc_stream_chunk(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, bytes: &[u8]) {
+        // Streams are not routed between guests.
+        let routed = match kind {
+            StreamKind::Request => self.routes(id),
+            StreamKind::Response => self.invocations.contains_key(&id),
+        };
+        if !routed {
+            self.inner.wapc_stream_chunk(self_, id, kind, bytes)
+        }
+    }
+
+    fn wapc_stream_end(&mut self, self_: &Self::Wapc, id: u32) {
+        if !self.routes(id) {
+            self.inner.wapc_stream_end(self_, id)
+        }
+    }
+
+    fn wapc_stream_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        if !self.routes(id) {
+            self.inner.wapc_stream_error(self_, id, bytes)
+        }
+    }
+
+    fn wapc_stream_credit(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, chunks: u32) {
+        let routed = match kind {
+            StreamKind::Request => self.invocations.contains_key(&id),
+            StreamKind::Response => self.routes(id),
+        };
+        if !routed {
+            self.inner.wapc_stream_credit(self_, id, kind, chunks)
+        }
+    }
+
+    fn drop_wapc(&mut self, state: Self::Wapc) {
+        self.inner.drop_wapc(state)
+    }
+}
+
+/// A call routed to another guest, awaiting its answer.
+struct Routed {
+    from: String,
+    /// The caller's id for its host request.
+    request: u32,
+    to: String,
+    /// The target's id for the invocation.
+    invocation: u32,
+}
+
+/// What a guest left for the mesh to do.
+enum Pending {
+    Call(Call),
+    Cancelled(u32),
+    Answer(u32, Answer),
+}
+
+/// Named guests which call each other through host requests
+/// for `BINDING`.
+///
+/// Each guest runs in its own `Instance` over a `Routing` host,
+/// which holds back the guest's requests for `BINDING` for the
+/// mesh. `route` invokes the target guest with the request's
+/// metadata and answers the caller with whatever the target
+/// answers, response code and all. A call is failed instead
+/// when:
+///
+/// - no guest goes by the name;
+/// - no link from the caller to the target allows the
+///   operation, which fails it with `permission denied`;
+/// - the target is in the call's chain already, a cycle such
+///   as `a -> b -> a`;
+/// - the chain is `max_depth` calls deep already.
+///
+/// Call chains are kept by the `Routing` hosts, see there.
+/// Streams are not routed between guests, so the `STREAM`
+/// entry is not passed on.
+pub struct Mesh<H: WapcHost + AsMut<StubHost>> {
+    guests: BTreeMap<String, Instance<Routing<H>>>,
+    /// What each guest may call of another, by caller and
+    /// target.
+    links: HashMap<(String, String), Policy>,
+    max_depth: usize,
+    calls: Vec<Routed>,
+}
+
+impl<H> Mesh<H>
+where
+    H: WapcHost + Hooks + AsMut<StubHost> + 'static,
+{
+    pub fn new() -> Self {
+        Mesh {
+            guests: BTreeMap::new(),
+            links: HashMap::new(),
+            max_depth: MAX_DEPTH,
+            calls: Vec::new(),
+        }
+    }
+
+    /// Fails calls made once `max_depth` routed calls are
+    /// nested, instead of `MAX_DEPTH`.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Adds `instance` to the mesh as guest `name`.
+    pub fn add(&mut self, name: &str, mut instance: Instance<Routing<H>>) -> anyhow::Result<()> {
+        if self.guests.contains_key(name) {
+            anyhow::bail!("guest `{}` was added already", name);
+        }
+        instance.host_mut().name = name.to_string();
+        self.guests.insert(name.to_string(), instance);
+        Ok(())
+    }
+
+    /// Lets guest `from` call the operations of guest `to` which
+    /// `policy` allows, matched as host requests for `BINDING`
+    /// in namespace `to`. Guests which are not linked may not
+    /// call each other.
+    pub fn link(&mut self, from: &str, to: &str, policy: Policy) {
+        self.links
+            .insert((from.to_string(), to.to_string()), policy);
+    }
+
+    pub fn guest(&self, name: &str) -> Option<&Instance<Routing<H>>> {
+        self.guests.get(name)
+    }
+
+    pub fn guest_mut(&mut self, name: &str) -> Option<&mut Instance<Routing<H>>> {
+        self.guests.get_mut(name)
+    }
+
+    /// Routes the calls the guests made to each other, their
+    /// cancellations and the answers to them, until none are
+    /// left.
+    ///
+    /// Stops at the first call into a guest which fails, after
+    /// failing the routed call it was for; calling `route` again
+    /// carries on from there.
+    pub fn route(&mut self) -> anyhow::Result<()> {
+        while let Some((name, pending)) = self.next_pending() {
+            match pending {
+                Pending::Call(call) => self.forward(&name, call)?,
+                Pending::Cancelled(request) => self.cancel(&name, request)?,
+                Pending::Answer(invocation, answer) => self.deliver(&name, invocation, answer)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn next_pending(&mut self) -> Option<(String, Pending)> {
+        self.guests.iter_mut().find_map(|(name, instance)| {
+            // A guest whose host was lost leaves nothing to do.
+            let routing = instance.store_mut().data_mut().host_mut()?;
+            let pending = match routing.calls.pop_front() {
+                Some(call) => Pending::Call(call),
+                None => match routing.cancelled.pop_front() {
+                    Some(request) => Pending::Cancelled(request),
+                    None => {
+                        let (invocation, answer) = routing.answers.pop_front()?;
+                        Pending::Answer(invocation, answer)
+                    }
+                },
+            };
+            Some((name.clone(), pending))
+        })
+    }
+
+    fn instance(&mut self, name: &str) -> &mut Instance<Routing<H>> {
+        self.guests
+            .get_mut(name)
+            .expect("guests are never removed from the mesh")
+    }
+
+    fn allows(&self, from: &str, to: &str, operation: &str) -> bool {
+        self.links
+            .get(&(from.to_string(), to.to_string()))
+            .is_some_and(|policy| policy.allows(BINDING, to, operation))
+    }
+
+    /// Invokes the target of `call`, made by guest `from`, or
+    /// fails it.
+    fn forward(&mut self, from: &str, call: Call) -> anyhow::Result<()> {
+        let Call {
+            id,
+            to,
+            operation,
+            payload,
+            mut metadata,
+            mut chain,
+        } = call;
+        let refusal = if !self.guests.contains_key(&to) {
+            Some(format!("no guest `{}`", to))
+        } else if !self.allows(from, &to, &operation) {
+            Some("permission denied".to_string())
+        } else if chain.contains(&to) {
+            Some(format!("call cycle: {} -> {}", chain.join(" -> "), to))
+        } else if chain.len() > self.max_depth {
+            Some(format!(
+                "call depth exceeded: {} -> {}",
+                chain.join(" -> "),
+                to
+            ))
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            return self.answer(from, id, Answer::Error(refusal.into_bytes()));
+        }
+
+        chain.push(to.clone());
+        metadata.push((CALL_CHAIN.to_string(), chain.join(",")));
+        let instance = self.instance(&to);
+        if let Some(routing) = instance.store_mut().data_mut().host_mut() {
+            routing.expected = Some(chain);
+        }
+        let result = instance.invoke_with(&operation, &payload, &metadata);
+        // Unless the guest was never reached.
+        if let Some(routing) = instance.store_mut().data_mut().host_mut() {
+            routing.expected = None;
+        }
+        match result {
+            Ok(invocation) => {
+                self.calls.push(Routed {
+                    from: from.to_string(),
+                    request: id,
+                    to,
+                    invocation,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                let message = format!("guest `{}` trapped: {}", to, e.trap);
+                self.answer(from, id, Answer::Error(message.into_bytes()))?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Cancels the invocation routed for host request `request`
+    /// of guest `from`.
+    fn cancel(&mut self, from: &str, request: u32) -> anyhow::Result<()> {
+        let index = self
+            .calls
+            .iter()
+            .position(|call| call.from == from && call.request == request);
+        if let Some(index) = index {
+            let call = self.calls.remove(index);
+            self.instance(&call.to).cancel(call.invocation)?;
+        }
+        Ok(())
+    }
+
+    /// Passes on guest `to`'s answer to a routed invocation to
+    /// the guest which made the call.
+    fn deliver(&mut self, to: &str, invocation: u32, answer: Answer) -> anyhow::Result<()> {
+        let index = self
+            .calls
+            .iter()
+            .position(|call| call.to == to && call.invocation == invocation);
+        match index {
+            Some(index) => {
+                let call = self.calls.remove(index);
+                self.answer(&call.from, call.request, answer)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Answers host request `request` of guest `name`.
+    fn answer(&mut self, name: &str, request: u32, answer: Answer) -> anyhow::Result<()> {
+        let instance = self.instance(name);
+        // A guest which cancelled the call, or trapped since,
+        // no longer waits on it.
+        if !instance.awaits(request) {
+            return Ok(());
+        }
+        match answer {
+            Answer::Response { code, bytes } => instance.respond(request, code, &bytes)?,
+            Answer::Error(bytes) => instance.fail(request, &bytes)?,
+        }
+        Ok(())
+    }
+
+    /// Invokes `operation` in guest `name` and drives the mesh
+    /// until it answers, routing calls between guests and
+    /// settling every other host request with `answer`, which is
+    /// told the guest that made it.
+    pub fn call(
+        &mut self,
+        name: &str,
+        operation: &str,
+        payload: &[u8],
+        answer: impl FnMut(&str, &HostRequest) -> Answer,
+    ) -> anyhow::Result<Answer> {
+        self.call_with(name, operation, payload, &[] as &[(&str, &str)], answer)
+    }
+
+    /// Like `call`, sending `metadata` along with the invocation.
+    pub fn call_with(
+        &mut self,
+        name: &str,
+        operation: &str,
+        payload: &[u8],
+        metadata: &[(impl AsRef<str>, impl AsRef<str>)],
+        mut answer: impl FnMut(&str, &HostRequest) -> Answer,
+    ) -> anyhow::Result<Answer> {
+        let instance = self
+            .guests
+            .get_mut(name)
+            .ok_or_else(|| anyhow::anyhow!("no guest `{}`", name))?;
+        let id = instance.invoke_with(operation, payload, metadata)?;
+        loop {
+            self.route()?;
+            if let Some(result) = self.instance(name).host_mut().as_mut().take_answer(id) {
+                return Ok(result);
+            }
+            let next = self.guests.iter_mut().find_map(|(guest, instance)| {
+                let request = instance.host_mut().as_mut().take_request()?;
+                Some((guest.clone(), request))
+            });
+            let (guest, request) = match next {
+                Some(next) => next,
+                None => anyhow::bail!(
+                    "guest `{}` did not answer request {} (`{}`)",
+                    name,
+                    id,
+                    operation
+                ),
+            };
+            let instance = self.instance(&guest);
+            match answer(&guest, &request) {
+                Answer::Response { code, bytes } => instance.respond(request.id, code, &bytes)?,
+                Answer::Error(bytes) => instance.fail(request.id, &bytes)?,
+            }
+        }
+    }
+}
+
+impl<H> Default for Mesh<H>
+where
+    H: WapcHost + Hooks + AsMut<StubHost> + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use wit_bindgen_wasmtime::wasmtime;
+
+    use super::*;
+    use crate::audit::{AuditEvent, AuditSink, Audited, Record};
+    use crate::policy::Restricted;
+
+    /// What the guests logged, each line after the guest's name.
+    type Logs = Arc<Mutex<Vec<String>>>;
+
+    fn relay() -> wasmtime::Module {
+        let wasm = wat::parse_str(include_str!("../benches/guests/relay.wat")).unwrap();
+        wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap()
+    }
+
+    /// Relay guests by the given names, each linked to every
+    /// other.
+    fn mesh(names: &[&str], logs: &Logs) -> Mesh<StubHost> {
+        let module = relay();
+        let mut mesh = Mesh::new();
+        for &name in names {
+            let (sink, guest) = (logs.clone(), name.to_string());
+            let host = StubHost::new().on_log(move |message| {
+                sink.lock().unwrap().push(format!("{}: {}", guest, message))
+            });
+            let instance = Instance::new(&module, Routing::new(host)).unwrap();
+            mesh.add(name, instance).unwrap();
+            for &to in names {
+                mesh.link(name, to, Policy::allow_all());
+            }
+        }
+        mesh
+    }
+
+    fn call(mesh: &mut Mesh<StubHost>, operation: &str, payload: &[u8]) -> Answer {
+        mesh.call("a", operation, payload, |_, request| {
+            panic!("unrouted request {:?}", request)
+        })
+        .unwrap()
+    }
+
+    fn error(message: &str) -> Answer {
+        Answer::Error(message.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn passes_answers_back_along_the_chain() {
+        let mut mesh = mesh(&["a", "b", "c"], &Logs::default());
+        let leaf = Answer::Response {
+            code: 7,
+            bytes: b"leaf".to_vec(),
+        };
+        assert_eq!(call(&mut mesh, "relay", b"bc"), leaf);
+        assert_eq!(call(&mut mesh, "relay", b"bx"), error("no guest `x`"));
+    }
+
+    #[test]
+    fn refuses_cycles_whatever_chain_guests_send() {
+        // Each guest claims to start a new chain.
+        let mut mesh = mesh(&["a", "b"], &Logs::default());
+        assert_eq!(
+            call(&mut mesh, "loop", b"ba"),
+            error("call cycle: a -> b -> a")
+        );
+        assert_eq!(call(&mut mesh, "loop", b"a"), error("call cycle: a -> a"));
+    }
+
+    #[test]
+    fn refuses_calls_nested_too_deep() {
+        let mut mesh = mesh(&["a", "b", "c", "d"], &Logs::default()).max_depth(2);
+        assert_eq!(
+            call(&mut mesh, "relay", b"bcd"),
+            error("call depth exceeded: a -> b -> c -> d")
+        );
+        assert_eq!(
+            call(&mut mesh, "relay", b"bc"),
+            Answer::Response {
+                code: 7,
+                bytes: b"leaf".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn refuses_calls_no_link_allows() {
+        let mut mesh = mesh(&["a", "b", "c"], &Logs::default());
+        mesh.links.remove(&("b".to_string(), "c".to_string()));
+        assert_eq!(call(&mut mesh, "relay", b"bc"), error("permission denied"));
+    }
+
+    #[test]
+    fn cancels_routed_calls_along_the_chain() {
+        let logs = Logs::default();
+        let mut mesh = mesh(&["a", "b", "c"], &logs);
+        let a = mesh.guest_mut("a").unwrap();
+        let id = a.invoke("relay", b"bc.").unwrap();
+        mesh.route().unwrap();
+        assert!(mesh.calls.len() == 2 && logs.lock().unwrap().is_empty());
+
+        mesh.guest_mut("a").unwrap().cancel(id).unwrap();
+        mesh.route().unwrap();
+        assert!(mesh.calls.is_empty());
+        assert_eq!(
+            *logs.lock().unwrap(),
+            ["a: cancelled", "b: cancelled", "c: cancelled"]
+        );
+    }
+
+    /// Keeps the events of the records written to it.
+    struct Events(Arc<Mutex<Vec<AuditEvent>>>);
+
+    impl AuditSink for Events {
+        fn record(&mut self, record: &Record) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(record.event.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn wrapped_hosts_see_routed_calls() {
+        let module = relay();
+        let mut mesh = Mesh::new();
+        let mut events = Vec::new();
+        for name in ["a", "b"] {
+            let sink = Arc::new(Mutex::new(Vec::new()));
+            let host = Audited::new(StubHost::new(), name, Events(sink.clone()));
+            let instance = Instance::new(&module, Routing::new(host)).unwrap();
+            mesh.add(name, instance).unwrap();
+            mesh.link(name, "b", Policy::allow_all());
+            events.push(sink);
+        }
+        let answer = mesh
+            .call("a", "relay", b"b", |_, request| {
+                panic!("unrouted request {:?}", request)
+            })
+            .unwrap();
+        assert!(matches!(answer, Answer::Response { code: 7, .. }));
+
+        let a = events[0].lock().unwrap();
+        assert!(matches!(
+            a.as_slice(),
+            [
+                AuditEvent::Invoke { .. },
+                AuditEvent::HostRequest { binding, namespace, .. },
+                AuditEvent::HostRequestId { id: 1 },
+                AuditEvent::HostResponse { id: 1, code: 7, .. },
+                AuditEvent::GuestResponse { code: 7, .. },
+            ] if binding == BINDING && namespace == "b"
+        ));
+        let b = events[1].lock().unwrap();
+        assert!(matches!(
+            b.as_slice(),
+            [
+                AuditEvent::Invoke { operation, .. },
+                AuditEvent::GuestResponse { code: 7, .. },
+            ] if operation == "relay"
+        ));
+    }
+
+    #[test]
+    fn calls_the_wrapped_host_refuses_are_not_routed() {
+        let module = relay();
+        let mut mesh = Mesh::new();
+        for name in ["a", "b"] {
+            let policy = Policy {
+                allow: Vec::new(),
+                deny: Vec::new(),
+            };
+            let host = Restricted::new(StubHost::new(), policy);
+            let instance = Instance::new(&module, Routing::new(host)).unwrap();
+            mesh.add(name, instance).unwrap();
+            mesh.link(name, "b", Policy::allow_all());
+        }
+        let answer = mesh
+            .call("a", "relay", b"b", |_, request| {
+                panic!("unrouted request {:?}", request)
+            })
+            .unwrap();
+        assert_eq!(answer, error("permission denied"));
+        assert!(mesh.calls.is_empty());
+    }
+}
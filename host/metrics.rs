@@ -0,0 +1,685 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::diagnostics::{CallError, HostCall};
+use crate::policy::Policy;
+use crate::runtime::{Answer, Hooks};
+use crate::stub::StubHost;
+use crate::wapc_host::{StreamKind, WapcHost};
+
+/// The metrics of every guest using it, kept in a Prometheus
+/// registry.
+///
+/// Invocations and host requests are counted and timed by a
+/// `Measured` host; the health of an instance is sampled after
+/// every call into it once attached with `Instance::metrics`.
+/// Clones share the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    invocations: IntCounterVec,
+    invocation_seconds: HistogramVec,
+    host_requests: IntCounterVec,
+    host_request_seconds: HistogramVec,
+    pending_invocations: IntGaugeVec,
+    pending_host_requests: IntGaugeVec,
+    instances: IntGaugeVec,
+    pool_size: IntGaugeVec,
+    memory_bytes: IntGaugeVec,
+    fuel_consumed: IntCounterVec,
+    traps: IntCounterVec,
+    restarts: IntCounterVec,
+    discarded_answers: IntCounterVec,
+}
+
+const GUEST: &[&str] = &["guest"];
+const INVOCATION: &[&str] = &["guest", "operation"];
+const HOST_REQUEST: &[&str] = &["guest", "binding", "namespace", "operation"];
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        Metrics {
+            invocations: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("wapc_invocations_total", "Invocations answered or failed."),
+                    &with_outcome(INVOCATION),
+                ),
+            ),
+            invocation_seconds: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "wapc_invocation_duration_seconds",
+                        "Time from invoking an operation to the guest's answer.",
+                    ),
+                    INVOCATION,
+                ),
+            ),
+            host_requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "wapc_host_requests_total",
+                        "Host requests answered or failed.",
+                    ),
+                    &with_outcome(HOST_REQUEST),
+                ),
+            ),
+            host_request_seconds: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "wapc_host_request_duration_seconds",
+                        "Time from a host request to its answer.",
+                    ),
+                    HOST_REQUEST,
+                ),
+            ),
+            pending_invocations: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("wapc_pending_invocations", "Invocations not yet answered."),
+                    GUEST,
+                ),
+            ),
+            pending_host_requests: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new(
+                        "wapc_pending_host_requests",
+                        "Host requests the guest waits on.",
+                    ),
+                    GUEST,
+                ),
+            ),
+            instances: register(
+                &registry,
+                IntGaugeVec::new(Opts::new("wapc_instances", "Live guest instances."), GUEST),
+            ),
+            pool_size: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("wapc_pool_size", "Instances in the guest's pools."),
+                    GUEST,
+                ),
+            ),
+            memory_bytes: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("wapc_memory_bytes", "Size of the guest's linear memory."),
+                    GUEST,
+                ),
+            ),
+            fuel_consumed: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("wapc_fuel_consumed_total", "Fuel consumed by the guest."),
+                    GUEST,
+                ),
+            ),
+            traps: register(
+                &registry,
+                IntCounterVec::new(Opts::new("wapc_traps_total", "Guest traps."), GUEST),
+            ),
+            restarts: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("wapc_restarts_total", "Guest restarts after a trap."),
+                    GUEST,
+                ),
+            ),
+            discarded_answers: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "wapc_discarded_answers_total",
+                        "Late answers to cancelled requests, by who answered.",
+                    ),
+                    &["guest", "from"],
+                ),
+            ),
+            registry,
+        }
+    }
+
+    /// The registry holding the metrics, for serving them along
+    /// with others.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut text = Vec::new();
+        // Encoding into memory only fails on invalid metrics,
+        // which are never registered.
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut text);
+        String::from_utf8_lossy(&text).into_owned()
+    }
+
+    pub(crate) fn pool_size(&self, guest: &str) -> IntGauge {
+        self.pool_size.with_label_values(&[guest])
+    }
+
+    pub(crate) fn sampler(&self, guest: &str) -> Sampler {
+        let instances = self.instances.with_label_values(&[guest]);
+        instances.inc();
+        Sampler {
+            instances,
+            memory: self.memory_bytes.with_label_values(&[guest]),
+            fuel: self.fuel_consumed.with_label_values(&[guest]),
+            fuel_seen: 0,
+            pending_invocations: self.pending_invocations.with_label_values(&[guest]),
+            pending_host_requests: self.pending_host_requests.with_label_values(&[guest]),
+            traps: self.traps.with_label_values(&[guest]),
+            restarts: self.restarts.with_label_values(&[guest]),
+            discarded_from_guest: self.discarded_answers.with_label_values(&[guest, "guest"]),
+            discarded_from_host: self.discarded_answers.with_label_values(&[guest, "host"]),
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn with_outcome(labels: &[&'static str]) -> Vec<&'static str> {
+    let mut labels = labels.to_vec();
+    labels.push("outcome");
+    labels
+}
+
+fn register<M>(registry: &Registry, metric: prometheus::Result<M>) -> M
+where
+    M: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.expect("valid metric definition");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric is registered once");
+    metric
+}
+
+/// How long a scraper may take to send its request or read the
+/// response before it is hung up on.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most a scraper may send, request line and headers
+/// included.
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// Serves `metrics` at `http://127.0.0.1:<port>/metrics` from a
+/// background thread, returning the address it listens on.
+/// Port 0 picks a free port. Scrapers are served one at a time,
+/// each within `SCRAPE_TIMEOUT`.
+pub fn serve(metrics: Metrics, port: u16) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let addr = listener.local_addr()?;
+    std::thread::Builder::new()
+        .name("wapc-metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // A scraper hanging up early only loses its own
+                // response.
+                let _ = scrape(&metrics, stream);
+            }
+        })?;
+    Ok(addr)
+}
+
+fn scrape(metrics: &Metrics, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    let started = Instant::now();
+    let mut reader = BufReader::new(io::Read::take(&stream, MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, up to the blank line ending them.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        // The timeouts bound each read, not a scraper trickling
+        // in a byte at a time.
+        if started.elapsed() > SCRAPE_TIMEOUT {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", metrics.encode()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// The health metrics of one instance, sampled after each call
+/// into its guest.
+pub(crate) struct Sampler {
+    instances: IntGauge,
+    memory: IntGauge,
+    fuel: IntCounter,
+    /// The fuel the current store had consumed when last
+    /// sampled.
+    fuel_seen: u64,
+    pending_invocations: IntGauge,
+    pending_host_requests: IntGauge,
+    traps: IntCounter,
+    restarts: IntCounter,
+    discarded_from_guest: IntCounter,
+    discarded_from_host: IntCounter,
+}
+
+impl Sampler {
+    pub(crate) fn sample(
+        &mut self,
+        memory: Option<usize>,
+        fuel: Option<u64>,
+        pending_invocations: usize,
+        pending_host_requests: usize,
+    ) {
+        self.memory.set(memory.unwrap_or(0) as i64);
+        if let Some(fuel) = fuel {
+            self.fuel.inc_by(fuel.saturating_sub(self.fuel_seen));
+            self.fuel_seen = fuel;
+        }
+        self.pending_invocations.set(pending_invocations as i64);
+        self.pending_host_requests.set(pending_host_requests as i64);
+    }
+
+    pub(crate) fn trapped(&self) {
+        self.traps.inc();
+    }
+
+    /// Counts `n` answers the guest gave to invocations the host
+    /// had cancelled.
+    pub(crate) fn discarded_from_guest(&self, n: u64) {
+        self.discarded_from_guest.inc_by(n);
+    }
+
+    /// Counts an answer the host gave to a request the guest had
+    /// cancelled.
+    pub(crate) fn discarded_from_host(&self) {
+        self.discarded_from_host.inc();
+    }
+
+    /// Counts a restart, which starts a store with no fuel
+    /// consumed.
+    pub(crate) fn restarted(&mut self) {
+        self.restarts.inc();
+        self.fuel_seen = 0;
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.instances.dec();
+    }
+}
+
+/// The most distinct host requests a `Measured` host labels
+/// with their binding, namespace and operation.
+pub const MAX_HOST_REQUEST_LABELS: usize = 64;
+
+/// The label of host requests beyond those.
+const OTHER: &str = "other";
+
+/// A `WapcHost` wrapper which counts and times the invocations
+/// of a guest and the host requests it makes, labelled with the
+/// guest's name.
+///
+/// An invocation's `outcome` is `ok`, `error`, `cancelled` or,
+/// when the guest trapped before returning its id, `trapped`; a
+/// host request's is `ok`, `error`, `cancelled` or, when the
+/// guest trapped while waiting on it, `trapped`.
+///
+/// The guest chooses the binding, namespace and operation of
+/// its host requests, so they only become labels for requests
+/// every policy given to `labels` allows, and for at most
+/// `MAX_HOST_REQUEST_LABELS` distinct requests. Every other
+/// request is labelled `other`.
+pub struct Measured<H> {
+    inner: H,
+    guest: String,
+    metrics: Metrics,
+    labels: Vec<Policy>,
+    labelled: HashSet<HostCall>,
+    /// The invocation being started, until the guest returns its
+    /// id or answers it.
+    starting: Option<(String, Instant)>,
+    invocations: HashMap<u32, (String, Instant)>,
+    requests: HashMap<u32, (HostCall, Instant)>,
+}
+
+impl<H> Measured<H> {
+    pub fn new(inner: H, guest: impl Into<String>, metrics: Metrics) -> Self {
+        Measured {
+            inner,
+            guest: guest.into(),
+            metrics,
+            labels: Vec::new(),
+            labelled: HashSet::new(),
+            starting: None,
+            invocations: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Only labels host requests `policy` allows with their
+    /// binding, namespace and operation.
+    pub fn labels(mut self, policy: Policy) -> Self {
+        self.labels.push(policy);
+        self
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The labels of a host request, bounded as described above.
+    fn label(&mut self, call: HostCall) -> HostCall {
+        let allowed = self
+            .labels
+            .iter()
+            .all(|policy| policy.allows(&call.binding, &call.namespace, &call.operation));
+        if allowed
+            && (self.labelled.contains(&call) || self.labelled.len() < MAX_HOST_REQUEST_LABELS)
+        {
+            self.labelled.insert(call.clone());
+            return call;
+        }
+        HostCall {
+            binding: OTHER.to_string(),
+            namespace: OTHER.to_string(),
+            operation: OTHER.to_string(),
+        }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    fn finish_invocation(&mut self, id: u32, outcome: &str) {
+        let (operation, started) = match self.invocations.remove(&id) {
+            Some(invocation) => invocation,
+            // The guest answered the invocation before returning
+            // its id.
+            None => match self.starting.take() {
+                Some(invocation) => invocation,
+                None => return,
+            },
+        };
+        self.observe_invocation(&operation, started, outcome);
+    }
+
+    fn observe_invocation(&self, operation: &str, started: Instant, outcome: &str) {
+        let labels = [self.guest.as_str(), operation];
+        self.metrics
+            .invocation_seconds
+            .with_label_values(&labels)
+            .observe(started.elapsed().as_secs_f64());
+        self.metrics
+            .invocations
+            .with_label_values(&[labels[0], labels[1], outcome])
+            .inc();
+    }
+
+    fn finish_request(&mut self, id: u32, outcome: &str) {
+        let (call, started) = match self.requests.remove(&id) {
+            Some(request) => request,
+            None => return,
+        };
+        let labels = [
+            self.guest.as_str(),
+            call.binding.as_str(),
+            call.namespace.as_str(),
+            call.operation.as_str(),
+        ];
+        self.metrics
+            .host_request_seconds
+            .with_label_values(&labels)
+            .observe(started.elapsed().as_secs_f64());
+        self.metrics
+            .host_requests
+            .with_label_values(&[labels[0], labels[1], labels[2], labels[3], outcome])
+            .inc();
+    }
+}
+
+impl<H> AsMut<StubHost> for Measured<H>
+where
+    H: AsMut<StubHost>,
+{
+    fn as_mut(&mut self) -> &mut StubHost {
+        self.inner.as_mut()
+    }
+}
+
+impl<H: Hooks> Hooks for Measured<H> {
+    fn on_invoke(&mut self, operation: &str, payload: &[u8], metadata: &mut Vec<(String, String)>) {
+        self.starting = Some((operation.to_string(), Instant::now()));
+        self.inner.on_invoke(operation, payload, metadata)
+    }
+
+    fn on_invoked(&mut self, operation: &str, result: Result<u32, &CallError>) {
+        if let Some((operation, started)) = self.starting.take() {
+            match result {
+                Ok(id) => {
+                    self.invocations.insert(id, (operation, started));
+                }
+                Err(_) => self.observe_invocation(&operation, started, "trapped"),
+            }
+        }
+        self.inner.on_invoked(operation, result)
+    }
+
+    fn on_respond(&mut self, id: u32, code: u32, bytes: &[u8]) {
+        self.finish_request(id, "ok");
+        self.inner.on_respond(id, code, bytes)
+    }
+
+    fn on_fail(&mut self, id: u32, bytes: &[u8]) {
+        self.finish_request(id, "error");
+        self.inner.on_fail(id, bytes)
+    }
+
+    fn on_cancel(&mut self, id: u32) {
+        self.finish_invocation(id, "cancelled");
+        self.inner.on_cancel(id)
+    }
+
+    fn on_trap(&mut self, error: &CallError) {
+        // A trapped guest forgets its outstanding requests.
+        let ids = self.requests.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            self.finish_request(id, "trapped");
+        }
+        let ids = self.invocations.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            self.finish_invocation(id, "trapped");
+        }
+        self.inner.on_trap(error)
+    }
+
+    fn take_settled(&mut self) -> Option<(u32, Answer)> {
+        self.inner.take_settled()
+    }
+}
+
+impl<H: WapcHost> WapcHost for Measured<H> {
+    type Wapc = H::Wapc;
+
+    fn instance(&mut self) -> Self::Wapc {
+        self.inner.instance()
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        self_: &Self::Wapc,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        let started = Instant::now();
+        let id = self
+            .inner
+            .wapc_init_host_request(self_, binding, namespace, operation, bytes, metadata);
+        let call = self.label(HostCall {
+            binding: binding.to_string(),
+            namespace: namespace.to_string(),
+            operation: operation.to_string(),
+        });
+        self.requests.insert(id, (call, started));
+        id
+    }
+
+    fn wapc_on_guest_response(&mut self, self_: &Self::Wapc, id: u32, code: u32, bytes: &[u8]) {
+        self.finish_invocation(id, "ok");
+        self.inner.wapc_on_guest_response(self_, id, code, bytes)
+    }
+
+    fn wapc_on_guest_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.finish_invocation(id, "error");
+        self.inner.wapc_on_guest_error(self_, id, bytes)
+    }
+
+    fn wapc_console_log(&mut self, self_: &Self::Wapc, message: &str) {
+        self.inner.wapc_console_log(self_, message)
+    }
+
+    fn wapc_cancel(&mut self, self_: &Self::Wapc, id: u32) {
+        self.finish_request(id, "cancelled");
+        self.inner.wapc_cancel(self_, id)
+    }
+
+    fn wapc_stream_chunk(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, bytes: &[u8]) {
+        self.inner.wapc_stream_chunk(self_, id, kind, bytes)
+    }
+
+    fn wapc_stream_end(&mut self, self_: &Self::Wapc, id: u32) {
+        self.inner.wapc_stream_end(self_, id)
+    }
+
+    fn wapc_stream_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.inner.wapc_stream_error(self_, id, bytes)
+    }
+
+    fn wapc_stream_credit(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, chunks: u32) {
+        self.inner.wapc_stream_credit(self_, id, kind, chunks)
+    }
+
+    fn drop_wapc(&mut self, state: Self::Wapc) {
+        self.inner.drop_wapc(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use wit_bindgen_wasmtime::wasmtime;
+
+    use super::*;
+    use crate::policy::Rule;
+    use crate::runtime::Instance;
+    use crate::stub::Handle;
+
+    fn request(host: &mut Measured<StubHost>, binding: &str, operation: &str) {
+        let id = host.wapc_init_host_request(&Handle, binding, "app", operation, b"", Vec::new());
+        host.on_respond(id, 0, b"");
+    }
+
+    fn count(metrics: &Metrics, binding: &str, operation: &str) -> u64 {
+        let labels = ["guest", binding, "app", operation, "ok"];
+        metrics.host_requests.with_label_values(&labels).get()
+    }
+
+    #[test]
+    fn host_request_labels_are_bounded() {
+        let policy = Policy {
+            allow: vec![Rule {
+                binding: "kv".to_string(),
+                namespace: "*".to_string(),
+                operation: "*".to_string(),
+            }],
+            deny: Vec::new(),
+        };
+        let metrics = Metrics::new();
+        let mut host = Measured::new(StubHost::new(), "guest", metrics.clone()).labels(policy);
+
+        request(&mut host, "secret-1", "get");
+        for n in 0..=MAX_HOST_REQUEST_LABELS {
+            request(&mut host, "kv", &n.to_string());
+        }
+        request(&mut host, "kv", "0");
+
+        assert_eq!(count(&metrics, "kv", "0"), 2);
+        let last = MAX_HOST_REQUEST_LABELS.to_string();
+        assert_eq!(count(&metrics, "kv", &last), 0);
+        let other = ["guest", OTHER, OTHER, OTHER, "ok"];
+        assert_eq!(metrics.host_requests.with_label_values(&other).get(), 2);
+    }
+
+    #[test]
+    fn requests_pending_at_a_trap_are_finished() {
+        // `fanout`, but trapping on the first host response.
+        let wat = include_str!("../benches/guests/fanout.wat").replace(
+            "(param $self i32) (param i32 i32 i32 i32)\n    (call $answered))",
+            "(param $self i32) (param i32 i32 i32 i32)\n    unreachable)",
+        );
+        let wasm = wat::parse_str(wat).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        let metrics = Metrics::new();
+        let host = Measured::new(StubHost::new(), "guest", metrics.clone());
+        let mut instance = Instance::new(&module, host).unwrap();
+
+        instance.invoke("fanout", &[2]).unwrap();
+        let request = instance.host_mut().as_mut().take_request().unwrap();
+        assert!(instance.respond(request.id, 0, b"").is_err());
+
+        assert!(instance.host().requests.is_empty());
+        let labels = ["guest", "bench", "ns", "op"];
+        let outcome = |outcome| {
+            let labels = [labels[0], labels[1], labels[2], labels[3], outcome];
+            metrics.host_requests.with_label_values(&labels).get()
+        };
+        assert_eq!((outcome("ok"), outcome("trapped")), (1, 1));
+    }
+
+    #[test]
+    fn serves_the_text_format() {
+        let metrics = Metrics::new();
+        metrics.traps.with_label_values(&["guest"]).inc();
+        let addr = serve(metrics, 0).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(
+            response.contains("wapc_traps_total{guest=\"guest\"} 1"),
+            "{}",
+            response
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use wit_bindgen_wasmtime::anyhow;
+
+/// A payload as written in fixture and trace files.
+///
+/// Plain strings are taken as UTF-8 text, `{"hex": "..."}` as
+/// hex-encoded bytes and `{"json": ...}` as a JSON document
+/// serialized to bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Payload {
+    Hex { hex: String },
+    Json { json: serde_json::Value },
+    Text(String),
+}
+
+/// How JSON payloads are turned into bytes on the wire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl Codec {
+    pub fn encode(&self, value: &serde_json::Value) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            Codec::Msgpack => Ok(rmp_serde::to_vec_named(value)?),
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> anyhow::Result<serde_json::Value> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+            Codec::Msgpack => Ok(rmp_serde::from_slice(bytes)?),
+        }
+    }
+}
+
+impl Payload {
+    /// Picks the most readable representation of `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Payload::Text(text.to_string()),
+            Err(_) => Payload::Hex {
+                hex: encode_hex(bytes),
+            },
+        }
+    }
+
+    /// Decodes `bytes` as a JSON payload with `codec`, falling
+    /// back to `from_bytes` when they are not a valid document.
+    pub fn decode(bytes: &[u8], codec: Codec) -> Self {
+        match codec.decode(bytes) {
+            Ok(json) => Payload::Json { json },
+            Err(_) => Self::from_bytes(bytes),
+        }
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        self.encode(Codec::Json)
+    }
+
+    /// Converts the payload to bytes, encoding JSON documents
+    /// with `codec`.
+    pub fn encode(&self, codec: Codec) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Payload::Hex { hex } => decode_hex(hex),
+            Payload::Json { json } => codec.encode(json),
+            Payload::Text(text) => Ok(text.as_bytes().to_vec()),
+        }
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::Text(String::new())
+    }
+}
+
+/// Parses a payload typed by hand: `hex:` followed by hex
+/// digits, or otherwise a JSON document which is serialized to
+/// bytes as-is.
+pub fn parse(input: &str) -> anyhow::Result<Vec<u8>> {
+    let input = input.trim();
+    if let Some(hex) = input.strip_prefix("hex:") {
+        return decode_hex(hex);
+    }
+    if input.is_empty() {
+        return Ok(Vec::new());
+    }
+    let json: serde_json::Value = serde_json::from_str(input)
+        .map_err(|e| anyhow::anyhow!("payload is neither `hex:...` nor JSON: {}", e))?;
+    Ok(serde_json::to_vec(&json)?)
+}
+
+/// Renders `bytes` as text when they are valid UTF-8 and as
+/// `hex:...` otherwise.
+pub fn display(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("hex:{}", encode_hex(bytes)),
+    }
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let hex: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("hex payload has an odd number of digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| anyhow::anyhow!("invalid hex payload `{}`", hex))
+        })
+        .collect()
+}
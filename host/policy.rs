@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use wit_bindgen_wasmtime::anyhow;
+
+use crate::diagnostics::CallError;
+use crate::runtime::{self, Answer, Hooks};
+use crate::stub::StubHost;
+use crate::wapc_host::{StreamKind, WapcHost};
+
+/// Which host requests a guest may make, read from JSON:
+///
+/// ```json
+/// {
+///   "allow": [{ "binding": "kv", "operation": "get" }, { "binding": "log" }],
+///   "deny": [{ "binding": "kv", "namespace": "secrets" }]
+/// }
+/// ```
+///
+/// A request is allowed when it matches an `allow` rule and no
+/// `deny` rule. Each field of a rule is a pattern in which `*`
+/// matches any run of characters, and an omitted field matches
+/// anything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub allow: Vec<Rule>,
+    #[serde(default)]
+    pub deny: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    #[serde(default = "any")]
+    pub binding: String,
+    #[serde(default = "any")]
+    pub namespace: String,
+    #[serde(default = "any")]
+    pub operation: String,
+}
+
+fn any() -> String {
+    "*".to_string()
+}
+
+impl Rule {
+    pub fn matches(&self, binding: &str, namespace: &str, operation: &str) -> bool {
+        wildcard(&self.binding, binding)
+            && wildcard(&self.namespace, namespace)
+            && wildcard(&self.operation, operation)
+    }
+}
+
+impl Policy {
+    /// A policy which allows every request.
+    pub fn allow_all() -> Self {
+        Policy {
+            allow: vec![Rule {
+                binding: any(),
+                namespace: any(),
+                operation: any(),
+            }],
+            deny: Vec::new(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn allows(&self, binding: &str, namespace: &str, operation: &str) -> bool {
+        let matches = |rule: &Rule| rule.matches(binding, namespace, operation);
+        self.allow.iter().any(matches) && !self.deny.iter().any(matches)
+    }
+}
+
+/// Matches `text` against `pattern`, in which `*` stands for
+/// any run of characters.
+fn wildcard(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // `split` always yields at least one part.
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // No `*` at all.
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// A host request refused by a `Restricted` host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denial {
+    pub id: u32,
+    pub binding: String,
+    pub namespace: String,
+    pub operation: String,
+}
+
+/// A `WapcHost` wrapper which only passes on the host requests
+/// every one of its policies allows.
+///
+/// Refused requests never reach the wrapped host: the guest
+/// gets an id from `runtime::settled_id`, and the request
+/// fails with a `permission denied` error once the current
+/// call into the guest returns.
+pub struct Restricted<H> {
+    inner: H,
+    policies: Vec<Policy>,
+    denied: VecDeque<u32>,
+    audit: Box<dyn FnMut(&Denial) + Send>,
+}
+
+impl<H> Restricted<H> {
+    /// Creates a host which refuses requests silently; see
+    /// `on_denied` to report them.
+    pub fn new(inner: H, policy: Policy) -> Self {
+        Restricted {
+            inner,
+            policies: vec![policy],
+            denied: VecDeque::new(),
+            audit: Box::new(|_| {}),
+        }
+    }
+
+    /// Sets the sink for refused requests.
+    pub fn on_denied(mut self, audit: impl FnMut(&Denial) + Send + 'static) -> Self {
+        self.audit = Box::new(audit);
+        self
+    }
+
+    /// Additionally requires requests to be allowed by
+    /// `policy`.
+    pub fn and(mut self, policy: Policy) -> Self {
+        self.policies.push(policy);
+        self
+    }
+
+    pub fn policies(&self) -> &[Policy] {
+        &self.policies
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H> AsMut<StubHost> for Restricted<H>
+where
+    H: AsMut<StubHost>,
+{
+    fn as_mut(&mut self) -> &mut StubHost {
+        self.inner.as_mut()
+    }
+}
+
+impl<H: Hooks> Hooks for Restricted<H> {
+    fn on_invoke(&mut self, operation: &str, payload: &[u8], metadata: &mut Vec<(String, String)>) {
+        self.inner.on_invoke(operation, payload, metadata)
+    }
+
+    fn on_invoked(&mut self, operation: &str, result: Result<u32, &CallError>) {
+        self.inner.on_invoked(operation, result)
+    }
+
+    fn on_respond(&mut self, id: u32, code: u32, bytes: &[u8]) {
+        self.inner.on_respond(id, code, bytes)
+    }
+
+    fn on_fail(&mut self, id: u32, bytes: &[u8]) {
+        self.inner.on_fail(id, bytes)
+    }
+
+    fn on_cancel(&mut self, id: u32) {
+        self.inner.on_cancel(id)
+    }
+
+    fn on_trap(&mut self, error: &CallError) {
+        // The guest no longer waits on its refused requests.
+        self.denied.clear();
+        self.inner.on_trap(error)
+    }
+
+    fn take_settled(&mut self) -> Option<(u32, Answer)> {
+        match self.denied.pop_front() {
+            Some(id) => Some((id, Answer::Error(b"permission denied".to_vec()))),
+            None => self.inner.take_settled(),
+        }
+    }
+}
+
+impl<H: WapcHost> WapcHost for Restricted<H> {
+    type Wapc = H::Wapc;
+
+    fn instance(&mut self) -> Self::Wapc {
+        self.inner.instance()
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        self_: &Self::Wapc,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        let allowed = self
+            .policies
+            .iter()
+            .all(|policy| policy.allows(binding, namespace, operation));
+        if allowed {
+            return self
+                .inner
+                .wapc_init_host_request(self_, binding, namespace, operation, bytes, metadata);
+        }
+        let id = runtime::settled_id();
+        self.denied.push_back(id);
+        (self.audit)(&Denial {
+            id,
+            binding: binding.to_string(),
+            namespace: namespace.to_string(),
+            operation: operation.to_string(),
+        });
+        id
+    }
+
+    fn wapc_on_guest_response(&mut self, self_: &Self::Wapc, id: u32, code: u32, bytes: &[u8]) {
+        self.inner.wapc_on_guest_response(self_, id, code, bytes)
+    }
+
+    fn wapc_on_guest_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.inner.wapc_on_guest_error(self_, id, bytes)
+    }
+
+    fn wapc_console_log(&mut self, self_: &Self::Wapc, message: &str) {
+        self.inner.wapc_console_log(self_, message)
+    }
+
+    fn wapc_cancel(&mut self, self_: &Self::Wapc, id: u32) {
+        match self.denied.iter().position(|&denied| denied == id) {
+            // Never passed on, so there is nothing to cancel.
+            Some(index) => {
+                self.denied.remove(index);
+            }
+            None => self.inner.wapc_cancel(self_, id),
+        }
+    }
+
+    // Denied requests were never passed on, so the guest's
+    // streams for them go nowhere.
+
+    fn wapc_stream_chunk(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, bytes: &[u8]) {
+        if kind == StreamKind::Response || !self.denied.contains(&id) {
+            self.inner.wapc_stream_chunk(self_, id, kind, bytes)
+        }
+    }
+
+    fn wapc_stream_end(&mut self, self_: &Self::Wapc, id: u32) {
+        if !self.denied.contains(&id) {
+            self.inner.wapc_stream_end(self_, id)
+        }
+    }
+
+    fn wapc_stream_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        if !self.denied.contains(&id) {
+            self.inner.wapc_stream_error(self_, id, bytes)
+        }
+    }
+
+    fn wapc_stream_credit(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, chunks: u32) {
+        if kind == StreamKind::Request || !self.denied.contains(&id) {
+            self.inner.wapc_stream_credit(self_, id, kind, chunks)
+        }
+    }
+
+    fn drop_wapc(&mut self, state: Self::Wapc) {
+        self.inner.drop_wapc(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::stub::Handle;
+
+    fn rule(binding: &str, namespace: &str, operation: &str) -> Rule {
+        Rule {
+            binding: binding.to_string(),
+            namespace: namespace.to_string(),
+            operation: operation.to_string(),
+        }
+    }
+
+    #[test]
+    fn wildcard_matches_runs_of_characters() {
+        assert!(wildcard("kv", "kv"));
+        assert!(!wildcard("kv", "kvs"));
+        assert!(!wildcard("kv", "k"));
+        assert!(wildcard("*", ""));
+        assert!(wildcard("*", "anything"));
+        assert!(wildcard("kv.*", "kv.get"));
+        assert!(wildcard("kv.*", "kv."));
+        assert!(!wildcard("kv.*", "kv"));
+        assert!(wildcard("*.get", "kv.get"));
+        assert!(!wildcard("*.get", "kv.set"));
+        assert!(wildcard("a*b*c", "abc"));
+        assert!(wildcard("a*b*c", "a-b-b-c"));
+        assert!(!wildcard("a*b*c", "acb"));
+        // The parts on either side of a `*` may not overlap.
+        assert!(!wildcard("ab*bc", "abc"));
+        assert!(!wildcard("a*a", "a"));
+        assert!(wildcard("**", "x"));
+    }
+
+    #[test]
+    fn deny_rules_override_allow_rules() {
+        let policy = Policy {
+            allow: vec![rule("kv", "*", "*")],
+            deny: vec![rule("kv", "secrets", "*")],
+        };
+        assert!(policy.allows("kv", "app", "get"));
+        assert!(!policy.allows("kv", "secrets", "get"));
+        assert!(!policy.allows("http", "app", "get"));
+        assert!(!Policy::default().allows("kv", "app", "get"));
+        assert!(Policy::allow_all().allows("", "", ""));
+    }
+
+    #[test]
+    fn omitted_fields_match_anything() {
+        let policy: Policy = serde_json::from_str(r#"{ "allow": [{ "binding": "log" }] }"#).unwrap();
+        assert!(policy.allows("log", "any", "thing"));
+        assert!(!policy.allows("logs", "any", "thing"));
+    }
+
+    #[test]
+    fn refused_requests_are_reported_and_failed() {
+        let denials = Arc::new(Mutex::new(Vec::new()));
+        let sink = denials.clone();
+        let policy = Policy {
+            allow: vec![rule("kv", "*", "*")],
+            deny: Vec::new(),
+        };
+        let mut host = Restricted::new(StubHost::new(), policy)
+            .on_denied(move |denial| sink.lock().unwrap().push(denial.clone()));
+
+        let allowed = host.wapc_init_host_request(&Handle, "kv", "app", "get", b"", Vec::new());
+        let denied = host.wapc_init_host_request(&Handle, "http", "app", "get", b"", Vec::new());
+        assert!(denied > allowed);
+
+        assert_eq!(host.as_mut().take_request().map(|r| r.id), Some(allowed));
+        assert_eq!(host.as_mut().take_request().map(|r| r.id), None);
+        assert_eq!(
+            denials.lock().unwrap().as_slice(),
+            &[Denial {
+                id: denied,
+                binding: "http".to_string(),
+                namespace: "app".to_string(),
+                operation: "get".to_string(),
+            }]
+        );
+        match host.take_settled() {
+            Some((id, Answer::Error(bytes))) => {
+                assert_eq!(id, denied);
+                assert_eq!(bytes, b"permission denied");
+            }
+            other => panic!("expected a denial, got {:?}", other.map(|(id, _)| id)),
+        }
+    }
+}
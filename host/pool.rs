@@ -0,0 +1,125 @@
+use prometheus::IntGauge;
+
+use crate::metrics::Metrics;
+use crate::runtime::Instance;
+use crate::wapc_host::WapcHost;
+
+/// Instances of one guest which calls are spread over in
+/// turn, so that a guest busy with one call does not hold up
+/// the next.
+pub struct Pool<H: WapcHost> {
+    instances: Vec<Instance<H>>,
+    next: usize,
+    size: Option<IntGauge>,
+}
+
+impl<H: WapcHost> Pool<H> {
+    pub fn new() -> Self {
+        Pool {
+            instances: Vec::new(),
+            next: 0,
+            size: None,
+        }
+    }
+
+    /// Reports the size of the pool, labelled with `guest`, to
+    /// `metrics`, added to that of any other pool of `guest`.
+    pub fn metrics(mut self, metrics: &Metrics, guest: &str) -> Self {
+        let size = metrics.pool_size(guest);
+        size.add(self.instances.len() as i64);
+        self.size = Some(size);
+        self
+    }
+
+    pub fn push(&mut self, instance: Instance<H>) {
+        self.instances.push(instance);
+        if let Some(size) = &self.size {
+            size.inc();
+        }
+    }
+
+    /// Takes the instance at `index` out of the pool, e.g. once
+    /// it can no longer be restarted.
+    pub fn remove(&mut self, index: usize) -> Option<Instance<H>> {
+        if index >= self.instances.len() {
+            return None;
+        }
+        if let Some(size) = &self.size {
+            size.dec();
+        }
+        Some(self.instances.remove(index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// The instance whose turn it is to take a call.
+    pub fn next_instance(&mut self) -> Option<&mut Instance<H>> {
+        if self.instances.is_empty() {
+            return None;
+        }
+        let index = self.next % self.instances.len();
+        self.next = index + 1;
+        self.instances.get_mut(index)
+    }
+
+    pub fn instances(&self) -> impl Iterator<Item = &Instance<H>> {
+        self.instances.iter()
+    }
+}
+
+impl<H: WapcHost> Default for Pool<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: WapcHost> Drop for Pool<H> {
+    fn drop(&mut self) {
+        if let Some(size) = &self.size {
+            size.sub(self.instances.len() as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wit_bindgen_wasmtime::wasmtime;
+
+    use super::*;
+    use crate::stub::StubHost;
+
+    fn instance() -> Instance<StubHost> {
+        let wasm = wat::parse_str(include_str!("../benches/guests/echo.wat")).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        Instance::new(&module, StubHost::new()).unwrap()
+    }
+
+    #[test]
+    fn takes_turns_and_reports_its_size() {
+        let metrics = Metrics::new();
+        let size = metrics.pool_size("echo");
+        let mut pool = Pool::new().metrics(&metrics, "echo");
+        for _ in 0..3 {
+            pool.push(instance());
+        }
+        assert_eq!(size.get(), 3);
+
+        // Each instance numbers its own invocations from 1.
+        let ids = (0..4)
+            .map(|_| pool.next_instance().unwrap().invoke("echo", b"").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [1, 1, 1, 2]);
+
+        assert!(pool.remove(3).is_none());
+        assert!(pool.remove(0).is_some());
+        assert_eq!(size.get(), 2);
+        drop(pool);
+        assert_eq!(size.get(), 0);
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use wit_bindgen_wasmtime::anyhow;
+
+use crate::payload;
+use crate::runtime::{Hooks, Instance};
+use crate::stub::StubHost;
+use crate::wapc_host::WapcHost;
+
+const HELP: &str = "\
+commands:
+    call <operation> [payload]     invoke an operation in the guest
+    meta [key=value ...]           set the metadata sent with later calls
+    reply <id> <code> [payload]    answer host request <id>
+    error <id> [payload]           fail host request <id>
+    cancel <id>                    cancel invocation <id>
+    pending                        list outstanding requests in both directions
+    help                           show this message
+    quit                           exit
+
+payloads are JSON documents, or raw bytes written as hex:0a0b0c";
+
+/// An interactive session around a single guest instance.
+///
+/// Invocations are fire-and-forget: their ids are tracked until
+/// the guest answers them, and requests the guest makes of the
+/// host stay queued until the operator settles them with
+/// `reply` or `error`.
+///
+/// The host may be a `StubHost` or any wrapper around one.
+pub struct Repl<H: WapcHost = StubHost> {
+    instance: Instance<H>,
+    invocations: BTreeSet<u32>,
+    announced: u32,
+    metadata: Vec<(String, String)>,
+}
+
+impl<H> Repl<H>
+where
+    H: WapcHost + Hooks + AsMut<StubHost> + 'static,
+{
+    pub fn new(instance: Instance<H>) -> Self {
+        Repl {
+            instance,
+            invocations: BTreeSet::new(),
+            announced: 0,
+            metadata: Vec::new(),
+        }
+    }
+
+    pub fn into_instance(self) -> Instance<H> {
+        self.instance
+    }
+
+    /// Reads commands from `input` until it is exhausted or the
+    /// operator quits.
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> anyhow::Result<()> {
+        let mut line = String::new();
+        loop {
+            write!(output, "wapc> ")?;
+            output.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            match self.execute(line.trim(), &mut output) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) => writeln!(output, "error: {}", e)?,
+            }
+            self.report(&mut output)?;
+        }
+    }
+
+    /// Executes a single command, returning `false` when the
+    /// session should end.
+    fn execute(&mut self, line: &str, output: &mut impl Write) -> anyhow::Result<bool> {
+        let (command, rest) = split(line);
+        match command {
+            "" => {}
+            "call" => {
+                let (operation, payload) = split(rest);
+                if operation.is_empty() {
+                    anyhow::bail!("usage: call <operation> [payload]");
+                }
+                let payload = payload::parse(payload)?;
+                let id = self
+                    .instance
+                    .invoke_with(operation, &payload, &self.metadata)?;
+                self.invocations.insert(id);
+                writeln!(output, "-> invoked `{}` as request {}", operation, id)?;
+            }
+            "meta" => {
+                let metadata = rest
+                    .split_whitespace()
+                    .map(|entry| match entry.split_once('=') {
+                        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+                        None => anyhow::bail!("usage: meta [key=value ...]"),
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                self.metadata = metadata;
+            }
+            "reply" => {
+                let (id, rest) = split(rest);
+                let (code, payload) = split(rest);
+                let id = parse_id(id)?;
+                let code = code
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("usage: reply <id> <code> [payload]"))?;
+                let bytes = payload::parse(payload)?;
+                self.take_host_request(id)?;
+                self.instance.respond(id, code, &bytes)?;
+            }
+            "error" => {
+                let (id, payload) = split(rest);
+                let id = parse_id(id)?;
+                let bytes = payload::parse(payload)?;
+                self.take_host_request(id)?;
+                self.instance.fail(id, &bytes)?;
+            }
+            "cancel" => {
+                let id = parse_id(rest)?;
+                self.instance.cancel(id)?;
+                self.invocations.remove(&id);
+                writeln!(output, "-> cancelled request {}", id)?;
+            }
+            "pending" => {
+                writeln!(output, "guest requests awaiting a response:")?;
+                for id in &self.invocations {
+                    writeln!(output, "  {}", id)?;
+                }
+                writeln!(output, "host requests awaiting a reply:")?;
+                for r in self.instance.host_mut().as_mut().requests() {
+                    writeln!(
+                        output,
+                        "  {} {}/{}/{} {}{}",
+                        r.id,
+                        r.binding,
+                        r.namespace,
+                        r.operation,
+                        payload::display(&r.payload),
+                        show_metadata(&r.metadata)
+                    )?;
+                }
+            }
+            "help" => writeln!(output, "{}", HELP)?,
+            "quit" | "exit" => return Ok(false),
+            other => anyhow::bail!("unknown command `{}`, try `help`", other),
+        }
+        Ok(true)
+    }
+
+    /// Prints guest answers and host requests which arrived
+    /// while executing the last command.
+    fn report(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let host = self.instance.host_mut().as_mut();
+        let mut answered = Vec::new();
+        for id in &self.invocations {
+            match host.take_result(*id) {
+                Some(Ok(bytes)) => {
+                    writeln!(output, "<- response {}: {}", id, payload::display(&bytes))?
+                }
+                Some(Err(bytes)) => {
+                    writeln!(output, "<- error {}: {}", id, payload::display(&bytes))?
+                }
+                None => continue,
+            }
+            answered.push(*id);
+        }
+        for id in answered {
+            self.invocations.remove(&id);
+        }
+        while let Some(id) = host.take_cancelled() {
+            writeln!(output, "<- host request {} cancelled", id)?;
+        }
+        let announced = self.announced;
+        for r in host.requests().filter(|r| r.id > announced) {
+            writeln!(
+                output,
+                "<- host request {}: {}/{}/{} {}{}",
+                r.id,
+                r.binding,
+                r.namespace,
+                r.operation,
+                payload::display(&r.payload),
+                show_metadata(&r.metadata)
+            )?;
+            self.announced = self.announced.max(r.id);
+        }
+        Ok(())
+    }
+
+    fn take_host_request(&mut self, id: u32) -> anyhow::Result<()> {
+        match self.instance.host_mut().as_mut().take_request_by_id(id) {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("no pending host request {}", id),
+        }
+    }
+}
+
+fn split(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+/// Formats metadata as ` [key=value ...]`, or nothing when
+/// there is none.
+fn show_metadata(metadata: &[(String, String)]) -> String {
+    if metadata.is_empty() {
+        return String::new();
+    }
+    let entries = metadata
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>();
+    format!(" [{}]", entries.join(" "))
+}
+
+fn parse_id(id: &str) -> anyhow::Result<u32> {
+    id.parse()
+        .map_err(|_| anyhow::anyhow!("`{}` is not a request id", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use wit_bindgen_wasmtime::wasmtime;
+
+    use super::*;
+
+    /// Runs `script` in a session around the echo guest and
+    /// returns what the session printed, without its prompts.
+    fn session(script: &str) -> Vec<String> {
+        let wasm = wat::parse_str(include_str!("../benches/guests/echo.wat")).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        let instance = Instance::new(&module, StubHost::new()).unwrap();
+        let mut output = Vec::new();
+        Repl::new(instance)
+            .run(script.as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .split("wapc> ")
+            .flat_map(|printed| printed.lines().map(str::to_string).collect::<Vec<_>>())
+            .collect()
+    }
+
+    #[test]
+    fn splits_off_the_first_word() {
+        assert_eq!(split("call  echo {\"a\": 1}"), ("call", "echo {\"a\": 1}"));
+        assert_eq!(split("  pending  "), ("pending", ""));
+        assert_eq!(split("reply\t3 0"), ("reply", "3 0"));
+        assert_eq!(split(""), ("", ""));
+    }
+
+    #[test]
+    fn parses_request_ids() {
+        assert_eq!(parse_id("42").unwrap(), 42);
+        for bad in ["", "-1", "x", "4294967296"] {
+            let error = parse_id(bad).unwrap_err();
+            assert_eq!(error.to_string(), format!("`{}` is not a request id", bad));
+        }
+    }
+
+    #[test]
+    fn runs_commands() {
+        assert_eq!(
+            session("call echo \"hi\"\n\nmeta a=1 b=2\ncall echo hex:00ff\nquit\ncall echo 1\n"),
+            [
+                "-> invoked `echo` as request 1",
+                "<- response 1: \"hi\"",
+                "-> invoked `echo` as request 2",
+                "<- response 2: hex:00ff",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_bad_input_and_carries_on() {
+        let script = "\
+            frobnicate\n\
+            call\n\
+            call echo {\"unterminated\"\n\
+            call echo hex:0\n\
+            meta novalue\n\
+            reply 1\n\
+            reply x 0\n\
+            reply 1 ok\n\
+            error 1\n\
+            cancel\n\
+            call echo 1\n";
+        let printed = session(script);
+        assert_eq!(printed.len(), 12, "{:#?}", printed);
+        assert_eq!(
+            printed[0],
+            "error: unknown command `frobnicate`, try `help`"
+        );
+        assert_eq!(printed[1], "error: usage: call <operation> [payload]");
+        assert!(printed[2].starts_with("error: payload is neither `hex:...` nor JSON"));
+        assert!(printed[3].starts_with("error: "));
+        assert_eq!(printed[4], "error: usage: meta [key=value ...]");
+        assert_eq!(printed[5], "error: usage: reply <id> <code> [payload]");
+        assert_eq!(printed[6], "error: `x` is not a request id");
+        assert_eq!(printed[7], "error: usage: reply <id> <code> [payload]");
+        assert_eq!(printed[8], "error: no pending host request 1");
+        assert_eq!(printed[9], "error: `` is not a request id");
+        assert_eq!(printed[10], "-> invoked `echo` as request 1");
+        assert_eq!(printed[11], "<- response 1: 1");
+    }
+}
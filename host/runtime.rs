@@ -0,0 +1,1950 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use wit_bindgen_wasmtime::rt::{get_memory, invalid_variant, RawMem};
+use wit_bindgen_wasmtime::{anyhow, wasmtime, BorrowChecker};
+
+use crate::bindings::wapc_host::{self, WapcHostTables};
+use crate::diagnostics::{self, Call, CallError, HostCall};
+use crate::metrics::{Metrics, Sampler};
+use crate::validate;
+use crate::wapc_guest::{self, HandleCounts, Lifecycle, StreamKind, WapcGuest, WapcGuestData};
+use crate::wapc_host::WapcHost;
+
+/// The data stored within the `wasmtime::Store` of an
+/// `Instance`: the host implementation along with the
+/// bookkeeping the generated bindings need for both
+/// directions.
+pub struct Context<H: WapcHost> {
+    // `None` while the host is being moved into the store of a
+    // restarted guest, and for good if that panics.
+    host: Option<Supervised<H>>,
+    tables: WapcHostTables<Supervised<H>>,
+    guest: WapcGuestData,
+}
+
+impl<H: WapcHost> Context<H> {
+    fn new(host: Supervised<H>) -> Self {
+        Context {
+            host: Some(host),
+            tables: WapcHostTables::default(),
+            guest: WapcGuestData::default(),
+        }
+    }
+
+    fn supervised(&self) -> Result<&Supervised<H>, wasmtime::Trap> {
+        self.host.as_ref().ok_or_else(host_lost)
+    }
+
+    fn supervised_mut(&mut self) -> Result<&mut Supervised<H>, wasmtime::Trap> {
+        self.host.as_mut().ok_or_else(host_lost)
+    }
+
+    /// The host, unless it was lost to a restart of the guest
+    /// which panicked.
+    pub fn host(&self) -> Option<&H> {
+        self.host.as_ref().map(|supervised| &supervised.inner)
+    }
+
+    pub fn host_mut(&mut self) -> Option<&mut H> {
+        self.host.as_mut().map(|supervised| &mut supervised.inner)
+    }
+}
+
+/// What calls fail with once the host was lost to a restart of
+/// the guest which panicked.
+fn host_lost() -> wasmtime::Trap {
+    wasmtime::Trap::new("host was lost when restarting the guest panicked")
+}
+
+/// Callbacks a host implementation receives for calls made
+/// into the guest through an `Instance`, complementing the
+/// `WapcHost` methods which cover calls out of it.
+///
+/// Every method defaults to doing nothing.
+pub trait Hooks {
+    /// Called before `operation` is started in the guest, with
+    /// the metadata it will be sent with, which may be added to.
+    fn on_invoke(&mut self, operation: &str, payload: &[u8], metadata: &mut Vec<(String, String)>) {
+        let _ = (operation, payload, metadata);
+    }
+
+    /// Called once the guest has returned the id of `operation`,
+    /// or failed to start it.
+    fn on_invoked(&mut self, operation: &str, result: Result<u32, &CallError>) {
+        let _ = (operation, result);
+    }
+
+    /// Called before host request `id` is answered.
+    fn on_respond(&mut self, id: u32, code: u32, bytes: &[u8]) {
+        let _ = (id, code, bytes);
+    }
+
+    /// Called before host request `id` is failed.
+    fn on_fail(&mut self, id: u32, bytes: &[u8]) {
+        let _ = (id, bytes);
+    }
+
+    /// Called before invocation `id` is cancelled.
+    fn on_cancel(&mut self, id: u32) {
+        let _ = id;
+    }
+
+    /// Called once the guest trapped, after the invocations it
+    /// was handling have been failed. The host requests it was
+    /// waiting on are forgotten, whether or not the guest is
+    /// restarted, and answering them fails.
+    fn on_trap(&mut self, error: &CallError) {
+        let _ = error;
+    }
+
+    /// Returns a host request the host has answered on its own,
+    /// such as one it refused outright. The `Instance` delivers
+    /// these to the guest after every call into it.
+    fn take_settled(&mut self) -> Option<(u32, Answer)> {
+        None
+    }
+}
+
+static SETTLED_IDS: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Returns an id for a host request a wrapper settles on its
+/// own instead of passing it on, as `take_settled` returns.
+///
+/// Every wrapper takes these from the one counter, down from
+/// `u32::MAX`, while `StubHost` counts up from 1, so wrappers
+/// stacked on each other never give two requests the same id.
+pub fn settled_id() -> u32 {
+    SETTLED_IDS.fetch_sub(1, Ordering::Relaxed)
+}
+
+/// How a host request is to be answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Answer {
+    Response { code: u32, bytes: Vec<u8> },
+    Error(Vec<u8>),
+}
+
+/// How an `Instance` recovers from a guest trap.
+///
+/// A trapped guest is poisoned and, when a policy is set, is
+/// re-instantiated from the same module on the first call once
+/// a backoff has passed. Calls before then fail right away with
+/// `CallError::retry_after` set. At most `max_restarts` restarts
+/// are allowed within any `window`, and each backoff within a
+/// window is twice as long as the previous one, starting at
+/// `initial_backoff` and capped at `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    pub max_restarts: usize,
+    pub window: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RestartPolicy {
+    fn backoff(&self, recent: usize) -> Duration {
+        let factor = 1u32.checked_shl(recent as u32).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+}
+
+/// The error a request fails with when it is refused for lack of
+/// room, see `Limits`.
+pub const UNAVAILABLE: &[u8] = b"unavailable";
+
+/// A cap on the requests in flight in one direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    /// How many requests may await an answer at once; at least
+    /// one.
+    pub max_in_flight: usize,
+    /// How many more requests may wait for room, in order. Any
+    /// beyond that fail right away with `UNAVAILABLE`, so zero
+    /// fails fast.
+    pub queue: usize,
+}
+
+/// Caps on the requests in flight between an `Instance` and its
+/// host, in each direction.
+///
+/// In a limited direction the runtime hands out request ids of
+/// its own, since queued requests need one before the other side
+/// has seen them. The ids the host and the guest know a request
+/// by then differ, and are translated as answers pass through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Invocations of the guest by the host.
+    pub invocations: Option<Limit>,
+    /// Requests of the host by the guest.
+    pub host_requests: Option<Limit>,
+}
+
+struct Restarts {
+    policy: RestartPolicy,
+    recent: VecDeque<Instant>,
+    total: usize,
+    /// When a poisoned guest may be restarted.
+    not_before: Option<Instant>,
+}
+
+impl Restarts {
+    /// Forgets the restarts which fell out of the window.
+    fn prune(&mut self, now: Instant) {
+        while let Some(&at) = self.recent.front() {
+            if now.duration_since(at) < self.policy.window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    /// Holds off the next restart for as long as the restarts
+    /// within the window call for.
+    fn back_off(&mut self, now: Instant) {
+        self.prune(now);
+        self.not_before = Some(now + self.policy.backoff(self.recent.len()));
+    }
+}
+
+/// Why a guest cannot take a call.
+struct Unrecovered {
+    trap: wasmtime::Trap,
+    retry_after: Option<Duration>,
+}
+
+impl Unrecovered {
+    fn into_error(self, call: Call) -> CallError {
+        let mut error = CallError::new(call, self.trap);
+        error.retry_after = self.retry_after;
+        error
+    }
+}
+
+impl From<wasmtime::Trap> for Unrecovered {
+    fn from(trap: wasmtime::Trap) -> Self {
+        Unrecovered {
+            trap,
+            retry_after: None,
+        }
+    }
+}
+
+/// A single instantiated guest wired up to a `WapcHost`.
+///
+/// Requests into the guest are started with `invoke` and are
+/// answered asynchronously through the host's
+/// `wapc_on_guest_response` / `wapc_on_guest_error`. Requests
+/// the guest makes of the host are answered with `respond` or
+/// `fail`.
+///
+/// Either side may cancel a request it made: the host with
+/// `cancel`, the guest through `wapc_cancel`. Answers which
+/// arrive for a cancelled request are discarded.
+///
+/// Payloads and answers may also be streamed in chunks, see
+/// `send_chunk`, with the receiver of each stream granting
+/// credit for the chunks it is ready to take. `crate::stream`
+/// builds on these.
+///
+/// A trap poisons the guest: every invocation it had not yet
+/// answered is failed through `wapc_on_guest_error`, and its
+/// outstanding host requests can no longer be answered. See
+/// `RestartPolicy` for bringing it back.
+///
+/// Failed calls return a `CallError` describing the call and
+/// the guest's backtrace.
+pub struct Instance<H: WapcHost> {
+    module: wasmtime::Module,
+    store: wasmtime::Store<Context<H>>,
+    guest: WapcGuest<Context<H>>,
+    handle: wapc_guest::Guest,
+    instance: wasmtime::Instance,
+    restarts: Option<Restarts>,
+    dump_dir: Option<PathBuf>,
+    sampler: Option<Sampler>,
+}
+
+impl<H: WapcHost + Hooks + 'static> Instance<H> {
+    /// Validates and instantiates `module`, with `host`
+    /// servicing the guest's imports.
+    pub fn new(module: &wasmtime::Module, host: H) -> anyhow::Result<Self> {
+        validate::validate(module)?;
+
+        let mut store = wasmtime::Store::new(module.engine(), Context::new(Supervised::new(host)));
+        let (guest, handle, instance) = instantiate(module, &mut store)?;
+
+        Ok(Instance {
+            module: module.clone(),
+            store,
+            guest,
+            handle,
+            instance,
+            restarts: None,
+            dump_dir: None,
+            sampler: None,
+        })
+    }
+
+    /// Re-instantiates the guest after a trap according to
+    /// `policy`, instead of leaving it poisoned.
+    pub fn restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.restarts = Some(Restarts {
+            policy,
+            recent: VecDeque::new(),
+            total: 0,
+            not_before: None,
+        });
+        self
+    }
+
+    /// Writes a dump of the guest into `dir` whenever it traps,
+    /// see `CallError::dump`.
+    pub fn dump_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dump_dir = Some(dir.into());
+        self
+    }
+
+    /// Caps the requests in flight in each direction. Set this
+    /// before the first call into the guest.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        if let Ok(supervised) = self.store.data_mut().supervised_mut() {
+            supervised.limits = limits;
+        }
+        self
+    }
+
+    /// Reports the health of this instance, labelled with
+    /// `guest`, to `metrics`: its memory size, fuel consumed,
+    /// pending requests, traps and restarts.
+    pub fn metrics(mut self, metrics: &Metrics, guest: &str) -> Self {
+        self.sampler = Some(metrics.sampler(guest));
+        self.sample();
+        self
+    }
+
+    /// Starts `operation` in the guest, returning the id under
+    /// which its response will be delivered to the host.
+    pub fn invoke(&mut self, operation: &str, payload: &[u8]) -> Result<u32, CallError> {
+        self.invoke_with(operation, payload, &[] as &[(&str, &str)])
+    }
+
+    /// Starts `operation` in the guest like `invoke`, sending
+    /// `metadata` along with it.
+    ///
+    /// With a limit on invocations, an invocation beyond it is
+    /// queued or failed with `UNAVAILABLE` through
+    /// `wapc_on_guest_error`; either way it has an id.
+    pub fn invoke_with(
+        &mut self,
+        operation: &str,
+        payload: &[u8],
+        metadata: &[(impl AsRef<str>, impl AsRef<str>)],
+    ) -> Result<u32, CallError> {
+        let result = self.start(operation, payload, metadata);
+        self.sample();
+        result
+    }
+
+    fn start(
+        &mut self,
+        operation: &str,
+        payload: &[u8],
+        metadata: &[(impl AsRef<str>, impl AsRef<str>)],
+    ) -> Result<u32, CallError> {
+        let call = || Call::Invoke {
+            operation: operation.to_string(),
+        };
+        self.recover().map_err(|e| e.into_error(call()))?;
+        let mut metadata = metadata
+            .iter()
+            .map(|(key, value)| (key.as_ref().to_string(), value.as_ref().to_string()))
+            .collect();
+        let supervised = self.supervised_mut(call)?;
+        supervised
+            .inner
+            .on_invoke(operation, payload, &mut metadata);
+        let result = match supervised.limits.invocations {
+            Some(limit) => self.submit(limit, operation, payload, metadata),
+            None => {
+                let result = self.guest.wapc_init_guest_request(
+                    &mut self.store,
+                    &self.handle,
+                    operation,
+                    payload,
+                    &borrowed(&metadata),
+                );
+                match result {
+                    Ok(id) => {
+                        let supervised = self.supervised_mut(call)?;
+                        supervised.started(id);
+                        supervised.grant_early(id);
+                        Ok(id)
+                    }
+                    Err(trap) => Err(self.poison(call(), trap)),
+                }
+            }
+        };
+        self.supervised_mut(call)?
+            .inner
+            .on_invoked(operation, result.as_ref().copied());
+        let id = result?;
+        self.deliver_settled()?;
+        Ok(id)
+    }
+
+    /// Starts, queues or refuses an invocation under `limit`,
+    /// returning the id the host knows it by.
+    fn submit(
+        &mut self,
+        limit: Limit,
+        operation: &str,
+        payload: &[u8],
+        metadata: Vec<(String, String)>,
+    ) -> Result<u32, CallError> {
+        let call = || Call::Invoke {
+            operation: operation.to_string(),
+        };
+        let supervised = self.supervised_mut(call)?;
+        let id = supervised.next_id();
+        let full = supervised.invocations.len() >= limit.max_in_flight
+            || !supervised.queued_invocations.is_empty();
+        if !full {
+            self.start_aliased(id, operation, payload, &borrowed(&metadata))
+                .map_err(|trap| self.poison(call(), trap))?;
+        } else if supervised.queued_invocations.len() < limit.queue {
+            supervised.queued_invocations.push_back(QueuedInvocation {
+                id,
+                operation: operation.to_string(),
+                payload: payload.to_vec(),
+                metadata,
+                deferred: Vec::new(),
+            });
+        } else {
+            supervised.answer(id, Answer::Error(UNAVAILABLE.to_vec()));
+        }
+        Ok(id)
+    }
+
+    /// Starts invocation `id`, as the host knows it, in the guest
+    /// and remembers the guest's id for it.
+    fn start_aliased(
+        &mut self,
+        id: u32,
+        operation: &str,
+        payload: &[u8],
+        metadata: &[(&str, &str)],
+    ) -> Result<(), wasmtime::Trap> {
+        self.store.data_mut().supervised_mut()?.starting = true;
+        let result = self.guest.wapc_init_guest_request(
+            &mut self.store,
+            &self.handle,
+            operation,
+            payload,
+            metadata,
+        );
+        let supervised = self.store.data_mut().supervised_mut()?;
+        supervised.starting = false;
+        let early = std::mem::take(&mut supervised.early);
+        let guest_id = result?;
+        supervised.started(guest_id);
+        if !early.iter().any(|(answered, _)| *answered == guest_id) {
+            supervised.aliases.insert(guest_id, id);
+        }
+        supervised.grant_early(guest_id);
+        for (answered, answer) in early {
+            // Answers to ids the runtime never handed out are
+            // passed on as they are.
+            let answered = if answered == guest_id { id } else { answered };
+            supervised.answer(answered, answer);
+        }
+        Ok(())
+    }
+
+    /// Starts an invocation which waited for room.
+    fn start_queued(&mut self, queued: QueuedInvocation) -> Result<(), CallError> {
+        let metadata = borrowed(&queued.metadata);
+        let result = self.start_aliased(queued.id, &queued.operation, &queued.payload, &metadata);
+        let trap = match result {
+            Ok(()) => return self.replay(queued.id, queued.deferred),
+            Err(trap) => trap,
+        };
+        let call = Call::Invoke {
+            operation: queued.operation.clone(),
+        };
+        let mut error = self.poison(call, trap);
+        let message = format!("guest trapped: {}", error.trap);
+        if let Ok(supervised) = self.store.data_mut().supervised_mut() {
+            supervised.answer(queued.id, Answer::Error(message.into_bytes()));
+        }
+        error.abandoned.push(queued.id);
+        Err(error)
+    }
+
+    /// Cancels invocation `id`, e.g. because the client which
+    /// asked for it went away.
+    ///
+    /// The guest is told through its `cancel` export, and
+    /// whatever it answers from then on is discarded. Guests
+    /// acknowledge a cancellation by answering right away, which
+    /// lets the runtime forget the invocation; later answers are
+    /// counted as late. An invocation queued for lack of room is
+    /// dropped without reaching the guest.
+    pub fn cancel(&mut self, id: u32) -> Result<(), CallError> {
+        let result = self.cancel_once(id).and_then(|()| self.deliver_settled());
+        self.sample();
+        result
+    }
+
+    fn cancel_once(&mut self, id: u32) -> Result<(), CallError> {
+        let call = || Call::Cancel { id };
+        self.recover().map_err(|e| e.into_error(call()))?;
+        let supervised = self.supervised_mut(call)?;
+        if supervised.unqueue(id) {
+            supervised.inner.on_cancel(id);
+            return Ok(());
+        }
+        let guest_id = match supervised.withdraw(id) {
+            Some(guest_id) => guest_id,
+            None => {
+                let trap = wasmtime::Trap::new(format!("no pending invocation {}", id));
+                return Err(CallError::new(call(), trap));
+            }
+        };
+        supervised.inner.on_cancel(id);
+        supervised.cancelling = true;
+        let result = self
+            .guest
+            .wapc_cancel(&mut self.store, &self.handle, guest_id);
+        self.supervised_mut(call)?.cancelling = false;
+        result.map_err(|trap| self.poison(call(), trap))
+    }
+
+    /// Does what the host did to the streams of invocation `id`
+    /// while it was queued, unless the guest answered it as soon
+    /// as it started.
+    fn replay(&mut self, id: u32, deferred: Vec<Deferred>) -> Result<(), CallError> {
+        for deferred in deferred {
+            let supervised = self.supervised_mut(|| Call::Stream { id })?;
+            if supervised.guest_invocation(id).is_none() {
+                break;
+            }
+            match deferred {
+                Deferred::End => self.end_stream_once(id, None)?,
+                Deferred::Error(bytes) => self.end_stream_once(id, Some(&bytes))?,
+                Deferred::Credit(chunks) => self.grant_once(id, StreamKind::Response, chunks)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the guest a chunk of the stream `kind` of request
+    /// `id`: of the payload of invocation `id` for
+    /// `StreamKind::Request`, or of the answer to host request
+    /// `id` for `StreamKind::Response`. A streamed answer is
+    /// completed with `respond` or `fail`.
+    ///
+    /// Every chunk uses up one unit of the credit the guest
+    /// granted for the stream. Without credit the chunk is
+    /// refused, leaving the guest as it was.
+    pub fn send_chunk(&mut self, id: u32, kind: StreamKind, bytes: &[u8]) -> Result<(), CallError> {
+        let result = self
+            .send_chunk_once(id, kind, bytes)
+            .and_then(|()| self.deliver_settled());
+        self.sample();
+        result
+    }
+
+    /// Ends the streamed payload of invocation `id`.
+    pub fn end_stream(&mut self, id: u32) -> Result<(), CallError> {
+        let result = self
+            .end_stream_once(id, None)
+            .and_then(|()| self.deliver_settled());
+        self.sample();
+        result
+    }
+
+    /// Aborts the streamed payload of invocation `id` with
+    /// `bytes`.
+    pub fn fail_stream(&mut self, id: u32, bytes: &[u8]) -> Result<(), CallError> {
+        let result = self
+            .end_stream_once(id, Some(bytes))
+            .and_then(|()| self.deliver_settled());
+        self.sample();
+        result
+    }
+
+    /// Grants the guest credit for `chunks` more chunks of the
+    /// stream `kind` of request `id`: of the payload of host
+    /// request `id` for `StreamKind::Request`, or of the answer
+    /// to invocation `id` for `StreamKind::Response`.
+    pub fn grant(&mut self, id: u32, kind: StreamKind, chunks: u32) -> Result<(), CallError> {
+        let result = self
+            .grant_once(id, kind, chunks)
+            .and_then(|()| self.deliver_settled());
+        self.sample();
+        result
+    }
+
+    /// The chunks the host may still send on the stream `kind`
+    /// of request `id`, as for `send_chunk`.
+    pub fn credit(&self, id: u32, kind: StreamKind) -> u32 {
+        let supervised = self.store.data().supervised();
+        supervised.map_or(0, |supervised| {
+            supervised.credit.get(&(id, kind)).copied().unwrap_or(0)
+        })
+    }
+
+    fn send_chunk_once(
+        &mut self,
+        id: u32,
+        kind: StreamKind,
+        bytes: &[u8],
+    ) -> Result<(), CallError> {
+        let guest_id = self.stream_target(id, kind == StreamKind::Request)?;
+        let supervised = self.supervised_mut(|| Call::Stream { id })?;
+        let credit = supervised.credit.get_mut(&(id, kind));
+        let guest_id = match (guest_id, credit) {
+            (Some(guest_id), Some(credit)) if *credit > 0 => {
+                *credit -= 1;
+                guest_id
+            }
+            _ => {
+                let trap =
+                    wasmtime::Trap::new(format!("no credit for stream {:?} of {}", kind, id));
+                return Err(CallError::new(Call::Stream { id }, trap));
+            }
+        };
+        let result =
+            self.guest
+                .wapc_stream_chunk(&mut self.store, &self.handle, guest_id, kind, bytes);
+        result.map_err(|trap| self.poison(Call::Stream { id }, trap))
+    }
+
+    fn end_stream_once(&mut self, id: u32, error: Option<&[u8]>) -> Result<(), CallError> {
+        let guest_id = match self.stream_target(id, true)? {
+            Some(guest_id) => guest_id,
+            None => {
+                let deferred = match error {
+                    Some(bytes) => Deferred::Error(bytes.to_vec()),
+                    None => Deferred::End,
+                };
+                self.supervised_mut(|| Call::Stream { id })?
+                    .defer_invocation(id, deferred);
+                return Ok(());
+            }
+        };
+        self.supervised_mut(|| Call::Stream { id })?
+            .credit
+            .remove(&(id, StreamKind::Request));
+        let result = match error {
+            Some(bytes) => {
+                self.guest
+                    .wapc_stream_error(&mut self.store, &self.handle, guest_id, bytes)
+            }
+            None => self
+                .guest
+                .wapc_stream_end(&mut self.store, &self.handle, guest_id),
+        };
+        result.map_err(|trap| self.poison(Call::Stream { id }, trap))
+    }
+
+    fn grant_once(&mut self, id: u32, kind: StreamKind, chunks: u32) -> Result<(), CallError> {
+        let guest_id = match self.stream_target(id, kind == StreamKind::Response)? {
+            Some(guest_id) => guest_id,
+            None => {
+                self.supervised_mut(|| Call::Stream { id })?
+                    .defer_invocation(id, Deferred::Credit(chunks));
+                return Ok(());
+            }
+        };
+        // The guest may send chunks as soon as it hears of it.
+        self.supervised_mut(|| Call::Stream { id })?
+            .grant(guest_id, kind, chunks);
+        let result =
+            self.guest
+                .wapc_stream_credit(&mut self.store, &self.handle, guest_id, kind, chunks);
+        result.map_err(|trap| self.poison(Call::Stream { id }, trap))
+    }
+
+    /// Returns the guest's id for the pending invocation `id`, or
+    /// host request `id` when not `invocation`, or `None` while
+    /// the invocation is queued.
+    fn stream_target(&mut self, id: u32, invocation: bool) -> Result<Option<u32>, CallError> {
+        let call = || Call::Stream { id };
+        self.recover().map_err(|e| e.into_error(call()))?;
+        let error = |message: String| CallError::new(call(), wasmtime::Trap::new(message));
+        let supervised = self.supervised_mut(call)?;
+        if !invocation {
+            return match supervised.requests.get(&id) {
+                Some(request) => Ok(Some(request.guest_id)),
+                None => Err(error(format!("no outstanding host request {}", id))),
+            };
+        }
+        if let Some(guest_id) = supervised.guest_invocation(id) {
+            return Ok(Some(guest_id));
+        }
+        if supervised
+            .queued_invocations
+            .iter()
+            .any(|queued| queued.id == id)
+        {
+            return Ok(None);
+        }
+        Err(error(format!("no pending invocation {}", id)))
+    }
+
+    /// Answers the host request `id` previously made by the
+    /// guest.
+    pub fn respond(&mut self, id: u32, code: u32, bytes: &[u8]) -> Result<(), CallError> {
+        let result = self
+            .respond_once(id, code, bytes)
+            .and_then(|()| self.deliver_settled());
+        self.sample();
+        result
+    }
+
+    /// Fails the host request `id` previously made by the
+    /// guest.
+    pub fn fail(&mut self, id: u32, bytes: &[u8]) -> Result<(), CallError> {
+        let result = self
+            .fail_once(id, bytes)
+            .and_then(|()| self.deliver_settled());
+        self.sample();
+        result
+    }
+
+    fn respond_once(&mut self, id: u32, code: u32, bytes: &[u8]) -> Result<(), CallError> {
+        let request = match self.settle(id, |request| Call::Respond { id, request })? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        self.supervised_mut(|| Call::Respond { id, request: None })?
+            .inner
+            .on_respond(id, code, bytes);
+        let result = self.guest.wapc_on_host_response(
+            &mut self.store,
+            &self.handle,
+            request.guest_id,
+            code,
+            bytes,
+        );
+        let request = Some(Box::new(request.call));
+        result.map_err(|trap| self.poison(Call::Respond { id, request }, trap))
+    }
+
+    fn fail_once(&mut self, id: u32, bytes: &[u8]) -> Result<(), CallError> {
+        let request = match self.settle(id, |request| Call::Fail { id, request })? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        self.supervised_mut(|| Call::Fail { id, request: None })?
+            .inner
+            .on_fail(id, bytes);
+        let result =
+            self.guest
+                .wapc_on_host_error(&mut self.store, &self.handle, request.guest_id, bytes);
+        let request = Some(Box::new(request.call));
+        result.map_err(|trap| self.poison(Call::Fail { id, request }, trap))
+    }
+
+    /// Fails host request `id`, as the guest knows it, which was
+    /// refused for lack of room.
+    fn refuse(&mut self, id: u32) -> Result<(), CallError> {
+        let result = self
+            .guest
+            .wapc_on_host_error(&mut self.store, &self.handle, id, UNAVAILABLE);
+        result.map_err(|trap| self.poison(Call::Fail { id, request: None }, trap))
+    }
+
+    /// Delivers the host requests the host settled on its own and
+    /// those refused for lack of room, and moves queued requests
+    /// along as room frees up, including any the guest makes in
+    /// reaction to all that.
+    fn deliver_settled(&mut self) -> Result<(), CallError> {
+        loop {
+            let supervised = match self.store.data_mut().supervised_mut() {
+                Ok(supervised) => supervised,
+                // Nothing is left to deliver without the host.
+                Err(_) => return Ok(()),
+            };
+            supervised.forward_queued();
+            if let Some(id) = supervised.unavailable.pop_front() {
+                self.refuse(id)?;
+            } else if let Some((id, answer)) = supervised.inner.take_settled() {
+                match answer {
+                    Answer::Response { code, bytes } => self.respond_once(id, code, &bytes)?,
+                    Answer::Error(bytes) => self.fail_once(id, &bytes)?,
+                }
+            } else if let Some(queued) = supervised.dequeue_invocation() {
+                self.start_queued(queued)?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Updates the health metrics, if reported.
+    fn sample(&mut self) {
+        if self.sampler.is_none() {
+            return;
+        }
+        let (late_answers, invocations, requests) = match self.store.data_mut().supervised_mut() {
+            Ok(supervised) => (
+                std::mem::take(&mut supervised.late_answers),
+                supervised.invocations.len(),
+                supervised.requests.len(),
+            ),
+            Err(_) => return,
+        };
+        let memory = self
+            .instance
+            .get_memory(&mut self.store, "memory")
+            .map(|memory| memory.data_size(&self.store));
+        if let Some(sampler) = &mut self.sampler {
+            sampler.discarded_from_guest(late_answers);
+            sampler.sample(memory, self.store.fuel_consumed(), invocations, requests);
+        }
+    }
+
+    /// The supervised host, or an error failing `call` when the
+    /// host was lost.
+    fn supervised_mut(
+        &mut self,
+        call: impl FnOnce() -> Call,
+    ) -> Result<&mut Supervised<H>, CallError> {
+        let cx = self.store.data_mut();
+        cx.supervised_mut()
+            .map_err(|trap| CallError::new(call(), trap))
+    }
+
+    /// Whether the guest is waiting on host request `id`.
+    pub(crate) fn awaits(&self, id: u32) -> bool {
+        let supervised = self.store.data().supervised();
+        supervised.is_ok_and(|supervised| supervised.requests.contains_key(&id))
+    }
+
+    /// # Panics
+    ///
+    /// If the host was lost to a restart of the guest which
+    /// panicked, see `Context::host`.
+    pub fn host(&self) -> &H {
+        self.store
+            .data()
+            .host()
+            .expect("host was lost to a restart")
+    }
+
+    /// # Panics
+    ///
+    /// As for `host`.
+    pub fn host_mut(&mut self) -> &mut H {
+        self.store
+            .data_mut()
+            .host_mut()
+            .expect("host was lost to a restart")
+    }
+
+    /// Drops the guest and returns its host.
+    ///
+    /// # Panics
+    ///
+    /// As for `host`.
+    pub fn into_host(self) -> H {
+        let host = self.store.into_data().host;
+        host.expect("host was lost to a restart").inner
+    }
+
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.store.data().guest.lifecycle()
+    }
+
+    /// The number of times the guest has been restarted.
+    pub fn restarts(&self) -> usize {
+        self.restarts.as_ref().map_or(0, |r| r.total)
+    }
+
+    /// The number of live guest resources and handles, which
+    /// stays constant across calls unless the guest leaks.
+    pub fn handle_counts(&self) -> HandleCounts {
+        self.store.data().guest.handle_counts()
+    }
+
+    /// The underlying wasm instance, for reaching exports
+    /// beyond the `wapc-guest` ABI.
+    ///
+    /// This changes whenever the guest is restarted.
+    pub fn instance(&self) -> &wasmtime::Instance {
+        &self.instance
+    }
+
+    pub fn store(&self) -> &wasmtime::Store<Context<H>> {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut wasmtime::Store<Context<H>> {
+        &mut self.store
+    }
+
+    /// Checks that `id` is a host request the current guest is
+    /// waiting on, and that it is only answered once, returning
+    /// what was requested, or `None` when the guest cancelled it
+    /// and the answer is to be discarded.
+    fn settle(
+        &mut self,
+        id: u32,
+        call: impl FnOnce(Option<Box<HostCall>>) -> Call,
+    ) -> Result<Option<Outstanding>, CallError> {
+        if let Err(e) = self.recover() {
+            return Err(e.into_error(call(None)));
+        }
+        let supervised = match self.store.data_mut().supervised_mut() {
+            Ok(supervised) => supervised,
+            Err(trap) => return Err(CallError::new(call(None), trap)),
+        };
+        supervised.credit.remove(&(id, StreamKind::Response));
+        if supervised.cancelled_requests.remove(&id) {
+            if let Some(sampler) = &self.sampler {
+                sampler.discarded_from_host();
+            }
+            return Ok(None);
+        }
+        match supervised.requests.remove(&id) {
+            Some(request) => {
+                supervised
+                    .granted
+                    .remove(&(request.guest_id, StreamKind::Request));
+                Ok(Some(request))
+            }
+            None => Err(CallError::new(
+                call(None),
+                wasmtime::Trap::new(format!("no outstanding host request {}", id)),
+            )),
+        }
+    }
+
+    /// Poisons the guest after `trap` and fails everything it
+    /// left pending, describing it all in the returned error.
+    fn poison(&mut self, call: Call, trap: wasmtime::Trap) -> CallError {
+        let mut error = CallError::new(call, trap);
+        if let Some(sampler) = &self.sampler {
+            sampler.trapped();
+        }
+        if let Some(restarts) = &mut self.restarts {
+            restarts.back_off(Instant::now());
+        }
+        let cx = self.store.data_mut();
+        cx.guest.poison();
+        if let Ok(supervised) = cx.supervised_mut() {
+            error.abandoned =
+                supervised.abandon(format!("guest trapped: {}", error.trap).as_bytes());
+        }
+
+        if let Some(dir) = &self.dump_dir {
+            let memory = self
+                .instance
+                .get_memory(&mut self.store, "memory")
+                .map(|memory| memory.data(&self.store).to_vec());
+            error.dump = diagnostics::write_dump(dir, &error, memory.as_deref()).ok();
+        }
+        if let Ok(supervised) = self.store.data_mut().supervised_mut() {
+            supervised.inner.on_trap(&error);
+        }
+        error
+    }
+
+    /// Checks that the guest can take a call, restarting it if it
+    /// is poisoned, the policy allows it and its backoff passed.
+    fn recover(&mut self) -> Result<(), Unrecovered> {
+        self.store.data().supervised()?;
+        if self.lifecycle() != Lifecycle::Poisoned {
+            return Ok(());
+        }
+        let restarts = match &mut self.restarts {
+            Some(restarts) => restarts,
+            // Calls into a poisoned guest fail in the bindings.
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        restarts.prune(now);
+        if restarts.recent.len() >= restarts.policy.max_restarts {
+            let trap = wasmtime::Trap::new(format!(
+                "guest is poisoned and used up its {} restarts within {:?}",
+                restarts.policy.max_restarts, restarts.policy.window
+            ));
+            return Err(trap.into());
+        }
+        let wait = restarts
+            .not_before
+            .and_then(|at| at.checked_duration_since(now))
+            .filter(|wait| !wait.is_zero());
+        if let Some(wait) = wait {
+            return Err(Unrecovered {
+                trap: wasmtime::Trap::new(format!("guest is poisoned and restarts in {:?}", wait)),
+                retry_after: Some(wait),
+            });
+        }
+        restarts.recent.push_back(now);
+        restarts.total += 1;
+        let result = self.restart();
+        if let (Err(_), Some(restarts)) = (&result, &mut self.restarts) {
+            restarts.back_off(Instant::now());
+        }
+        result.map_err(|e| wasmtime::Trap::new(format!("{:#}", e)).into())
+    }
+
+    fn restart(&mut self) -> anyhow::Result<()> {
+        let host = self.store.data_mut().host.take().ok_or_else(host_lost)?;
+        let mut store = wasmtime::Store::new(self.module.engine(), Context::new(host));
+        match instantiate(&self.module, &mut store) {
+            Ok((guest, handle, instance)) => {
+                self.store = store;
+                self.guest = guest;
+                self.handle = handle;
+                self.instance = instance;
+                if let Some(sampler) = &mut self.sampler {
+                    sampler.restarted();
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Leave the old, poisoned guest in place so the
+                // host stays reachable.
+                self.store.data_mut().host = store.into_data().host;
+                Err(e.context("failed to restart guest"))
+            }
+        }
+    }
+}
+
+fn instantiate<H: WapcHost + 'static>(
+    module: &wasmtime::Module,
+    store: &mut wasmtime::Store<Context<H>>,
+) -> anyhow::Result<(WapcGuest<Context<H>>, wapc_guest::Guest, wasmtime::Instance)> {
+    let mut linker = wasmtime::Linker::new(module.engine());
+    wapc_host::add_to_linker(&mut linker, host_and_tables::<H>)?;
+    WapcGuest::add_to_linker(&mut linker, guest_data::<H>)?;
+
+    let mut imports = Vec::new();
+    for import in module.imports() {
+        let item = linker.get_by_import(&mut *store, &import).ok_or_else(|| {
+            anyhow::anyhow!("unknown import `{}::{}`", import.module(), import.name())
+        })?;
+        let item = match (import.module(), import.name()) {
+            ("wapc-host", "wapc::init-host-request") => init_host_request(&mut *store).into(),
+            ("wapc-host", "wapc::stream-chunk") => stream_chunk(&mut *store).into(),
+            _ => item,
+        };
+        imports.push(item);
+    }
+    let instance = wasmtime::Instance::new(&mut *store, module, &imports)?;
+    let guest = WapcGuest::new(&mut *store, &instance, guest_data::<H>)?;
+    let handle = guest.instance(&mut *store)?;
+    Ok((guest, handle, instance))
+}
+
+/// The `wapc::init-host-request` import, in place of the
+/// generated one. The generated import sizes the metadata `Vec`
+/// by the length the guest passes before reading any of it,
+/// which would let a guest abort the host with a huge or
+/// negative length; this one first traps when the list does not
+/// fit in the guest's memory.
+fn init_host_request<H: WapcHost + 'static>(
+    store: &mut wasmtime::Store<Context<H>>,
+) -> wasmtime::Func {
+    wasmtime::Func::wrap(
+        store,
+        |mut caller: wasmtime::Caller<'_, Context<H>>,
+         handle: i32,
+         binding_ptr: i32,
+         binding_len: i32,
+         namespace_ptr: i32,
+         namespace_len: i32,
+         operation_ptr: i32,
+         operation_len: i32,
+         bytes_ptr: i32,
+         bytes_len: i32,
+         metadata_ptr: i32,
+         metadata_len: i32| {
+            let memory = &get_memory(&mut caller, "memory")?;
+            let (mem, cx) = memory.data_and_store_mut(&mut caller);
+            let size = (mem.len() as u64).min(i32::MAX as u64);
+            let end = u64::from(metadata_ptr as u32) + u64::from(metadata_len as u32) * 16;
+            if metadata_ptr < 0 || metadata_len < 0 || end > size {
+                return Err(wasmtime::Trap::new("metadata list out of bounds"));
+            }
+            let mut bc = BorrowChecker::new(mem);
+            let mut metadata = Vec::with_capacity(metadata_len as usize);
+            for i in 0..metadata_len {
+                let base = metadata_ptr + i * 16;
+                let (key_ptr, key_len) = (bc.load::<i32>(base)?, bc.load::<i32>(base + 4)?);
+                let (value_ptr, value_len) = (bc.load::<i32>(base + 8)?, bc.load::<i32>(base + 12)?);
+                metadata.push((
+                    bc.slice_str(key_ptr, key_len)?,
+                    bc.slice_str(value_ptr, value_len)?,
+                ));
+            }
+            let (host, tables) = host_and_tables(cx);
+            let wapc = tables
+                .wapc_table
+                .get(handle as u32)
+                .ok_or_else(|| wasmtime::Trap::new("invalid handle index"))?;
+            let id = host.wapc_init_host_request(
+                wapc,
+                bc.slice_str(binding_ptr, binding_len)?,
+                bc.slice_str(namespace_ptr, namespace_len)?,
+                bc.slice_str(operation_ptr, operation_len)?,
+                bc.slice(bytes_ptr, bytes_len)?,
+                metadata,
+            );
+            Ok(id as i32)
+        },
+    )
+}
+
+/// The `wapc::stream-chunk` import, in place of the generated
+/// one, which traps a guest sending a chunk the host granted no
+/// credit for.
+fn stream_chunk<H: WapcHost + 'static>(store: &mut wasmtime::Store<Context<H>>) -> wasmtime::Func {
+    wasmtime::Func::wrap(
+        store,
+        |mut caller: wasmtime::Caller<'_, Context<H>>,
+         handle: i32,
+         id: i32,
+         kind: i32,
+         bytes_ptr: i32,
+         bytes_len: i32| {
+            let memory = &get_memory(&mut caller, "memory")?;
+            let (mem, cx) = memory.data_and_store_mut(&mut caller);
+            let mut bc = BorrowChecker::new(mem);
+            let kind = match kind {
+                0 => StreamKind::Request,
+                1 => StreamKind::Response,
+                _ => return Err(invalid_variant("StreamKind")),
+            };
+            let (host, tables) = host_and_tables(cx);
+            let wapc = tables
+                .wapc_table
+                .get(handle as u32)
+                .ok_or_else(|| wasmtime::Trap::new("invalid handle index"))?;
+            host.stream_chunk(wapc, id as u32, kind, bc.slice(bytes_ptr, bytes_len)?)
+        },
+    )
+}
+
+fn borrowed(metadata: &[(String, String)]) -> Vec<(&str, &str)> {
+    metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect()
+}
+
+/// A host request the guest is waiting on.
+struct Outstanding {
+    call: HostCall,
+    /// The id the guest knows the request by.
+    guest_id: u32,
+}
+
+/// An invocation waiting for the guest to have room for it.
+struct QueuedInvocation {
+    /// The id the host knows the invocation by.
+    id: u32,
+    operation: String,
+    payload: Vec<u8>,
+    metadata: Vec<(String, String)>,
+    deferred: Vec<Deferred>,
+}
+
+/// A host request waiting for the host to have room for it.
+struct QueuedRequest {
+    guest_id: u32,
+    call: HostCall,
+    bytes: Vec<u8>,
+    metadata: Vec<(String, String)>,
+    deferred: Vec<Deferred>,
+}
+
+/// Something done to the streams of a queued request, to be done
+/// again once it is passed on: ending or aborting the payload
+/// stream, or granting credit for the answer stream.
+enum Deferred {
+    End,
+    Error(Vec<u8>),
+    Credit(u32),
+}
+
+/// Wraps the host to keep track of what is in flight between it
+/// and the guest, so that a trapped guest's work can be failed,
+/// and to hold back what is beyond the `Limits`.
+struct Supervised<H: WapcHost> {
+    inner: H,
+    limits: Limits,
+    /// The last id the runtime handed out in a limited
+    /// direction.
+    last_id: u32,
+    /// Invocations the guest has yet to answer, by the guest's
+    /// id.
+    invocations: BTreeSet<u32>,
+    /// Invocations the guest answered before `invoke` returned
+    /// their id.
+    answered: BTreeSet<u32>,
+    /// Invocations the host cancelled, by the guest's id, whose
+    /// answers are discarded.
+    cancelled: BTreeSet<u32>,
+    /// Set while the guest is told of a cancellation, when its
+    /// answer is an acknowledgement rather than a late one.
+    cancelling: bool,
+    /// Late answers discarded since the metrics were last
+    /// sampled.
+    late_answers: u64,
+    /// The ids the host knows limited invocations by, keyed by
+    /// the guest's id.
+    aliases: HashMap<u32, u32>,
+    /// Set while a limited invocation is being started, when the
+    /// guest may answer it before its id is known.
+    starting: bool,
+    /// Answers the guest gave while `starting`, by the guest's
+    /// id.
+    early: Vec<(u32, Answer)>,
+    queued_invocations: VecDeque<QueuedInvocation>,
+    /// Host requests the guest is waiting on, by the host's id.
+    requests: BTreeMap<u32, Outstanding>,
+    queued_requests: VecDeque<QueuedRequest>,
+    /// Host requests the guest cancelled, by the host's id,
+    /// whose answers are discarded.
+    cancelled_requests: BTreeSet<u32>,
+    /// Host requests refused for lack of room, by the guest's
+    /// id.
+    unavailable: VecDeque<u32>,
+    /// The credit the guest granted for the streams the host
+    /// sends, by the host's id and the stream's kind.
+    credit: HashMap<(u32, StreamKind), u32>,
+    /// Credit the guest granted for the payload of invocations
+    /// whose id was not known yet, by the guest's id.
+    early_credit: Vec<(u32, u32)>,
+    /// The credit the host granted for the streams the guest
+    /// sends, by the guest's id and the stream's kind.
+    granted: HashMap<(u32, StreamKind), u32>,
+    /// The runtime's own handle, for calls into the host outside
+    /// of any call from the guest.
+    handle: Option<H::Wapc>,
+}
+
+impl<H: WapcHost> Supervised<H> {
+    fn new(inner: H) -> Self {
+        Supervised {
+            inner,
+            limits: Limits::default(),
+            last_id: 0,
+            invocations: BTreeSet::new(),
+            answered: BTreeSet::new(),
+            cancelled: BTreeSet::new(),
+            cancelling: false,
+            late_answers: 0,
+            aliases: HashMap::new(),
+            starting: false,
+            early: Vec::new(),
+            queued_invocations: VecDeque::new(),
+            requests: BTreeMap::new(),
+            queued_requests: VecDeque::new(),
+            cancelled_requests: BTreeSet::new(),
+            unavailable: VecDeque::new(),
+            credit: HashMap::new(),
+            early_credit: Vec::new(),
+            granted: HashMap::new(),
+            handle: None,
+        }
+    }
+
+    fn next_id(&mut self) -> u32 {
+        self.last_id = self.last_id.wrapping_add(1);
+        self.last_id
+    }
+
+    fn take_handle(&mut self) -> H::Wapc {
+        match self.handle.take() {
+            Some(handle) => handle,
+            None => self.inner.instance(),
+        }
+    }
+
+    /// Passes the guest's answer to invocation `id`, as the host
+    /// knows it, on to the host.
+    fn answer(&mut self, id: u32, answer: Answer) {
+        let handle = self.take_handle();
+        match answer {
+            Answer::Response { code, bytes } => {
+                self.inner.wapc_on_guest_response(&handle, id, code, &bytes)
+            }
+            Answer::Error(bytes) => self.inner.wapc_on_guest_error(&handle, id, &bytes),
+        }
+        self.handle = Some(handle);
+    }
+
+    /// The id the host knows the guest's invocation `id` by, or
+    /// `None` while it cannot be known yet.
+    fn host_id(&mut self, id: u32) -> Option<u32> {
+        if self.limits.invocations.is_none() {
+            return Some(id);
+        }
+        match self.aliases.remove(&id) {
+            Some(alias) => Some(alias),
+            None if self.starting => None,
+            // Not an invocation the runtime handed out an id for.
+            None => Some(id),
+        }
+    }
+
+    /// The next queued invocation, once the guest has room for
+    /// it.
+    fn dequeue_invocation(&mut self) -> Option<QueuedInvocation> {
+        let limit = self.limits.invocations?;
+        if self.invocations.len() >= limit.max_in_flight {
+            return None;
+        }
+        self.queued_invocations.pop_front()
+    }
+
+    /// Drops an answer to a cancelled invocation.
+    fn discard(&mut self) {
+        if !self.cancelling {
+            self.late_answers += 1;
+        }
+    }
+
+    /// Drops invocation `id` from the queue, returning whether
+    /// it was queued.
+    fn unqueue(&mut self, id: u32) -> bool {
+        let queued = self.queued_invocations.len();
+        self.queued_invocations
+            .retain(|invocation| invocation.id != id);
+        self.queued_invocations.len() < queued
+    }
+
+    /// Stops waiting on invocation `id`, as the host knows it,
+    /// returning the guest's id for it unless it was answered
+    /// already.
+    fn withdraw(&mut self, id: u32) -> Option<u32> {
+        let guest_id = self.guest_invocation(id)?;
+        self.aliases.remove(&guest_id);
+        self.invocations.remove(&guest_id);
+        self.cancelled.insert(guest_id);
+        self.credit.remove(&(id, StreamKind::Request));
+        self.granted.remove(&(guest_id, StreamKind::Response));
+        Some(guest_id)
+    }
+
+    /// The guest's id for the pending invocation `id`, as the
+    /// host knows it.
+    fn guest_invocation(&self, id: u32) -> Option<u32> {
+        let guest_id = match self.limits.invocations {
+            None => id,
+            Some(_) => *self.aliases.iter().find(|(_, &alias)| alias == id)?.0,
+        };
+        Some(guest_id).filter(|guest_id| self.invocations.contains(guest_id))
+    }
+
+    /// The host's id for the guest's pending invocation `id`.
+    fn host_invocation(&self, id: u32) -> Option<u32> {
+        if !self.invocations.contains(&id) {
+            return None;
+        }
+        Some(self.aliases.get(&id).copied().unwrap_or(id))
+    }
+
+    /// The host's id for the guest's host request `id`, once the
+    /// host has seen it and until it is answered.
+    fn host_request(&self, id: u32) -> Option<u32> {
+        match self.limits.host_requests {
+            None => Some(id).filter(|id| self.requests.contains_key(id)),
+            Some(_) => self
+                .requests
+                .iter()
+                .find(|(_, request)| request.guest_id == id)
+                .map(|(&host_id, _)| host_id),
+        }
+    }
+
+    fn defer_invocation(&mut self, id: u32, deferred: Deferred) {
+        if let Some(queued) = self.queued_invocations.iter_mut().find(|q| q.id == id) {
+            queued.deferred.push(deferred);
+        }
+    }
+
+    fn defer_request(&mut self, id: u32, deferred: Deferred) {
+        if let Some(queued) = self.queued_requests.iter_mut().find(|q| q.guest_id == id) {
+            queued.deferred.push(deferred);
+        }
+    }
+
+    /// Grants the credit the guest gave invocation `id`, as it
+    /// knows it, before its id was returned.
+    fn grant_early(&mut self, id: u32) {
+        for (guest_id, chunks) in std::mem::take(&mut self.early_credit) {
+            let host_id = match self.host_invocation(guest_id) {
+                Some(host_id) if guest_id == id => host_id,
+                _ => continue,
+            };
+            let handle = self.take_handle();
+            self.add_credit(&handle, host_id, StreamKind::Request, chunks);
+            self.handle = Some(handle);
+        }
+    }
+
+    /// Records that the host granted credit for `chunks` more
+    /// chunks of the stream `kind` of the guest's request `id`.
+    fn grant(&mut self, id: u32, kind: StreamKind, chunks: u32) {
+        let granted = self.granted.entry((id, kind)).or_insert(0);
+        *granted = granted.saturating_add(chunks);
+    }
+
+    /// Passes on a chunk the guest sent on the stream `kind` of
+    /// its request `id`, using up a unit of the credit the host
+    /// granted for it. Without credit the guest traps.
+    fn stream_chunk(
+        &mut self,
+        self_: &H::Wapc,
+        id: u32,
+        kind: StreamKind,
+        bytes: &[u8],
+    ) -> Result<(), wasmtime::Trap> {
+        match self.granted.get_mut(&(id, kind)) {
+            Some(granted) if *granted > 0 => *granted -= 1,
+            _ => {
+                return Err(wasmtime::Trap::new(format!(
+                    "guest sent a chunk of stream {:?} of {} without credit",
+                    kind, id
+                )))
+            }
+        }
+        self.wapc_stream_chunk(self_, id, kind, bytes);
+        Ok(())
+    }
+
+    fn add_credit(&mut self, self_: &H::Wapc, id: u32, kind: StreamKind, chunks: u32) {
+        let credit = self.credit.entry((id, kind)).or_insert(0);
+        *credit = credit.saturating_add(chunks);
+        self.inner.wapc_stream_credit(self_, id, kind, chunks)
+    }
+
+    /// Does to the streams of host request `id`, as the host
+    /// knows it, what the guest did.
+    fn forward_deferred(&mut self, self_: &H::Wapc, id: u32, deferred: Deferred) {
+        match deferred {
+            Deferred::End => self.inner.wapc_stream_end(self_, id),
+            Deferred::Error(bytes) => self.inner.wapc_stream_error(self_, id, &bytes),
+            Deferred::Credit(chunks) => self.add_credit(self_, id, StreamKind::Response, chunks),
+        }
+    }
+
+    /// Passes queued host requests on to the host while it has
+    /// room for them.
+    fn forward_queued(&mut self) {
+        let limit = match self.limits.host_requests {
+            Some(limit) => limit,
+            None => return,
+        };
+        while self.requests.len() < limit.max_in_flight {
+            let queued = match self.queued_requests.pop_front() {
+                Some(queued) => queued,
+                None => return,
+            };
+            let handle = self.take_handle();
+            let call = &queued.call;
+            let id = self.inner.wapc_init_host_request(
+                &handle,
+                &call.binding,
+                &call.namespace,
+                &call.operation,
+                &queued.bytes,
+                borrowed(&queued.metadata),
+            );
+            let outstanding = Outstanding {
+                call: queued.call,
+                guest_id: queued.guest_id,
+            };
+            self.requests.insert(id, outstanding);
+            for deferred in queued.deferred {
+                self.forward_deferred(&handle, id, deferred);
+            }
+            self.handle = Some(handle);
+        }
+    }
+
+    fn started(&mut self, id: u32) {
+        if !self.answered.remove(&id) {
+            self.invocations.insert(id);
+        }
+    }
+
+    fn finished(&mut self, id: u32) {
+        if !self.invocations.remove(&id) {
+            self.answered.insert(id);
+        }
+    }
+
+    /// Fails every unanswered or queued invocation with `message`
+    /// and forgets the outstanding host requests, returning the
+    /// ids of the failed invocations.
+    fn abandon(&mut self, message: &[u8]) -> Vec<u32> {
+        self.requests.clear();
+        self.queued_requests.clear();
+        self.cancelled_requests.clear();
+        self.unavailable.clear();
+        self.answered.clear();
+        self.cancelled.clear();
+        self.early.clear();
+        self.credit.clear();
+        self.early_credit.clear();
+        self.granted.clear();
+        let mut abandoned = Vec::new();
+        for id in std::mem::take(&mut self.invocations) {
+            abandoned.push(self.aliases.remove(&id).unwrap_or(id));
+        }
+        self.aliases.clear();
+        abandoned.extend(self.queued_invocations.drain(..).map(|queued| queued.id));
+        for &id in &abandoned {
+            self.answer(id, Answer::Error(message.to_vec()));
+        }
+        abandoned
+    }
+}
+
+impl<H: WapcHost> WapcHost for Supervised<H> {
+    type Wapc = H::Wapc;
+
+    fn instance(&mut self) -> H::Wapc {
+        self.inner.instance()
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        self_: &H::Wapc,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        let call = HostCall {
+            binding: binding.to_string(),
+            namespace: namespace.to_string(),
+            operation: operation.to_string(),
+        };
+        let limit = match self.limits.host_requests {
+            Some(limit) => limit,
+            None => {
+                let id = self
+                    .inner
+                    .wapc_init_host_request(self_, binding, namespace, operation, bytes, metadata);
+                self.requests.insert(id, Outstanding { call, guest_id: id });
+                return id;
+            }
+        };
+        let guest_id = self.next_id();
+        let full = self.requests.len() >= limit.max_in_flight || !self.queued_requests.is_empty();
+        if !full {
+            let id = self
+                .inner
+                .wapc_init_host_request(self_, binding, namespace, operation, bytes, metadata);
+            self.requests.insert(id, Outstanding { call, guest_id });
+        } else if self.queued_requests.len() < limit.queue {
+            self.queued_requests.push_back(QueuedRequest {
+                guest_id,
+                call,
+                bytes: bytes.to_vec(),
+                metadata: metadata
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+                deferred: Vec::new(),
+            });
+        } else {
+            self.unavailable.push_back(guest_id);
+        }
+        guest_id
+    }
+
+    fn wapc_on_guest_response(&mut self, self_: &H::Wapc, id: u32, code: u32, bytes: &[u8]) {
+        self.granted.remove(&(id, StreamKind::Response));
+        if self.cancelled.remove(&id) {
+            self.discard();
+            return;
+        }
+        self.finished(id);
+        match self.host_id(id) {
+            Some(id) => {
+                self.credit.remove(&(id, StreamKind::Request));
+                self.inner.wapc_on_guest_response(self_, id, code, bytes)
+            }
+            None => {
+                let bytes = bytes.to_vec();
+                self.early.push((id, Answer::Response { code, bytes }))
+            }
+        }
+    }
+
+    fn wapc_on_guest_error(&mut self, self_: &H::Wapc, id: u32, bytes: &[u8]) {
+        self.granted.remove(&(id, StreamKind::Response));
+        if self.cancelled.remove(&id) {
+            self.discard();
+            return;
+        }
+        self.finished(id);
+        match self.host_id(id) {
+            Some(id) => {
+                self.credit.remove(&(id, StreamKind::Request));
+                self.inner.wapc_on_guest_error(self_, id, bytes)
+            }
+            None => self.early.push((id, Answer::Error(bytes.to_vec()))),
+        }
+    }
+
+    fn wapc_console_log(&mut self, self_: &H::Wapc, message: &str) {
+        self.inner.wapc_console_log(self_, message)
+    }
+
+    fn wapc_cancel(&mut self, self_: &H::Wapc, id: u32) {
+        self.granted.remove(&(id, StreamKind::Request));
+        match self.host_request(id) {
+            Some(host_id) => {
+                self.requests.remove(&host_id);
+                self.credit.remove(&(host_id, StreamKind::Response));
+                self.cancelled_requests.insert(host_id);
+                self.inner.wapc_cancel(self_, host_id)
+            }
+            None => {
+                // The host has not seen it yet, or it was
+                // answered already.
+                self.queued_requests.retain(|queued| queued.guest_id != id);
+                self.unavailable.retain(|&unavailable| unavailable != id);
+            }
+        }
+    }
+
+    // Reached through `stream_chunk`, which checks the credit
+    // first.
+    fn wapc_stream_chunk(&mut self, self_: &H::Wapc, id: u32, kind: StreamKind, bytes: &[u8]) {
+        let host_id = match kind {
+            StreamKind::Request => self.host_request(id),
+            StreamKind::Response => self.host_invocation(id),
+        };
+        if let Some(host_id) = host_id {
+            self.inner.wapc_stream_chunk(self_, host_id, kind, bytes)
+        }
+    }
+
+    fn wapc_stream_end(&mut self, self_: &H::Wapc, id: u32) {
+        self.granted.remove(&(id, StreamKind::Request));
+        match self.host_request(id) {
+            Some(host_id) => self.forward_deferred(self_, host_id, Deferred::End),
+            None => self.defer_request(id, Deferred::End),
+        }
+    }
+
+    fn wapc_stream_error(&mut self, self_: &H::Wapc, id: u32, bytes: &[u8]) {
+        self.granted.remove(&(id, StreamKind::Request));
+        let error = Deferred::Error(bytes.to_vec());
+        match self.host_request(id) {
+            Some(host_id) => self.forward_deferred(self_, host_id, error),
+            None => self.defer_request(id, error),
+        }
+    }
+
+    fn wapc_stream_credit(&mut self, self_: &H::Wapc, id: u32, kind: StreamKind, chunks: u32) {
+        match kind {
+            StreamKind::Request => match self.host_invocation(id) {
+                Some(host_id) => self.add_credit(self_, host_id, kind, chunks),
+                None => self.early_credit.push((id, chunks)),
+            },
+            StreamKind::Response => match self.host_request(id) {
+                Some(host_id) => self.add_credit(self_, host_id, kind, chunks),
+                None => self.defer_request(id, Deferred::Credit(chunks)),
+            },
+        }
+    }
+
+    fn drop_wapc(&mut self, state: H::Wapc) {
+        self.inner.drop_wapc(state)
+    }
+}
+
+fn host_and_tables<H: WapcHost>(
+    cx: &mut Context<H>,
+) -> (&mut Supervised<H>, &mut WapcHostTables<Supervised<H>>) {
+    let host = cx
+        .host
+        .as_mut()
+        .expect("host is only taken while restarting");
+    (host, &mut cx.tables)
+}
+
+fn guest_data<H: WapcHost>(cx: &mut Context<H>) -> &mut WapcGuestData {
+    &mut cx.guest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::Chunk;
+    use crate::stub::StubHost;
+
+    /// The echo guest, except that it traps on payloads of four
+    /// bytes.
+    fn trapping(policy: RestartPolicy) -> Instance<StubHost> {
+        let wat = include_str!("../benches/guests/echo.wat").replace(
+            "    (local $id i32)\n",
+            "    (local $id i32)\n    (if (i32.eq (local.get $len) (i32.const 4)) (then unreachable))\n",
+        );
+        let wasm = wat::parse_str(wat).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        let instance = Instance::new(&module, StubHost::new()).unwrap();
+        instance.restart_policy(policy)
+    }
+
+    fn streamer() -> Instance<StubHost> {
+        let wasm = wat::parse_str(include_str!("../benches/guests/streamer.wat")).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        Instance::new(&module, StubHost::new()).unwrap()
+    }
+
+    fn fanout(limits: Limits) -> Instance<StubHost> {
+        let wasm = wat::parse_str(include_str!("../benches/guests/fanout.wat")).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        let instance = Instance::new(&module, StubHost::new()).unwrap();
+        instance.limits(limits)
+    }
+
+    fn invocations(max_in_flight: usize, queue: usize) -> Limits {
+        Limits {
+            invocations: Some(Limit {
+                max_in_flight,
+                queue,
+            }),
+            ..Limits::default()
+        }
+    }
+
+    fn relay() -> Instance<StubHost> {
+        let wasm = wat::parse_str(include_str!("../benches/guests/relay.wat")).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        Instance::new(&module, StubHost::new()).unwrap()
+    }
+
+    /// Answers the oldest host request the guest made.
+    fn respond(instance: &mut Instance<StubHost>) {
+        let request = instance.host_mut().take_request().unwrap();
+        instance.respond(request.id, 0, b"").unwrap();
+    }
+
+    #[test]
+    fn translates_the_ids_of_limited_invocations() {
+        let mut instance = fanout(invocations(1, 0));
+        let first = instance.invoke("fan", &[1]).unwrap();
+        // Refused without reaching the guest, yet it has an id.
+        let refused = instance.invoke("fan", &[1]).unwrap();
+        respond(&mut instance);
+        let host = instance.host_mut();
+        assert_eq!(host.take_result(first), Some(Ok(Vec::new())));
+        assert_eq!(host.take_result(refused), Some(Err(UNAVAILABLE.to_vec())));
+
+        // The guest knows this one by the id the host knows
+        // `refused` by, and its answer is passed on as `later`.
+        let later = instance.invoke("fan", &[1]).unwrap();
+        assert_ne!(later, refused);
+        respond(&mut instance);
+        assert_eq!(instance.host_mut().take_result(later), Some(Ok(Vec::new())));
+
+        // The same goes for answers arriving before the guest
+        // returned the id.
+        let early = instance.invoke("fan", &[0]).unwrap();
+        let host = instance.host_mut();
+        assert_eq!(host.take_result(early), Some(Ok(Vec::new())));
+        assert!(!host.has_result(early - 1));
+    }
+
+    #[test]
+    fn queues_invocations_beyond_the_limit_then_refuses_them() {
+        let mut instance = fanout(invocations(1, 1));
+        let first = instance.invoke("fan", &[1]).unwrap();
+        let queued = instance.invoke("fan", &[1]).unwrap();
+        let refused = instance.invoke("fan", &[1]).unwrap();
+        let host = instance.host_mut();
+        assert_eq!(host.requests().count(), 1);
+        assert!(!host.has_result(queued));
+        assert_eq!(host.take_result(refused), Some(Err(UNAVAILABLE.to_vec())));
+
+        // Answering the first makes room for the queued one.
+        respond(&mut instance);
+        let host = instance.host_mut();
+        assert_eq!(host.take_result(first), Some(Ok(Vec::new())));
+        assert_eq!(host.requests().count(), 1);
+        respond(&mut instance);
+        let host = instance.host_mut();
+        assert_eq!(host.take_result(queued), Some(Ok(Vec::new())));
+    }
+
+    #[test]
+    fn queues_host_requests_beyond_the_limit_then_refuses_them() {
+        let mut instance = fanout(Limits {
+            host_requests: Some(Limit {
+                max_in_flight: 1,
+                queue: 1,
+            }),
+            ..Limits::default()
+        });
+        // The third request is refused, which the guest counts as
+        // an answer.
+        let id = instance.invoke("fan", &[3]).unwrap();
+        assert_eq!(instance.host_mut().requests().count(), 1);
+
+        // Answering the first forwards the queued one.
+        respond(&mut instance);
+        let host = instance.host_mut();
+        assert_eq!(host.requests().count(), 1);
+        assert!(!host.has_result(id));
+        respond(&mut instance);
+        assert_eq!(instance.host_mut().take_result(id), Some(Ok(Vec::new())));
+    }
+
+    #[test]
+    fn traps_guests_streaming_without_credit() {
+        let mut instance = streamer();
+        let error = instance.invoke("stream", b"").unwrap_err();
+        assert!(error.to_string().contains("without credit"), "{}", error);
+        assert_eq!(instance.lifecycle(), Lifecycle::Poisoned);
+    }
+
+    #[test]
+    fn counts_the_chunks_sent_against_the_credit_granted() {
+        let mut instance = streamer();
+        let id = instance.invoke("stream", b"ab").unwrap();
+        let error = instance.grant(id, StreamKind::Response, 2).unwrap_err();
+        assert!(error.to_string().contains("without credit"), "{}", error);
+
+        // The chunks the host granted credit for got through.
+        let host = instance.host_mut();
+        for expected in [b"a", b"b"] {
+            let chunk = host.take_chunk(id, StreamKind::Response);
+            assert_eq!(chunk, Some(Chunk::Data(expected.to_vec())));
+        }
+        assert_eq!(host.take_chunk(id, StreamKind::Response), None);
+    }
+
+    #[test]
+    fn drops_cancelled_invocations_from_the_queue() {
+        let mut instance = fanout(invocations(1, 1));
+        let first = instance.invoke("fan", &[1]).unwrap();
+        let queued = instance.invoke("fan", &[1]).unwrap();
+        instance.cancel(queued).unwrap();
+
+        // Room frees up, but the cancelled one never starts.
+        respond(&mut instance);
+        let host = instance.host_mut();
+        assert_eq!(host.take_result(first), Some(Ok(Vec::new())));
+        assert_eq!(host.requests().count(), 0);
+        assert!(!host.has_result(queued));
+        let error = instance.cancel(queued).unwrap_err().to_string();
+        assert!(error.contains("no pending invocation"), "{}", error);
+    }
+
+    #[test]
+    fn discards_answers_after_cancellation() {
+        let mut instance = fanout(invocations(1, 0));
+        let id = instance.invoke("fan", &[1]).unwrap();
+        instance.cancel(id).unwrap();
+
+        // The guest ignores the cancellation and answers once
+        // the host does.
+        respond(&mut instance);
+        assert!(!instance.host_mut().has_result(id));
+
+        // And the room it took is free again.
+        let next = instance.invoke("fan", &[0]).unwrap();
+        assert_eq!(instance.host_mut().take_result(next), Some(Ok(Vec::new())));
+    }
+
+    #[test]
+    fn refuses_to_cancel_answered_invocations() {
+        let mut instance = fanout(Limits::default());
+        let id = instance.invoke("fan", &[0]).unwrap();
+        let error = instance.cancel(id).unwrap_err().to_string();
+        assert!(error.contains("no pending invocation"), "{}", error);
+        assert_eq!(instance.host_mut().take_result(id), Some(Ok(Vec::new())));
+    }
+
+    #[test]
+    fn discards_answers_to_requests_the_guest_cancelled() {
+        let mut instance = relay();
+        let id = instance.invoke("relay", b"b").unwrap();
+        let request = instance.host_mut().requests().next().unwrap().id;
+
+        // The guest cancels its request along with the
+        // invocation, while the host's answer is on its way.
+        instance.cancel(id).unwrap();
+        let host = instance.host_mut();
+        assert_eq!(host.take_cancelled(), Some(request));
+        assert_eq!(host.requests().count(), 0);
+        instance.respond(request, 0, b"late").unwrap();
+        assert!(!instance.host_mut().has_result(id));
+        assert_eq!(instance.lifecycle(), Lifecycle::Ready);
+    }
+
+    #[test]
+    fn restarts_once_the_backoff_passed() {
+        let mut instance = trapping(RestartPolicy {
+            max_restarts: 1,
+            window: Duration::from_secs(60),
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(50),
+        });
+        let error = instance.invoke("echo", b"trap").unwrap_err();
+        assert_eq!(error.retry_after, None);
+        assert_eq!(instance.lifecycle(), Lifecycle::Poisoned);
+
+        // Calls fail without waiting until the backoff passed.
+        let error = instance.invoke("echo", b"hello").unwrap_err();
+        let wait = error.retry_after.unwrap();
+        assert!(wait <= Duration::from_millis(50));
+        assert_eq!(instance.restarts(), 0);
+
+        std::thread::sleep(wait);
+        instance.invoke("echo", b"hello").unwrap();
+        assert_eq!(instance.restarts(), 1);
+
+        // With its restarts used up, waiting would not help.
+        instance.invoke("echo", b"trap").unwrap_err();
+        let error = instance.invoke("echo", b"hello").unwrap_err();
+        assert_eq!(error.retry_after, None);
+        assert!(error.to_string().contains("used up its 1 restarts"));
+    }
+
+    #[test]
+    fn echoes_payloads_larger_than_the_initial_memory() {
+        // The guest starts with one page and grows its memory to
+        // fit the payload, which optimized builds of
+        // wasmtime-runtime 0.38 break; see the workspace manifest.
+        let wasm = wat::parse_str(include_str!("../benches/guests/echo.wat")).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        let mut instance = Instance::new(&module, StubHost::new()).unwrap();
+        let payload = vec![0xa5; 256 * 1024];
+        let id = instance.invoke("echo", &payload).unwrap();
+        assert_eq!(instance.host_mut().take_result(id), Some(Ok(payload)));
+    }
+}
@@ -0,0 +1,276 @@
+use std::io;
+
+use crate::diagnostics::CallError;
+use crate::runtime::{Hooks, Instance};
+use crate::stub::StubHost;
+use crate::wapc_host::{StreamKind, WapcHost};
+
+/// The metadata key with which whoever makes a request announces
+/// that its payload is streamed (`request`), that it takes a
+/// streamed answer (`response`), or both (`request,response`).
+pub const STREAM: &str = "stream";
+
+/// Whether `metadata` announces the stream `kind`, see `STREAM`.
+pub fn announces<K: AsRef<str>, V: AsRef<str>>(metadata: &[(K, V)], kind: StreamKind) -> bool {
+    let wanted = match kind {
+        StreamKind::Request => "request",
+        StreamKind::Response => "response",
+    };
+    metadata
+        .iter()
+        .filter(|(key, _)| key.as_ref().eq_ignore_ascii_case(STREAM))
+        .flat_map(|(_, value)| value.as_ref().split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(wanted))
+}
+
+/// A piece of a stream the guest sends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    Data(Vec<u8>),
+    /// The stream is complete.
+    End,
+    /// The stream was aborted with these bytes.
+    Error(Vec<u8>),
+}
+
+/// Sends a stream to the guest in chunks, as the guest grants
+/// credit for them.
+///
+/// A chunk sent without credit is refused. As a `io::Write`,
+/// the sink reports that as `io::ErrorKind::WouldBlock`: the
+/// guest only grants more credit when it runs, so the caller has
+/// to drive it, e.g. by answering its requests, before writing
+/// again.
+pub struct Sink<'a, H: WapcHost> {
+    instance: &'a mut Instance<H>,
+    id: u32,
+    kind: StreamKind,
+}
+
+impl<'a, H: WapcHost + Hooks + 'static> Sink<'a, H> {
+    /// Streams the payload of invocation `id`, which was invoked
+    /// with `STREAM` metadata announcing it.
+    pub fn payload(instance: &'a mut Instance<H>, id: u32) -> Self {
+        Sink {
+            instance,
+            id,
+            kind: StreamKind::Request,
+        }
+    }
+
+    /// Streams the answer to host request `id`, which the guest
+    /// made with `STREAM` metadata accepting it.
+    pub fn answer(instance: &'a mut Instance<H>, id: u32) -> Self {
+        Sink {
+            instance,
+            id,
+            kind: StreamKind::Response,
+        }
+    }
+
+    /// The chunks which may be sent before the guest grants more
+    /// credit.
+    pub fn credit(&self) -> u32 {
+        self.instance.credit(self.id, self.kind)
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) -> Result<(), CallError> {
+        self.instance.send_chunk(self.id, self.kind, bytes)
+    }
+
+    /// Completes the stream: ends a payload, or answers the host
+    /// request with code 0 and nothing more.
+    pub fn end(self) -> Result<(), CallError> {
+        match self.kind {
+            StreamKind::Request => self.instance.end_stream(self.id),
+            StreamKind::Response => self.instance.respond(self.id, 0, &[]),
+        }
+    }
+
+    /// Aborts the stream: a payload through `fail_stream`, or an
+    /// answer by failing the host request.
+    pub fn fail(self, bytes: &[u8]) -> Result<(), CallError> {
+        match self.kind {
+            StreamKind::Request => self.instance.fail_stream(self.id, bytes),
+            StreamKind::Response => self.instance.fail(self.id, bytes),
+        }
+    }
+}
+
+impl<H: WapcHost + Hooks + 'static> io::Write for Sink<'_, H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.credit() == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.send(buf)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Receives a stream the guest sends through a `StubHost`,
+/// keeping the guest granted credit for up to `window` chunks
+/// which have not been read yet.
+///
+/// As an `Iterator` it yields the chunks the guest has sent,
+/// including the `End` or `Error` one the stream is over with.
+/// The guest sends chunks when it runs, e.g. as credit is
+/// granted back while reading, so iteration also stops when it
+/// has sent nothing more for now; `is_done` tells the two apart.
+pub struct Stream<'a, H: WapcHost> {
+    instance: &'a mut Instance<H>,
+    id: u32,
+    kind: StreamKind,
+    window: u32,
+    /// Chunks read since credit was last granted.
+    read: u32,
+    /// What comes once the chunks of a streamed answer run out.
+    last: Option<Chunk>,
+    done: bool,
+}
+
+impl<'a, H> Stream<'a, H>
+where
+    H: WapcHost + Hooks + AsMut<StubHost> + 'static,
+{
+    /// Receives the payload of host request `id`, which the
+    /// guest made with `STREAM` metadata announcing it.
+    pub fn payload(instance: &'a mut Instance<H>, id: u32, window: u32) -> Result<Self, CallError> {
+        Self::open(instance, id, StreamKind::Request, window)
+    }
+
+    /// Receives the answer to invocation `id`, which was invoked
+    /// with `STREAM` metadata accepting it. The stream ends with
+    /// the guest's answer, whose bytes are the last chunk.
+    pub fn answer(instance: &'a mut Instance<H>, id: u32, window: u32) -> Result<Self, CallError> {
+        Self::open(instance, id, StreamKind::Response, window)
+    }
+
+    fn open(
+        instance: &'a mut Instance<H>,
+        id: u32,
+        kind: StreamKind,
+        window: u32,
+    ) -> Result<Self, CallError> {
+        let window = window.max(1);
+        instance.grant(id, kind, window)?;
+        Ok(Stream {
+            instance,
+            id,
+            kind,
+            window,
+            read: 0,
+            last: None,
+            done: false,
+        })
+    }
+
+    /// Whether the stream is over.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// The next chunk, or `None` when the guest has not sent it
+    /// yet. Once the stream is over, this keeps returning `End`.
+    pub fn try_next(&mut self) -> Result<Option<Chunk>, CallError> {
+        if self.done {
+            return Ok(Some(Chunk::End));
+        }
+        let stub = self.instance.host_mut().as_mut();
+        let last = &mut self.last;
+        let chunk = match stub.take_chunk(self.id, self.kind).or_else(|| last.take()) {
+            Some(chunk) => chunk,
+            None if self.kind == StreamKind::Response => match stub.take_result(self.id) {
+                Some(Ok(bytes)) if bytes.is_empty() => Chunk::End,
+                Some(Ok(bytes)) => {
+                    self.last = Some(Chunk::End);
+                    Chunk::Data(bytes)
+                }
+                Some(Err(bytes)) => Chunk::Error(bytes),
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        match &chunk {
+            Chunk::Data(_) if self.last.is_none() => self.replenish()?,
+            Chunk::Data(_) => {}
+            Chunk::End | Chunk::Error(_) => self.done = true,
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Grants back the credit used up by the chunks read, once
+    /// they make up half the window.
+    fn replenish(&mut self) -> Result<(), CallError> {
+        self.read += 1;
+        if self.read < (self.window / 2).max(1) {
+            return Ok(());
+        }
+        // A guest which answered already takes no more chunks.
+        if self.kind == StreamKind::Response
+            && self.instance.host_mut().as_mut().has_result(self.id)
+        {
+            return Ok(());
+        }
+        let read = std::mem::take(&mut self.read);
+        self.instance.grant(self.id, self.kind, read)
+    }
+}
+
+impl<H> Iterator for Stream<'_, H>
+where
+    H: WapcHost + Hooks + AsMut<StubHost> + 'static,
+{
+    type Item = Result<Chunk, CallError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        self.try_next().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wit_bindgen_wasmtime::wasmtime;
+
+    use super::*;
+    use crate::stub::OVERFLOW;
+
+    fn streamer(host: StubHost) -> Instance<StubHost> {
+        let wasm = wat::parse_str(include_str!("../benches/guests/streamer.wat")).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        Instance::new(&module, host).unwrap()
+    }
+
+    fn read_answer(instance: &mut Instance<StubHost>, id: u32, window: u32) -> Vec<Chunk> {
+        let stream = Stream::answer(instance, id, window).unwrap();
+        stream.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn iterates_over_the_chunks_as_credit_is_granted_back() {
+        let mut instance = streamer(StubHost::new());
+        let id = instance.invoke("stream", b"x").unwrap();
+        let chunks = read_answer(&mut instance, id, 2);
+        let mut expected = [b"a", b"b", b"c", b"d", b"e"]
+            .iter()
+            .map(|bytes| Chunk::Data(bytes.to_vec()))
+            .collect::<Vec<_>>();
+        expected.push(Chunk::End);
+        assert_eq!(chunks, expected);
+    }
+
+    #[test]
+    fn aborts_streams_overflowing_the_buffer() {
+        let mut instance = streamer(StubHost::new().stream_buffer(2));
+        let id = instance.invoke("stream", b"x").unwrap();
+        let chunks = read_answer(&mut instance, id, 4);
+        assert_eq!(chunks, [Chunk::Error(OVERFLOW.to_vec())]);
+    }
+}
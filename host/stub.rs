@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+
+use serde::Deserialize;
+use wit_bindgen_wasmtime::anyhow;
+
+use crate::payload::{Codec, Payload};
+pub use crate::runtime::Answer;
+use crate::runtime::{Hooks, Instance};
+use crate::stream::Chunk;
+use crate::wapc_host::{StreamKind, WapcHost};
+
+/// The outcome of a request: the response bytes, or the error
+/// bytes it failed with.
+pub type GuestResult = Result<Vec<u8>, Vec<u8>>;
+
+/// A request made by the guest through `init-host-request`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRequest {
+    pub id: u32,
+    pub binding: String,
+    pub namespace: String,
+    pub operation: String,
+    pub payload: Vec<u8>,
+    /// Key-value pairs sent along with the request, such as a
+    /// `traceparent`.
+    pub metadata: Vec<(String, String)>,
+}
+
+impl HostRequest {
+    /// The value of metadata `key`, ignoring ASCII case.
+    pub fn metadata_value(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// How many bytes of streamed chunks a `StubHost` buffers by
+/// default, see `StubHost::stream_buffer`.
+pub const STREAM_BUFFER: usize = 16 << 20;
+
+/// What a stream is aborted with when its chunks overflow the
+/// buffer of a `StubHost`.
+pub const OVERFLOW: &[u8] = b"stream buffer overflowed";
+
+/// The handle `StubHost` gives out to guests.
+#[derive(Debug)]
+pub struct Handle;
+
+/// A `WapcHost` which queues every request the guest makes and
+/// collects the guest's responses, leaving it to the caller to
+/// decide how and when to answer.
+///
+/// Chunks the guest streams are buffered too, by request id and
+/// stream kind, see `crate::stream::Stream`, up to a limit on
+/// their total size. A chunk beyond it aborts its stream with
+/// `OVERFLOW` in place of the chunks buffered for it.
+pub struct StubHost {
+    next_id: u32,
+    requests: VecDeque<HostRequest>,
+    cancelled: VecDeque<u32>,
+    results: HashMap<u32, Answer>,
+    streams: HashMap<(u32, StreamKind), VecDeque<Chunk>>,
+    /// The bytes buffered in `streams`.
+    buffered: usize,
+    max_buffered: usize,
+    /// Streams aborted with `OVERFLOW`, whose chunks are dropped
+    /// until they end.
+    overflowed: HashSet<(u32, StreamKind)>,
+    log: Box<dyn FnMut(&str) + Send>,
+}
+
+impl StubHost {
+    /// Creates a host which prints `console-log` output to
+    /// stderr.
+    pub fn new() -> Self {
+        StubHost {
+            next_id: 0,
+            requests: VecDeque::new(),
+            cancelled: VecDeque::new(),
+            results: HashMap::new(),
+            streams: HashMap::new(),
+            buffered: 0,
+            max_buffered: STREAM_BUFFER,
+            overflowed: HashSet::new(),
+            log: Box::new(|message| eprintln!("{}", message)),
+        }
+    }
+
+    /// Limits the chunks buffered across all streams to `bytes`,
+    /// in place of `STREAM_BUFFER`.
+    pub fn stream_buffer(mut self, bytes: usize) -> Self {
+        self.max_buffered = bytes;
+        self
+    }
+
+    /// Replaces the sink for `console-log` output.
+    pub fn on_log(mut self, log: impl FnMut(&str) + Send + 'static) -> Self {
+        self.log = Box::new(log);
+        self
+    }
+
+    /// Removes the oldest unanswered host request.
+    pub fn take_request(&mut self) -> Option<HostRequest> {
+        self.requests.pop_front()
+    }
+
+    /// Removes the unanswered host request `id`.
+    pub fn take_request_by_id(&mut self, id: u32) -> Option<HostRequest> {
+        let index = self.requests.iter().position(|r| r.id == id)?;
+        self.requests.remove(index)
+    }
+
+    /// Host requests which have not been taken yet.
+    pub fn requests(&self) -> impl Iterator<Item = &HostRequest> {
+        self.requests.iter()
+    }
+
+    /// Removes the id of the oldest host request the guest
+    /// cancelled. Cancelled requests are no longer queued.
+    pub fn take_cancelled(&mut self) -> Option<u32> {
+        self.cancelled.pop_front()
+    }
+
+    /// Removes `id` from the host requests the guest cancelled,
+    /// returning whether it was there.
+    pub fn take_cancelled_by_id(&mut self, id: u32) -> bool {
+        match self.cancelled.iter().position(|&cancelled| cancelled == id) {
+            Some(index) => self.cancelled.remove(index).is_some(),
+            None => false,
+        }
+    }
+
+    /// Removes the guest's answer to request `id`, if it has
+    /// arrived.
+    pub fn take_result(&mut self, id: u32) -> Option<GuestResult> {
+        match self.take_answer(id)? {
+            Answer::Response { bytes, .. } => Some(Ok(bytes)),
+            Answer::Error(bytes) => Some(Err(bytes)),
+        }
+    }
+
+    /// Like `take_result`, keeping the response code.
+    pub fn take_answer(&mut self, id: u32) -> Option<Answer> {
+        self.results.remove(&id)
+    }
+
+    /// Whether the guest's answer to request `id` has arrived.
+    pub fn has_result(&self, id: u32) -> bool {
+        self.results.contains_key(&id)
+    }
+
+    /// Removes the oldest chunk the guest streamed on the stream
+    /// `kind` of request `id`: of the payload of host request
+    /// `id` for `StreamKind::Request`, or of the answer to
+    /// invocation `id` for `StreamKind::Response`.
+    pub fn take_chunk(&mut self, id: u32, kind: StreamKind) -> Option<Chunk> {
+        let chunks = self.streams.get_mut(&(id, kind))?;
+        let chunk = chunks.pop_front();
+        if chunks.is_empty() {
+            self.streams.remove(&(id, kind));
+        }
+        self.buffered -= chunk.as_ref().map_or(0, size);
+        chunk
+    }
+
+    fn buffer(&mut self, id: u32, kind: StreamKind, chunk: Chunk) {
+        let over = !matches!(chunk, Chunk::Data(_));
+        if self.overflowed.contains(&(id, kind)) {
+            if over {
+                self.overflowed.remove(&(id, kind));
+            }
+            return;
+        }
+        if self.buffered + size(&chunk) > self.max_buffered {
+            self.drop_stream(id, kind);
+            if !over {
+                self.overflowed.insert((id, kind));
+            }
+            let chunk = Chunk::Error(OVERFLOW.to_vec());
+            self.buffered += size(&chunk);
+            self.streams.entry((id, kind)).or_default().push_back(chunk);
+            return;
+        }
+        self.buffered += size(&chunk);
+        self.streams.entry((id, kind)).or_default().push_back(chunk);
+    }
+
+    /// Forgets the stream `kind` of request `id`.
+    fn drop_stream(&mut self, id: u32, kind: StreamKind) {
+        self.overflowed.remove(&(id, kind));
+        if let Some(chunks) = self.streams.remove(&(id, kind)) {
+            self.buffered -= chunks.iter().map(size).sum::<usize>();
+        }
+    }
+}
+
+fn size(chunk: &Chunk) -> usize {
+    match chunk {
+        Chunk::Data(bytes) | Chunk::Error(bytes) => bytes.len(),
+        Chunk::End => 0,
+    }
+}
+
+impl Default for StubHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsMut<StubHost> for StubHost {
+    fn as_mut(&mut self) -> &mut StubHost {
+        self
+    }
+}
+
+impl Hooks for StubHost {}
+
+impl WapcHost for StubHost {
+    type Wapc = Handle;
+
+    fn instance(&mut self) -> Handle {
+        Handle
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        _self_: &Handle,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.requests.push_back(HostRequest {
+            id: self.next_id,
+            binding: binding.to_string(),
+            namespace: namespace.to_string(),
+            operation: operation.to_string(),
+            payload: bytes.to_vec(),
+            metadata: metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        });
+        self.next_id
+    }
+
+    fn wapc_on_guest_response(&mut self, _self_: &Handle, id: u32, code: u32, bytes: &[u8]) {
+        self.overflowed.remove(&(id, StreamKind::Response));
+        let bytes = bytes.to_vec();
+        self.results.insert(id, Answer::Response { code, bytes });
+    }
+
+    fn wapc_on_guest_error(&mut self, _self_: &Handle, id: u32, bytes: &[u8]) {
+        self.overflowed.remove(&(id, StreamKind::Response));
+        self.results.insert(id, Answer::Error(bytes.to_vec()));
+    }
+
+    fn wapc_console_log(&mut self, _self_: &Handle, message: &str) {
+        (self.log)(message)
+    }
+
+    fn wapc_cancel(&mut self, _self_: &Handle, id: u32) {
+        self.take_request_by_id(id);
+        self.drop_stream(id, StreamKind::Request);
+        self.cancelled.push_back(id);
+    }
+
+    fn wapc_stream_chunk(&mut self, _self_: &Handle, id: u32, kind: StreamKind, bytes: &[u8]) {
+        self.buffer(id, kind, Chunk::Data(bytes.to_vec()));
+    }
+
+    fn wapc_stream_end(&mut self, _self_: &Handle, id: u32) {
+        self.buffer(id, StreamKind::Request, Chunk::End);
+    }
+
+    fn wapc_stream_error(&mut self, _self_: &Handle, id: u32, bytes: &[u8]) {
+        self.buffer(id, StreamKind::Request, Chunk::Error(bytes.to_vec()));
+    }
+
+    // The runtime keeps track of the credit the guest grants.
+    fn wapc_stream_credit(&mut self, _self_: &Handle, _id: u32, _kind: StreamKind, _chunks: u32) {}
+}
+
+/// Invokes `operation` and drives the instance until the guest
+/// answers it, settling every host request along the way with
+/// `answer`.
+///
+/// The host may be a `StubHost` or any wrapper around one.
+pub fn call<H>(
+    instance: &mut Instance<H>,
+    operation: &str,
+    payload: &[u8],
+    answer: impl FnMut(&HostRequest) -> Answer,
+) -> anyhow::Result<GuestResult>
+where
+    H: WapcHost + Hooks + AsMut<StubHost> + 'static,
+{
+    call_with(instance, operation, payload, &[] as &[(&str, &str)], answer)
+}
+
+/// Like `call`, sending `metadata` along with the invocation.
+pub fn call_with<H>(
+    instance: &mut Instance<H>,
+    operation: &str,
+    payload: &[u8],
+    metadata: &[(impl AsRef<str>, impl AsRef<str>)],
+    mut answer: impl FnMut(&HostRequest) -> Answer,
+) -> anyhow::Result<GuestResult>
+where
+    H: WapcHost + Hooks + AsMut<StubHost> + 'static,
+{
+    let id = instance.invoke_with(operation, payload, metadata)?;
+    loop {
+        if let Some(result) = instance.host_mut().as_mut().take_result(id) {
+            return Ok(result);
+        }
+        let request = match instance.host_mut().as_mut().take_request() {
+            Some(request) => request,
+            None => anyhow::bail!("guest did not answer request {} (`{}`)", id, operation),
+        };
+        match answer(&request) {
+            Answer::Response { code, bytes } => instance.respond(request.id, code, &bytes)?,
+            Answer::Error(bytes) => instance.fail(request.id, &bytes)?,
+        }
+    }
+}
+
+/// Canned answers to host requests, read from a JSON file.
+///
+/// ```json
+/// [
+///   { "binding": "kv", "operation": "get", "response": "value" },
+///   { "binding": "kv", "operation": "set", "error": { "hex": "00ff" } }
+/// ]
+/// ```
+///
+/// Omitted `binding`, `namespace` and `operation` fields match
+/// anything, and a fixture with `metadata` only matches
+/// requests carrying every one of its entries; the first
+/// matching fixture wins.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Fixtures(Vec<Fixture>);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Fixture {
+    pub binding: Option<String>,
+    pub namespace: Option<String>,
+    pub operation: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub code: u32,
+    pub response: Option<Payload>,
+    pub error: Option<Payload>,
+}
+
+impl Fixtures {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Answers `request` from the first matching fixture, or
+    /// fails it when none match.
+    pub fn answer(&self, request: &HostRequest) -> Answer {
+        self.answer_with(request, Codec::Json)
+    }
+
+    /// Like `answer`, encoding JSON payloads with `codec`.
+    pub fn answer_with(&self, request: &HostRequest, codec: Codec) -> Answer {
+        let fixture = self.0.iter().find(|f| {
+            matches(&f.binding, &request.binding)
+                && matches(&f.namespace, &request.namespace)
+                && matches(&f.operation, &request.operation)
+                && f.metadata
+                    .iter()
+                    .all(|(key, value)| request.metadata_value(key) == Some(value.as_str()))
+        });
+        let fixture = match fixture {
+            Some(fixture) => fixture,
+            None => {
+                return Answer::Error(
+                    format!(
+                        "no fixture for {}/{}/{}",
+                        request.binding, request.namespace, request.operation
+                    )
+                    .into_bytes(),
+                )
+            }
+        };
+        let decoded = match (&fixture.error, &fixture.response) {
+            (Some(error), _) => error.encode(codec).map(Answer::Error),
+            (None, Some(response)) => response.encode(codec).map(|bytes| Answer::Response {
+                code: fixture.code,
+                bytes,
+            }),
+            (None, None) => Ok(Answer::Response {
+                code: fixture.code,
+                bytes: Vec::new(),
+            }),
+        };
+        decoded.unwrap_or_else(|e| Answer::Error(format!("invalid fixture: {}", e).into_bytes()))
+    }
+}
+
+fn matches(pattern: &Option<String>, value: &str) -> bool {
+    pattern.as_deref().is_none_or(|p| p == value)
+}
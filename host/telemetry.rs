@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace as sdktrace, Resource};
+use opentelemetry::trace::{SpanId, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use wit_bindgen_wasmtime::anyhow;
+
+use crate::diagnostics::CallError;
+use crate::runtime::{Answer, Hooks};
+use crate::stub::StubHost;
+use crate::wapc_host::{StreamKind, WapcHost};
+
+/// The metadata key carrying the W3C trace context.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Exports spans to the OpenTelemetry collector listening for
+/// OTLP over HTTP at `endpoint`, e.g. `http://localhost:4318`.
+pub fn install_otlp(endpoint: &str) -> anyhow::Result<()> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            sdktrace::config()
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", "wapc")])),
+        )
+        .install_simple()?;
+    Ok(())
+}
+
+/// Flushes the spans not yet exported.
+pub fn shutdown() {
+    global::shutdown_tracer_provider()
+}
+
+/// A host request whose span is open.
+struct Open {
+    cx: Context,
+    /// The span of the invocation the request was made for.
+    parent: SpanId,
+}
+
+/// A `WapcHost` wrapper which traces every invocation as a
+/// server span and every host request as a client span.
+///
+/// Invocations are sent the `traceparent` of their span, which
+/// guests built with the SDK pass back with the host requests
+/// they make, so those requests become children of the
+/// invocation. An invocation which already carries a
+/// `traceparent` continues that trace.
+pub struct Traced<H> {
+    inner: H,
+    tracer: BoxedTracer,
+    propagator: TraceContextPropagator,
+    parent: Context,
+    /// The invocation being started, until the guest returns its
+    /// id or answers it.
+    starting: Option<Context>,
+    invocations: HashMap<u32, Context>,
+    requests: HashMap<u32, Open>,
+}
+
+impl<H> Traced<H> {
+    /// Creates a host which traces through the global tracer
+    /// provider, starting new traces for invocations without a
+    /// `traceparent`.
+    pub fn new(inner: H) -> Self {
+        Traced {
+            inner,
+            tracer: global::tracer("wapc"),
+            propagator: TraceContextPropagator::new(),
+            parent: Context::new(),
+            starting: None,
+            invocations: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Makes invocations without a `traceparent` children of the
+    /// span of `cx`.
+    pub fn with_parent(mut self, cx: Context) -> Self {
+        self.parent = cx;
+        self
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    fn extract<K: AsRef<str>, V: AsRef<str>>(&self, metadata: &[(K, V)]) -> Option<Context> {
+        metadata
+            .iter()
+            .any(|(key, _)| key.as_ref().eq_ignore_ascii_case(TRACEPARENT))
+            .then(|| self.propagator.extract(&Fields(metadata)))
+    }
+
+    /// Ends the span of invocation `id`, along with the spans
+    /// of any of its host requests still open when it failed.
+    fn end_invocation(&mut self, id: u32, status: Status) {
+        let cx = match self.invocations.remove(&id) {
+            Some(cx) => cx,
+            // The guest answered the invocation before returning
+            // its id.
+            None => match self.starting.take() {
+                Some(cx) => cx,
+                None => return,
+            },
+        };
+        if let Status::Error { description } = &status {
+            let span_id = cx.span().span_context().span_id();
+            let orphans = self
+                .requests
+                .iter()
+                .filter(|(_, open)| open.parent == span_id)
+                .map(|(&id, _)| id)
+                .collect::<Vec<_>>();
+            for id in orphans {
+                let message = format!("invocation failed: {}", description);
+                self.end_request(id, Status::error(message));
+            }
+        }
+        end(&cx, status);
+    }
+
+    fn end_request(&mut self, id: u32, status: Status) {
+        if let Some(open) = self.requests.remove(&id) {
+            end(&open.cx, status);
+        }
+    }
+}
+
+fn end(cx: &Context, status: Status) {
+    let span = cx.span();
+    span.set_status(status);
+    span.end();
+}
+
+impl<H> AsMut<StubHost> for Traced<H>
+where
+    H: AsMut<StubHost>,
+{
+    fn as_mut(&mut self) -> &mut StubHost {
+        self.inner.as_mut()
+    }
+}
+
+impl<H: Hooks> Hooks for Traced<H> {
+    fn on_invoke(&mut self, operation: &str, payload: &[u8], metadata: &mut Vec<(String, String)>) {
+        let parent = self
+            .extract(metadata)
+            .unwrap_or_else(|| self.parent.clone());
+        let span = self
+            .tracer
+            .span_builder(format!("invoke {}", operation))
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("wapc.operation", operation.to_string()),
+                KeyValue::new("wapc.payload_size", payload.len() as i64),
+            ])
+            .start_with_context(&self.tracer, &parent);
+        let cx = parent.with_span(span);
+        self.propagator.inject_context(&cx, &mut Carrier(metadata));
+        self.starting = Some(cx);
+        self.inner.on_invoke(operation, payload, metadata)
+    }
+
+    fn on_invoked(&mut self, operation: &str, result: Result<u32, &CallError>) {
+        if let Some(cx) = self.starting.take() {
+            match result {
+                Ok(id) => {
+                    cx.span()
+                        .set_attribute(KeyValue::new("wapc.invocation_id", id as i64));
+                    self.invocations.insert(id, cx);
+                }
+                Err(e) => end(&cx, Status::error(e.to_string())),
+            }
+        }
+        self.inner.on_invoked(operation, result)
+    }
+
+    fn on_respond(&mut self, id: u32, code: u32, bytes: &[u8]) {
+        if let Some(open) = self.requests.get(&id) {
+            open.cx
+                .span()
+                .set_attribute(KeyValue::new("wapc.response_code", code as i64));
+        }
+        self.end_request(id, Status::Ok);
+        self.inner.on_respond(id, code, bytes)
+    }
+
+    fn on_fail(&mut self, id: u32, bytes: &[u8]) {
+        self.end_request(
+            id,
+            Status::error(String::from_utf8_lossy(bytes).into_owned()),
+        );
+        self.inner.on_fail(id, bytes)
+    }
+
+    fn on_cancel(&mut self, id: u32) {
+        self.end_invocation(id, Status::error("cancelled"));
+        self.inner.on_cancel(id)
+    }
+
+    fn on_trap(&mut self, error: &CallError) {
+        // A trapped guest forgets its outstanding requests.
+        let message = format!("guest trapped: {}", error.trap);
+        for (_, open) in self.requests.drain() {
+            end(&open.cx, Status::error(message.clone()));
+        }
+        for (_, cx) in self.invocations.drain() {
+            end(&cx, Status::error(message.clone()));
+        }
+        self.inner.on_trap(error)
+    }
+
+    fn take_settled(&mut self) -> Option<(u32, Answer)> {
+        self.inner.take_settled()
+    }
+}
+
+impl<H: WapcHost> WapcHost for Traced<H> {
+    type Wapc = H::Wapc;
+
+    fn instance(&mut self) -> Self::Wapc {
+        self.inner.instance()
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        self_: &Self::Wapc,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        let parent = self
+            .extract(&metadata)
+            .or_else(|| self.starting.clone())
+            .unwrap_or_else(|| self.parent.clone());
+        let span = self
+            .tracer
+            .span_builder(format!("{}/{}/{}", binding, namespace, operation))
+            .with_kind(SpanKind::Client)
+            .with_attributes(vec![
+                KeyValue::new("wapc.binding", binding.to_string()),
+                KeyValue::new("wapc.namespace", namespace.to_string()),
+                KeyValue::new("wapc.operation", operation.to_string()),
+                KeyValue::new("wapc.payload_size", bytes.len() as i64),
+            ])
+            .start_with_context(&self.tracer, &parent);
+        let cx = parent.with_span(span);
+        let id = self
+            .inner
+            .wapc_init_host_request(self_, binding, namespace, operation, bytes, metadata);
+        cx.span()
+            .set_attribute(KeyValue::new("wapc.request_id", id as i64));
+        let open = Open {
+            cx,
+            parent: parent.span().span_context().span_id(),
+        };
+        self.requests.insert(id, open);
+        id
+    }
+
+    fn wapc_on_guest_response(&mut self, self_: &Self::Wapc, id: u32, code: u32, bytes: &[u8]) {
+        if let Some(cx) = self.invocations.get(&id).or(self.starting.as_ref()) {
+            cx.span()
+                .set_attribute(KeyValue::new("wapc.response_code", code as i64));
+        }
+        self.end_invocation(id, Status::Ok);
+        self.inner.wapc_on_guest_response(self_, id, code, bytes)
+    }
+
+    fn wapc_on_guest_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.end_invocation(
+            id,
+            Status::error(String::from_utf8_lossy(bytes).into_owned()),
+        );
+        self.inner.wapc_on_guest_error(self_, id, bytes)
+    }
+
+    fn wapc_console_log(&mut self, self_: &Self::Wapc, message: &str) {
+        self.inner.wapc_console_log(self_, message)
+    }
+
+    fn wapc_cancel(&mut self, self_: &Self::Wapc, id: u32) {
+        self.end_request(id, Status::error("cancelled"));
+        self.inner.wapc_cancel(self_, id)
+    }
+
+    fn wapc_stream_chunk(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, bytes: &[u8]) {
+        self.inner.wapc_stream_chunk(self_, id, kind, bytes)
+    }
+
+    fn wapc_stream_end(&mut self, self_: &Self::Wapc, id: u32) {
+        self.inner.wapc_stream_end(self_, id)
+    }
+
+    fn wapc_stream_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.inner.wapc_stream_error(self_, id, bytes)
+    }
+
+    fn wapc_stream_credit(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, chunks: u32) {
+        self.inner.wapc_stream_credit(self_, id, kind, chunks)
+    }
+
+    fn drop_wapc(&mut self, state: Self::Wapc) {
+        self.inner.drop_wapc(state)
+    }
+}
+
+/// Metadata being sent, as a propagator writes it.
+struct Carrier<'a>(&'a mut Vec<(String, String)>);
+
+impl Injector for Carrier<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        self.0.push((key.to_string(), value));
+    }
+}
+
+/// Metadata received, as a propagator reads it.
+struct Fields<'a, K, V>(&'a [(K, V)]);
+
+impl<K: AsRef<str>, V: AsRef<str>> Extractor for Fields<'_, K, V> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.as_ref().eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_ref())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(key, _)| key.as_ref()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wit_bindgen_wasmtime::wasmtime;
+
+    use super::*;
+    use crate::runtime::Instance;
+
+    #[test]
+    fn spans_open_at_a_trap_are_ended() {
+        // `fanout`, but trapping on the first host response.
+        let wat = include_str!("../benches/guests/fanout.wat").replace(
+            "(param $self i32) (param i32 i32 i32 i32)\n    (call $answered))",
+            "(param $self i32) (param i32 i32 i32 i32)\n    unreachable)",
+        );
+        let wasm = wat::parse_str(wat).unwrap();
+        let module = wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap();
+        let mut instance = Instance::new(&module, Traced::new(StubHost::new())).unwrap();
+
+        instance.invoke("fanout", &[2]).unwrap();
+        assert_eq!(instance.host().requests.len(), 2);
+        let request = instance.host_mut().as_mut().take_request().unwrap();
+        assert!(instance.respond(request.id, 0, b"").is_err());
+
+        assert!(instance.host().requests.is_empty());
+        assert!(instance.host().invocations.is_empty());
+    }
+}
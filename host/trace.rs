@@ -0,0 +1,610 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use wit_bindgen_wasmtime::{anyhow, wasmtime};
+
+use crate::diagnostics::CallError;
+use crate::payload::Payload;
+use crate::policy::Policy;
+use crate::runtime::{Answer, Hooks, Instance};
+use crate::stub::{Handle, StubHost};
+use crate::wapc_host::{StreamKind, WapcHost};
+
+/// A single interaction between host and guest, in the order it
+/// happened.
+///
+/// `Invoke`, `HostResponse`, `HostError` and `CancelInvocation`
+/// are actions taken by the host; every other event is
+/// something the guest did. Streamed chunks are not recorded,
+/// so traces of streamed requests do not replay faithfully.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    Invoke {
+        operation: String,
+        payload: Payload,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        metadata: Vec<(String, String)>,
+    },
+    HostRequest {
+        id: u32,
+        binding: String,
+        namespace: String,
+        operation: String,
+        payload: Payload,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        metadata: Vec<(String, String)>,
+    },
+    HostResponse {
+        id: u32,
+        code: u32,
+        payload: Payload,
+    },
+    HostError {
+        id: u32,
+        payload: Payload,
+    },
+    GuestResponse {
+        id: u32,
+        #[serde(default)]
+        code: u32,
+        payload: Payload,
+    },
+    GuestError {
+        id: u32,
+        payload: Payload,
+    },
+    ConsoleLog {
+        message: String,
+    },
+    CancelInvocation {
+        id: u32,
+    },
+    CancelHostRequest {
+        id: u32,
+    },
+}
+
+impl Event {
+    /// Whether this event is something the host does to the
+    /// guest, as opposed to something the guest does.
+    pub fn is_action(&self) -> bool {
+        matches!(
+            self,
+            Event::Invoke { .. }
+                | Event::HostResponse { .. }
+                | Event::HostError { .. }
+                | Event::CancelInvocation { .. }
+        )
+    }
+}
+
+/// Reads a trace file of JSON lines as written by `Recorder`.
+pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Vec<Event>> {
+    let file = io::BufReader::new(std::fs::File::open(path)?);
+    let mut events = Vec::new();
+    for (n, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("trace line {}: {}", n + 1, e))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+fn owned(metadata: &[(&str, &str)]) -> Vec<(String, String)> {
+    metadata
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// A `WapcHost` wrapper which writes every interaction with the
+/// guest to a trace, one JSON object per line, before passing
+/// it on to the wrapped host.
+pub struct Recorder<H> {
+    inner: H,
+    out: Box<dyn Write + Send>,
+    error: Option<io::Error>,
+}
+
+impl<H> Recorder<H> {
+    pub fn new(inner: H, out: impl Write + Send + 'static) -> Self {
+        Recorder {
+            inner,
+            out: Box::new(out),
+            error: None,
+        }
+    }
+
+    /// Flushes the trace and returns the wrapped host, or the
+    /// first error encountered while writing the trace.
+    pub fn finish(mut self) -> io::Result<H> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.inner)
+    }
+
+    fn record(&mut self, event: Event) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = serde_json::to_writer(&mut self.out, &event)
+            .map_err(io::Error::from)
+            .and_then(|()| self.out.write_all(b"\n"))
+            .and_then(|()| self.out.flush());
+        self.error = result.err();
+    }
+}
+
+impl<H> AsMut<StubHost> for Recorder<H>
+where
+    H: AsMut<StubHost>,
+{
+    fn as_mut(&mut self) -> &mut StubHost {
+        self.inner.as_mut()
+    }
+}
+
+impl<H: Hooks> Hooks for Recorder<H> {
+    fn on_invoke(&mut self, operation: &str, payload: &[u8], metadata: &mut Vec<(String, String)>) {
+        // Record the metadata as the guest will see it, including
+        // whatever the wrapped host adds.
+        self.inner.on_invoke(operation, payload, metadata);
+        self.record(Event::Invoke {
+            operation: operation.to_string(),
+            payload: Payload::from_bytes(payload),
+            metadata: metadata.clone(),
+        });
+    }
+
+    fn on_invoked(&mut self, operation: &str, result: Result<u32, &CallError>) {
+        self.inner.on_invoked(operation, result)
+    }
+
+    fn on_respond(&mut self, id: u32, code: u32, bytes: &[u8]) {
+        self.record(Event::HostResponse {
+            id,
+            code,
+            payload: Payload::from_bytes(bytes),
+        });
+        self.inner.on_respond(id, code, bytes)
+    }
+
+    fn on_fail(&mut self, id: u32, bytes: &[u8]) {
+        self.record(Event::HostError {
+            id,
+            payload: Payload::from_bytes(bytes),
+        });
+        self.inner.on_fail(id, bytes)
+    }
+
+    fn on_cancel(&mut self, id: u32) {
+        self.record(Event::CancelInvocation { id });
+        self.inner.on_cancel(id)
+    }
+
+    fn on_trap(&mut self, error: &CallError) {
+        self.inner.on_trap(error)
+    }
+
+    fn take_settled(&mut self) -> Option<(u32, Answer)> {
+        self.inner.take_settled()
+    }
+}
+
+impl<H: WapcHost> WapcHost for Recorder<H> {
+    type Wapc = H::Wapc;
+
+    fn instance(&mut self) -> Self::Wapc {
+        self.inner.instance()
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        self_: &Self::Wapc,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        let recorded = owned(&metadata);
+        let id = self
+            .inner
+            .wapc_init_host_request(self_, binding, namespace, operation, bytes, metadata);
+        self.record(Event::HostRequest {
+            id,
+            binding: binding.to_string(),
+            namespace: namespace.to_string(),
+            operation: operation.to_string(),
+            payload: Payload::from_bytes(bytes),
+            metadata: recorded,
+        });
+        id
+    }
+
+    fn wapc_on_guest_response(&mut self, self_: &Self::Wapc, id: u32, code: u32, bytes: &[u8]) {
+        self.record(Event::GuestResponse {
+            id,
+            code,
+            payload: Payload::from_bytes(bytes),
+        });
+        self.inner.wapc_on_guest_response(self_, id, code, bytes)
+    }
+
+    fn wapc_on_guest_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.record(Event::GuestError {
+            id,
+            payload: Payload::from_bytes(bytes),
+        });
+        self.inner.wapc_on_guest_error(self_, id, bytes)
+    }
+
+    fn wapc_console_log(&mut self, self_: &Self::Wapc, message: &str) {
+        self.record(Event::ConsoleLog {
+            message: message.to_string(),
+        });
+        self.inner.wapc_console_log(self_, message)
+    }
+
+    fn wapc_cancel(&mut self, self_: &Self::Wapc, id: u32) {
+        self.record(Event::CancelHostRequest { id });
+        self.inner.wapc_cancel(self_, id)
+    }
+
+    fn wapc_stream_chunk(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, bytes: &[u8]) {
+        self.inner.wapc_stream_chunk(self_, id, kind, bytes)
+    }
+
+    fn wapc_stream_end(&mut self, self_: &Self::Wapc, id: u32) {
+        self.inner.wapc_stream_end(self_, id)
+    }
+
+    fn wapc_stream_error(&mut self, self_: &Self::Wapc, id: u32, bytes: &[u8]) {
+        self.inner.wapc_stream_error(self_, id, bytes)
+    }
+
+    fn wapc_stream_credit(&mut self, self_: &Self::Wapc, id: u32, kind: StreamKind, chunks: u32) {
+        self.inner.wapc_stream_credit(self_, id, kind, chunks)
+    }
+
+    fn drop_wapc(&mut self, state: Self::Wapc) {
+        self.inner.drop_wapc(state)
+    }
+}
+
+/// A point at which a replay did not match its trace.
+///
+/// `expected` is `None` when the guest did something the trace
+/// does not contain, and `actual` is `None` when the guest did
+/// not do something the trace does.
+///
+/// `error` is set when replaying the action `expected` failed,
+/// e.g. because the guest trapped. It is always the last
+/// divergence, since the replay stops there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub index: usize,
+    pub expected: Option<Event>,
+    pub actual: Option<Event>,
+    pub error: Option<String>,
+}
+
+/// The `WapcHost` used during a replay: it answers the guest
+/// exactly as the recorded host did and compares everything
+/// the guest does with the trace.
+pub struct Replayer {
+    events: Vec<Event>,
+    cursor: usize,
+    next_id: u32,
+    divergences: Vec<Divergence>,
+    policies: Vec<Policy>,
+    refused: HashSet<u32>,
+}
+
+impl Replayer {
+    pub fn new(events: Vec<Event>) -> Self {
+        Replayer {
+            events,
+            cursor: 0,
+            next_id: u32::MAX,
+            divergences: Vec::new(),
+            policies: Vec::new(),
+            refused: HashSet::new(),
+        }
+    }
+
+    /// Refuses the host requests `policy` does not allow, as a
+    /// `Restricted` host would. A refused request is answered
+    /// with a `permission denied` error, and flagged as a
+    /// divergence if the trace answered it differently.
+    pub fn restrict(mut self, policy: Policy) -> Self {
+        self.policies.push(policy);
+        self
+    }
+
+    /// Returns the next host action in the trace, flagging any
+    /// guest events before it which did not happen.
+    fn next_action(&mut self) -> Option<Event> {
+        while let Some(event) = self.events.get(self.cursor).cloned() {
+            if event.is_action() {
+                self.cursor += 1;
+                return Some(event);
+            }
+            self.diverge(Some(event), None);
+            self.cursor += 1;
+        }
+        None
+    }
+
+    /// Matches `actual` against the next event in the trace.
+    fn observe(&mut self, actual: Event) {
+        match self.events.get(self.cursor).cloned() {
+            Some(expected) if !expected.is_action() => {
+                if expected != actual {
+                    self.diverge(Some(expected), Some(actual));
+                }
+                self.cursor += 1;
+            }
+            _ => self.diverge(None, Some(actual)),
+        }
+    }
+
+    /// Replaces the recorded answer to a refused host request
+    /// with the denial, flagging the difference.
+    fn refuse(&mut self, action: Event) -> Event {
+        let id = match &action {
+            Event::HostResponse { id, .. } | Event::HostError { id, .. } => *id,
+            _ => return action,
+        };
+        if !self.refused.remove(&id) {
+            return action;
+        }
+        let denial = Event::HostError {
+            id,
+            payload: Payload::from_bytes(b"permission denied"),
+        };
+        if action != denial {
+            // The answer is the event just consumed.
+            self.divergences.push(Divergence {
+                index: self.cursor - 1,
+                expected: Some(action),
+                actual: Some(denial.clone()),
+                error: None,
+            });
+        }
+        denial
+    }
+
+    fn diverge(&mut self, expected: Option<Event>, actual: Option<Event>) {
+        self.divergences.push(Divergence {
+            index: self.cursor,
+            expected,
+            actual,
+            error: None,
+        });
+    }
+}
+
+impl Hooks for Replayer {}
+
+impl WapcHost for Replayer {
+    type Wapc = Handle;
+
+    fn instance(&mut self) -> Handle {
+        Handle
+    }
+
+    fn wapc_init_host_request(
+        &mut self,
+        _self_: &Handle,
+        binding: &str,
+        namespace: &str,
+        operation: &str,
+        bytes: &[u8],
+        metadata: Vec<(&str, &str)>,
+    ) -> u32 {
+        // Host request ids are chosen by the host, so the guest
+        // must be handed the recorded id for the comparison of
+        // later events to be meaningful.
+        let recorded_id = match self.events.get(self.cursor) {
+            Some(Event::HostRequest { id, .. }) => *id,
+            _ => {
+                self.next_id -= 1;
+                self.next_id
+            }
+        };
+        let actual = Event::HostRequest {
+            id: recorded_id,
+            binding: binding.to_string(),
+            namespace: namespace.to_string(),
+            operation: operation.to_string(),
+            payload: Payload::from_bytes(bytes),
+            metadata: owned(&metadata),
+        };
+        self.observe(actual);
+        let allowed = self
+            .policies
+            .iter()
+            .all(|policy| policy.allows(binding, namespace, operation));
+        if !allowed {
+            self.refused.insert(recorded_id);
+        }
+        recorded_id
+    }
+
+    fn wapc_on_guest_response(&mut self, _self_: &Handle, id: u32, code: u32, bytes: &[u8]) {
+        self.observe(Event::GuestResponse {
+            id,
+            code,
+            payload: Payload::from_bytes(bytes),
+        });
+    }
+
+    fn wapc_on_guest_error(&mut self, _self_: &Handle, id: u32, bytes: &[u8]) {
+        self.observe(Event::GuestError {
+            id,
+            payload: Payload::from_bytes(bytes),
+        });
+    }
+
+    fn wapc_console_log(&mut self, _self_: &Handle, message: &str) {
+        self.observe(Event::ConsoleLog {
+            message: message.to_string(),
+        });
+    }
+
+    fn wapc_cancel(&mut self, _self_: &Handle, id: u32) {
+        self.observe(Event::CancelHostRequest { id });
+    }
+
+    // Streams are not recorded.
+
+    fn wapc_stream_chunk(&mut self, _self_: &Handle, _id: u32, _kind: StreamKind, _bytes: &[u8]) {}
+
+    fn wapc_stream_end(&mut self, _self_: &Handle, _id: u32) {}
+
+    fn wapc_stream_error(&mut self, _self_: &Handle, _id: u32, _bytes: &[u8]) {}
+
+    fn wapc_stream_credit(&mut self, _self_: &Handle, _id: u32, _kind: StreamKind, _chunks: u32) {}
+}
+
+/// Feeds the host actions of `replayer`'s trace to a fresh
+/// instance of `module` and returns every point at which the
+/// guest's behaviour differs from the trace.
+pub fn replay(module: &wasmtime::Module, replayer: Replayer) -> anyhow::Result<Vec<Divergence>> {
+    let mut instance = Instance::new(module, replayer)?;
+    while let Some(action) = instance.host_mut().next_action() {
+        let action = instance.host_mut().refuse(action);
+        let result = match &action {
+            Event::Invoke {
+                operation,
+                payload,
+                metadata,
+            } => instance
+                .invoke_with(operation, &payload.to_bytes()?, metadata)
+                .map(drop),
+            Event::HostResponse { id, code, payload } => {
+                instance.respond(*id, *code, &payload.to_bytes()?)
+            }
+            Event::HostError { id, payload } => instance.fail(*id, &payload.to_bytes()?),
+            Event::CancelInvocation { id } => instance.cancel(*id),
+            _ => unreachable!("`next_action` only returns actions"),
+        };
+        if let Err(error) = result {
+            // Whatever the trace holds after a failed call cannot
+            // be compared meaningfully, so the replay ends here.
+            let host = instance.host_mut();
+            host.divergences.push(Divergence {
+                index: host.cursor - 1,
+                expected: Some(action),
+                actual: None,
+                error: Some(error.to_string()),
+            });
+            return Ok(std::mem::take(&mut host.divergences));
+        }
+    }
+    // Anything left over is guest activity that never happened.
+    let host = instance.host_mut();
+    while let Some(event) = host.events.get(host.cursor).cloned() {
+        host.diverge(Some(event), None);
+        host.cursor += 1;
+    }
+    Ok(std::mem::take(&mut host.divergences))
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |event: &Option<Event>| match event {
+            Some(event) => serde_json::to_string(event).unwrap_or_default(),
+            None => "nothing".to_string(),
+        };
+        match &self.error {
+            Some(error) => write!(
+                f,
+                "at event {}: {} failed: {}",
+                self.index,
+                show(&self.expected),
+                error
+            ),
+            None => write!(
+                f,
+                "at event {}: expected {}, guest did {}",
+                self.index,
+                show(&self.expected),
+                show(&self.actual)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo() -> wasmtime::Module {
+        let wasm = wat::parse_str(include_str!("../benches/guests/echo.wat")).unwrap();
+        wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap()
+    }
+
+    fn invoke(payload: &[u8]) -> Event {
+        Event::Invoke {
+            operation: "echo".to_string(),
+            payload: Payload::from_bytes(payload),
+            metadata: Vec::new(),
+        }
+    }
+
+    fn response(id: u32, payload: &[u8]) -> Event {
+        Event::GuestResponse {
+            id,
+            code: 0,
+            payload: Payload::from_bytes(payload),
+        }
+    }
+
+    #[test]
+    fn replays_matching_traces() {
+        let events = vec![
+            invoke(b"a"),
+            response(1, b"a"),
+            invoke(b"b"),
+            response(2, b"b"),
+        ];
+        assert_eq!(replay(&echo(), Replayer::new(events)).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn failed_calls_end_the_replay_with_a_divergence() {
+        let events = vec![
+            invoke(b"a"),
+            response(1, b"b"),
+            Event::HostResponse {
+                id: 5,
+                code: 0,
+                payload: Payload::from_bytes(b""),
+            },
+            invoke(b"c"),
+            response(2, b"c"),
+        ];
+        let divergences = replay(&echo(), Replayer::new(events.clone())).unwrap();
+        assert_eq!(divergences.len(), 2, "{:?}", divergences);
+        assert_eq!(divergences[0].index, 1);
+        assert_eq!(divergences[0].expected, Some(events[1].clone()));
+        assert_eq!(divergences[0].actual, Some(response(1, b"a")));
+
+        assert_eq!(divergences[1].index, 2);
+        assert_eq!(divergences[1].expected, Some(events[2].clone()));
+        let error = divergences[1].error.as_deref().unwrap_or_default();
+        assert!(error.contains("no outstanding host request 5"), "{}", error);
+    }
+}
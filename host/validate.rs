@@ -0,0 +1,373 @@
+use std::fmt;
+
+use wit_bindgen_wasmtime::wasmtime::{self, ExternType, ValType};
+
+use ValType::I32;
+
+/// The shape an import or export is required to have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    Memory,
+    Func(&'static [ValType], &'static [ValType]),
+}
+
+/// Exports every guest must provide, as consumed by
+/// `WapcGuest::new`.
+pub const REQUIRED_EXPORTS: &[(&str, Expected)] = &[
+    ("memory", Expected::Memory),
+    ("canonical_abi_realloc", Expected::Func(&[I32, I32, I32, I32], &[I32])),
+    ("canonical_abi_drop_guest", Expected::Func(&[I32], &[])),
+    ("instance", Expected::Func(&[], &[I32])),
+    (
+        "guest::init-guest-request",
+        Expected::Func(&[I32, I32, I32, I32, I32, I32, I32], &[I32]),
+    ),
+    ("guest::on-host-response", Expected::Func(&[I32, I32, I32, I32, I32], &[])),
+    ("guest::on-host-error", Expected::Func(&[I32, I32, I32, I32], &[])),
+    ("guest::cancel", Expected::Func(&[I32, I32], &[])),
+    ("guest::scratch", Expected::Func(&[I32], &[I32])),
+    ("guest::stream-chunk", Expected::Func(&[I32, I32, I32, I32, I32], &[])),
+    ("guest::stream-end", Expected::Func(&[I32, I32], &[])),
+    ("guest::stream-error", Expected::Func(&[I32, I32, I32, I32], &[])),
+    ("guest::stream-credit", Expected::Func(&[I32, I32, I32, I32], &[])),
+];
+
+/// Imports a guest may use. Anything outside this list cannot
+/// be satisfied by the host linker.
+pub const ALLOWED_IMPORTS: &[(&str, &str, Expected)] = &[
+    ("wapc-host", "instance", Expected::Func(&[], &[I32])),
+    (
+        "wapc-host",
+        "wapc::init-host-request",
+        Expected::Func(&[I32, I32, I32, I32, I32, I32, I32, I32, I32, I32, I32], &[I32]),
+    ),
+    ("wapc-host", "wapc::on-guest-response", Expected::Func(&[I32, I32, I32, I32, I32], &[])),
+    ("wapc-host", "wapc::on-guest-error", Expected::Func(&[I32, I32, I32, I32], &[])),
+    ("wapc-host", "wapc::console-log", Expected::Func(&[I32, I32, I32], &[])),
+    ("wapc-host", "wapc::cancel", Expected::Func(&[I32, I32], &[])),
+    ("wapc-host", "wapc::stream-chunk", Expected::Func(&[I32, I32, I32, I32, I32], &[])),
+    ("wapc-host", "wapc::stream-end", Expected::Func(&[I32, I32], &[])),
+    ("wapc-host", "wapc::stream-error", Expected::Func(&[I32, I32, I32, I32], &[])),
+    ("wapc-host", "wapc::stream-credit", Expected::Func(&[I32, I32, I32, I32], &[])),
+    ("canonical_abi", "resource_drop_wapc", Expected::Func(&[I32], &[])),
+    ("canonical_abi", "resource_drop_guest", Expected::Func(&[I32], &[])),
+    ("canonical_abi", "resource_clone_guest", Expected::Func(&[I32], &[I32])),
+    ("canonical_abi", "resource_get_guest", Expected::Func(&[I32], &[I32])),
+    ("canonical_abi", "resource_new_guest", Expected::Func(&[I32], &[I32])),
+];
+
+/// A single problem found while validating a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    MissingExport {
+        name: String,
+        expected: Expected,
+    },
+    MistypedExport {
+        name: String,
+        expected: Expected,
+        found: String,
+    },
+    MistypedImport {
+        module: String,
+        name: String,
+        expected: Expected,
+        found: String,
+    },
+    UnexpectedImport {
+        module: String,
+        name: String,
+        found: String,
+    },
+}
+
+/// Every issue found in a module which is not a valid guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub issues: Vec<Issue>,
+}
+
+/// Checks the imports and exports of `module` against the
+/// `wapc-guest` / `wapc-host` ABI without instantiating it.
+///
+/// Unlike `WapcGuest::new`, which stops at the first missing
+/// export, this reports every problem at once.
+pub fn validate(module: &wasmtime::Module) -> Result<(), ValidationError> {
+    let issues = check(module);
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { issues })
+    }
+}
+
+/// Returns the list of issues with `module`, which is empty
+/// when the module is a valid guest.
+pub fn check(module: &wasmtime::Module) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    for (name, expected) in REQUIRED_EXPORTS {
+        match module.get_export(name) {
+            None => issues.push(Issue::MissingExport {
+                name: name.to_string(),
+                expected: *expected,
+            }),
+            Some(ty) if !expected.matches(&ty) => issues.push(Issue::MistypedExport {
+                name: name.to_string(),
+                expected: *expected,
+                found: describe(&ty),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for import in module.imports() {
+        let module_name = import.module();
+        let name = import.name();
+        let ty = import.ty();
+        let allowed = ALLOWED_IMPORTS
+            .iter()
+            .find(|(m, n, _)| *m == module_name && *n == name);
+        match allowed {
+            None => issues.push(Issue::UnexpectedImport {
+                module: module_name.to_string(),
+                name: name.to_string(),
+                found: describe(&ty),
+            }),
+            Some((_, _, expected)) if !expected.matches(&ty) => {
+                issues.push(Issue::MistypedImport {
+                    module: module_name.to_string(),
+                    name: name.to_string(),
+                    expected: *expected,
+                    found: describe(&ty),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    issues
+}
+
+impl Expected {
+    fn matches(&self, ty: &ExternType) -> bool {
+        match (self, ty) {
+            (Expected::Memory, ExternType::Memory(_)) => true,
+            (Expected::Func(params, results), ExternType::Func(f)) => {
+                f.params().eq(params.iter().cloned()) && f.results().eq(results.iter().cloned())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Memory => write!(f, "memory"),
+            Expected::Func(params, results) => {
+                write_signature(f, params.iter().cloned(), results.iter().cloned())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::MissingExport { name, expected } => {
+                write!(f, "missing export `{}`: expected {}", name, expected)
+            }
+            Issue::MistypedExport {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "export `{}` has the wrong type: expected {}, found {}",
+                name, expected, found
+            ),
+            Issue::MistypedImport {
+                module,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "import `{}::{}` has the wrong type: expected {}, found {}",
+                module, name, expected, found
+            ),
+            Issue::UnexpectedImport {
+                module,
+                name,
+                found,
+            } => write!(
+                f,
+                "unexpected import `{}::{}` ({}) is not provided by the host",
+                module, name, found
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "module is not a valid wapc guest:")?;
+        for issue in &self.issues {
+            write!(f, "\n  - {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// `ty` as validation issues and `wapc-inspect` show it.
+pub fn describe(ty: &ExternType) -> String {
+    match ty {
+        ExternType::Func(func) => {
+            let mut s = String::new();
+            let _ = write_signature(&mut s, func.params(), func.results());
+            s
+        }
+        ExternType::Memory(_) => "memory".to_string(),
+        ExternType::Table(_) => "table".to_string(),
+        ExternType::Global(_) => "global".to_string(),
+        #[allow(unreachable_patterns)]
+        _ => "unknown".to_string(),
+    }
+}
+
+fn write_signature(
+    out: &mut impl fmt::Write,
+    params: impl Iterator<Item = ValType>,
+    results: impl Iterator<Item = ValType>,
+) -> fmt::Result {
+    let list = |types: Vec<ValType>| {
+        types
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    write!(
+        out,
+        "func({}) -> ({})",
+        list(params.collect()),
+        list(results.collect())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(wat: &str) -> wasmtime::Module {
+        let wasm = wat::parse_str(wat).unwrap();
+        wasmtime::Module::new(&wasmtime::Engine::default(), wasm).unwrap()
+    }
+
+    /// The issues of `wat` other than its missing exports.
+    fn issues(wat: &str) -> Vec<Issue> {
+        check(&module(wat))
+            .into_iter()
+            .filter(|issue| !matches!(issue, Issue::MissingExport { .. }))
+            .collect()
+    }
+
+    #[test]
+    fn reports_missing_exports() {
+        let issues = check(&module(r#"(module (memory (export "memory") 1))"#));
+        assert_eq!(issues.len(), REQUIRED_EXPORTS.len() - 1);
+        assert_eq!(
+            issues[0],
+            Issue::MissingExport {
+                name: "canonical_abi_realloc".to_string(),
+                expected: Expected::Func(&[I32, I32, I32, I32], &[I32]),
+            }
+        );
+        assert_eq!(
+            issues[0].to_string(),
+            "missing export `canonical_abi_realloc`: expected func(i32, i32, i32, i32) -> (i32)"
+        );
+    }
+
+    #[test]
+    fn reports_mistyped_exports() {
+        assert_eq!(
+            issues(r#"(module (func (export "memory")) (func (export "instance")))"#),
+            [
+                Issue::MistypedExport {
+                    name: "memory".to_string(),
+                    expected: Expected::Memory,
+                    found: "func() -> ()".to_string(),
+                },
+                Issue::MistypedExport {
+                    name: "instance".to_string(),
+                    expected: Expected::Func(&[], &[I32]),
+                    found: "func() -> ()".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_mistyped_imports() {
+        let issues = issues(r#"(module (import "wapc-host" "instance" (func (param i32))))"#);
+        assert_eq!(
+            issues,
+            [Issue::MistypedImport {
+                module: "wapc-host".to_string(),
+                name: "instance".to_string(),
+                expected: Expected::Func(&[], &[I32]),
+                found: "func(i32) -> ()".to_string(),
+            }]
+        );
+        assert_eq!(
+            issues[0].to_string(),
+            "import `wapc-host::instance` has the wrong type: \
+             expected func() -> (i32), found func(i32) -> ()"
+        );
+    }
+
+    #[test]
+    fn reports_unexpected_imports() {
+        assert_eq!(
+            issues(r#"(module (import "env" "abort" (func)) (import "env" "memory" (memory 1)))"#),
+            [
+                Issue::UnexpectedImport {
+                    module: "env".to_string(),
+                    name: "abort".to_string(),
+                    found: "func() -> ()".to_string(),
+                },
+                Issue::UnexpectedImport {
+                    module: "env".to_string(),
+                    name: "memory".to_string(),
+                    found: "memory".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_issue_at_once() {
+        let module = module(
+            r#"(module
+                (import "env" "abort" (func))
+                (import "wapc-host" "wapc::cancel" (func))
+                (func (export "memory")))"#,
+        );
+        let issues = check(&module);
+        assert_eq!(issues.len(), REQUIRED_EXPORTS.len() + 2);
+        let error = validate(&module).unwrap_err();
+        assert_eq!(error.issues, issues);
+        let message = error.to_string();
+        assert!(message.starts_with("module is not a valid wapc guest:"));
+        assert_eq!(message.lines().count(), issues.len() + 1);
+    }
+
+    #[test]
+    fn echo_guest_is_valid() {
+        validate(&module(include_str!("../benches/guests/echo.wat"))).unwrap();
+    }
+}
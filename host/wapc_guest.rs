@@ -0,0 +1,475 @@
+//! Calls from the host into the exports `wapc-guest.wit`
+//! describes.
+//!
+//! This side of the ABI is written by hand rather than generated
+//! with `wit-bindgen`, as its calling convention differs from
+//! the generated one in ways the WIT cannot express:
+//!
+//! - The host lends the guest the single index it holds for the
+//!   guest's resource on every call, instead of cloning the
+//!   resource into a fresh index which the guest then drops.
+//! - Arguments are written into the buffer the guest returns
+//!   from `guest::scratch`, which the guest only borrows from for
+//!   the duration of the call, instead of into one
+//!   `canonical_abi_realloc` allocation each.
+//! - Calls into a guest which is not `Ready` trap instead of
+//!   running, and the guest's destructor is never run before it
+//!   is set or after the guest is poisoned.
+//!
+//! The exports, their names and their signatures are otherwise
+//! those of `wapc-guest.wit`, so the two must be kept in sync.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+
+use wit_bindgen_wasmtime::rt::{IndexSlab, RawMem, ResourceIndex, ResourceSlab};
+use wit_bindgen_wasmtime::{anyhow, wasmtime};
+
+// Both interfaces define the same enum, so the host shares the
+// one generated for `wapc-host.wit` between them.
+pub use crate::wapc_host::StreamKind;
+
+// Streams are kept in maps keyed by their kind, which the
+// generated enum has no `Hash` for.
+impl Hash for StreamKind {
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        (*self as u8).hash(state)
+    }
+}
+
+/// A host-owned reference to a guest resource.
+///
+/// The host keeps a single index for the resource in the
+/// guest's index slab for as long as it holds the reference,
+/// and lends that index to the guest on every call rather than
+/// handing out a fresh one.
+#[derive(Debug)]
+pub struct Guest {
+    resource: ResourceIndex,
+    handle: u32,
+}
+
+/// Where a guest instance is in its lifecycle.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// Instantiation has not finished; the guest's start
+    /// function may still be running.
+    #[default]
+    Uninitialized,
+    /// All exports are available.
+    Ready,
+    /// The guest's state can no longer be trusted, typically
+    /// after a trap.
+    Poisoned,
+}
+
+/// Live handle counts of a `WapcGuestData`, for observing
+/// leaks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandleCounts {
+    /// Resources whose destructor has not run yet.
+    pub resources: usize,
+    /// Entries in the index slab, i.e. handles held by either
+    /// side.
+    pub indices: usize,
+}
+
+/// The state of the guest's resources, which is stored within
+/// the data of the `Store<T>` so the `canonical_abi` intrinsics
+/// can reach it.
+#[derive(Default)]
+pub struct WapcGuestData {
+    index_slab: IndexSlab,
+    resource_slab: ResourceSlab,
+    dtor: Option<wasmtime::TypedFunc<i32, ()>>,
+    /// Indices the host lends out on calls, which the guest may
+    /// not drop.
+    borrowed: HashSet<u32>,
+    counts: HandleCounts,
+    lifecycle: Lifecycle,
+}
+
+impl WapcGuestData {
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.lifecycle
+    }
+
+    /// Marks the guest as unusable; every later call into it
+    /// fails with a trap.
+    pub fn poison(&mut self) {
+        self.lifecycle = Lifecycle::Poisoned;
+    }
+
+    fn check_ready(&self) -> Result<(), wasmtime::Trap> {
+        match self.lifecycle {
+            Lifecycle::Ready => Ok(()),
+            Lifecycle::Uninitialized => Err(wasmtime::Trap::new("guest is not initialized yet")),
+            Lifecycle::Poisoned => Err(wasmtime::Trap::new("guest is poisoned")),
+        }
+    }
+
+    /// Returns the number of live resources and handles.
+    pub fn handle_counts(&self) -> HandleCounts {
+        self.counts
+    }
+}
+
+/// `init-guest-request(self, operation, payload, metadata)`,
+/// with each string and list passed as a pointer and length.
+type InitGuestRequest = wasmtime::TypedFunc<(i32, i32, i32, i32, i32, i32, i32), i32>;
+
+/// The exports of a guest instance.
+pub struct WapcGuest<T> {
+    get_state: Box<dyn Fn(&mut T) -> &mut WapcGuestData + Send + Sync>,
+    canonical_abi_realloc: wasmtime::TypedFunc<(i32, i32, i32, i32), i32>,
+    memory: wasmtime::Memory,
+    instance: wasmtime::TypedFunc<(), i32>,
+    init_guest_request: InitGuestRequest,
+    on_host_response: wasmtime::TypedFunc<(i32, i32, i32, i32, i32), ()>,
+    on_host_error: wasmtime::TypedFunc<(i32, i32, i32, i32), ()>,
+    cancel: wasmtime::TypedFunc<(i32, i32), ()>,
+    scratch: wasmtime::TypedFunc<i32, i32>,
+    stream_chunk: wasmtime::TypedFunc<(i32, i32, i32, i32, i32), ()>,
+    stream_end: wasmtime::TypedFunc<(i32, i32), ()>,
+    stream_error: wasmtime::TypedFunc<(i32, i32, i32, i32), ()>,
+    stream_credit: wasmtime::TypedFunc<(i32, i32, i32, i32), ()>,
+}
+
+impl<T> WapcGuest<T> {
+    /// Adds the `canonical_abi` intrinsics for the guest's
+    /// resource to `linker`.
+    ///
+    /// The `get_state` closure is required to access the
+    /// auxiliary data necessary for these wasm exports from the
+    /// general store's state.
+    pub fn add_to_linker(
+        linker: &mut wasmtime::Linker<T>,
+        get_state: impl Fn(&mut T) -> &mut WapcGuestData + Send + Sync + Copy + 'static,
+    ) -> anyhow::Result<()> {
+        linker.func_wrap(
+            "canonical_abi",
+            "resource_drop_guest",
+            move |mut caller: wasmtime::Caller<'_, T>, idx: u32| {
+                let state = get_state(caller.data_mut());
+                // Before touching any state, so that a trap leaves
+                // the handle as it was.
+                state.check_ready()?;
+                if state.borrowed.contains(&idx) {
+                    return Err(wasmtime::Trap::new("cannot drop a borrowed handle"));
+                }
+                let resource_idx = state.index_slab.remove(idx)?;
+                state.counts.indices -= 1;
+                let wasm = match state.resource_slab.drop(resource_idx) {
+                    Some(wasm) => wasm,
+                    None => return Ok(()),
+                };
+                state.counts.resources -= 1;
+                let dtor = state
+                    .dtor
+                    .ok_or_else(|| wasmtime::Trap::new("destructor not set yet"))?;
+                dtor.call(&mut caller, wasm)?;
+                Ok(())
+            },
+        )?;
+        linker.func_wrap(
+            "canonical_abi",
+            "resource_clone_guest",
+            move |mut caller: wasmtime::Caller<'_, T>, idx: u32| {
+                let state = get_state(caller.data_mut());
+                let resource_idx = state.index_slab.get(idx)?;
+                state.resource_slab.clone(resource_idx)?;
+                state.counts.indices += 1;
+                Ok(state.index_slab.insert(resource_idx))
+            },
+        )?;
+        linker.func_wrap(
+            "canonical_abi",
+            "resource_get_guest",
+            move |mut caller: wasmtime::Caller<'_, T>, idx: u32| {
+                let state = get_state(caller.data_mut());
+                let resource_idx = state.index_slab.get(idx)?;
+                Ok(state.resource_slab.get(resource_idx))
+            },
+        )?;
+        linker.func_wrap(
+            "canonical_abi",
+            "resource_new_guest",
+            move |mut caller: wasmtime::Caller<'_, T>, val: i32| {
+                let state = get_state(caller.data_mut());
+                let resource_idx = state.resource_slab.insert(val);
+                state.counts.resources += 1;
+                state.counts.indices += 1;
+                Ok(state.index_slab.insert(resource_idx))
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Looks up the exports of `instance`, which must have been
+    /// instantiated with a linker `add_to_linker` was called on,
+    /// and marks the guest `Ready`.
+    pub fn new(
+        mut store: impl wasmtime::AsContextMut<Data = T>,
+        instance: &wasmtime::Instance,
+        get_state: impl Fn(&mut T) -> &mut WapcGuestData + Send + Sync + Copy + 'static,
+    ) -> anyhow::Result<Self> {
+        let mut store = store.as_context_mut();
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("`memory` export not a memory"))?;
+        let guest = WapcGuest {
+            canonical_abi_realloc: instance.get_typed_func(&mut store, "canonical_abi_realloc")?,
+            memory,
+            instance: instance.get_typed_func(&mut store, "instance")?,
+            init_guest_request: instance.get_typed_func(&mut store, "guest::init-guest-request")?,
+            on_host_response: instance.get_typed_func(&mut store, "guest::on-host-response")?,
+            on_host_error: instance.get_typed_func(&mut store, "guest::on-host-error")?,
+            cancel: instance.get_typed_func(&mut store, "guest::cancel")?,
+            scratch: instance.get_typed_func(&mut store, "guest::scratch")?,
+            stream_chunk: instance.get_typed_func(&mut store, "guest::stream-chunk")?,
+            stream_end: instance.get_typed_func(&mut store, "guest::stream-end")?,
+            stream_error: instance.get_typed_func(&mut store, "guest::stream-error")?,
+            stream_credit: instance.get_typed_func(&mut store, "guest::stream-credit")?,
+            get_state: Box::new(get_state),
+        };
+        let dtor = instance.get_typed_func(&mut store, "canonical_abi_drop_guest")?;
+        let state = get_state(store.data_mut());
+        state.dtor = Some(dtor);
+        state.lifecycle = Lifecycle::Ready;
+        Ok(guest)
+    }
+
+    /// Asks the guest for its resource, whose index the host
+    /// keeps to lend out on calls.
+    pub fn instance(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+    ) -> Result<Guest, wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        let handle = self.instance.call(&mut caller, ())? as u32;
+        let mut caller = caller.as_context_mut();
+        let state = (self.get_state)(caller.data_mut());
+        let resource = state.index_slab.get(handle)?;
+        state.borrowed.insert(handle);
+        Ok(Guest { resource, handle })
+    }
+
+    pub fn wapc_init_guest_request(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+        self_: &Guest,
+        operation: &str,
+        payload: &[u8],
+        metadata: &[(&str, &str)],
+    ) -> Result<u32, wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        let mut args = vec![operation.as_bytes(), payload];
+        for (key, value) in metadata {
+            args.push(key.as_bytes());
+            args.push(value.as_bytes());
+        }
+        let ptrs = self.store_all(&mut caller, &args)?;
+        // The list itself holds a (ptr, len, ptr, len) record per
+        // entry, always in an allocation the guest owns.
+        let records = if metadata.is_empty() {
+            0
+        } else {
+            let mut records = Vec::with_capacity(metadata.len() * 16);
+            for (i, (key, value)) in metadata.iter().enumerate() {
+                let record = [
+                    ptrs[2 + 2 * i],
+                    len(key.as_bytes())?,
+                    ptrs[3 + 2 * i],
+                    len(value.as_bytes())?,
+                ];
+                for word in record {
+                    records.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            let ptr = self
+                .canonical_abi_realloc
+                .call(&mut caller, (0, 0, 4, len(&records)?))?;
+            self.memory.data_mut(&mut caller).store_many(ptr, &records)?;
+            ptr
+        };
+        let id = self.init_guest_request.call(
+            &mut caller,
+            (
+                self_.handle as i32,
+                ptrs[0],
+                len(operation.as_bytes())?,
+                ptrs[1],
+                len(payload)?,
+                records,
+                len(metadata)?,
+            ),
+        )?;
+        Ok(id as u32)
+    }
+
+    pub fn wapc_on_host_response(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+        self_: &Guest,
+        id: u32,
+        code: u32,
+        bytes: &[u8],
+    ) -> Result<(), wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        let ptr = self.store_all(&mut caller, &[bytes])?[0];
+        self.on_host_response.call(
+            &mut caller,
+            (self_.handle as i32, id as i32, code as i32, ptr, len(bytes)?),
+        )
+    }
+
+    pub fn wapc_on_host_error(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+        self_: &Guest,
+        id: u32,
+        bytes: &[u8],
+    ) -> Result<(), wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        let ptr = self.store_all(&mut caller, &[bytes])?[0];
+        self.on_host_error
+            .call(&mut caller, (self_.handle as i32, id as i32, ptr, len(bytes)?))
+    }
+
+    pub fn wapc_cancel(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+        self_: &Guest,
+        id: u32,
+    ) -> Result<(), wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        self.cancel.call(&mut caller, (self_.handle as i32, id as i32))
+    }
+
+    pub fn wapc_stream_chunk(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+        self_: &Guest,
+        id: u32,
+        kind: StreamKind,
+        bytes: &[u8],
+    ) -> Result<(), wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        let ptr = self.store_all(&mut caller, &[bytes])?[0];
+        self.stream_chunk.call(
+            &mut caller,
+            (self_.handle as i32, id as i32, kind as i32, ptr, len(bytes)?),
+        )
+    }
+
+    pub fn wapc_stream_end(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+        self_: &Guest,
+        id: u32,
+    ) -> Result<(), wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        self.stream_end.call(&mut caller, (self_.handle as i32, id as i32))
+    }
+
+    pub fn wapc_stream_error(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+        self_: &Guest,
+        id: u32,
+        bytes: &[u8],
+    ) -> Result<(), wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        let ptr = self.store_all(&mut caller, &[bytes])?[0];
+        self.stream_error
+            .call(&mut caller, (self_.handle as i32, id as i32, ptr, len(bytes)?))
+    }
+
+    pub fn wapc_stream_credit(
+        &self,
+        mut caller: impl wasmtime::AsContextMut<Data = T>,
+        self_: &Guest,
+        id: u32,
+        kind: StreamKind,
+        chunks: u32,
+    ) -> Result<(), wasmtime::Trap> {
+        self.ready(&mut caller)?;
+        self.stream_credit.call(
+            &mut caller,
+            (self_.handle as i32, id as i32, kind as i32, chunks as i32),
+        )
+    }
+
+    /// Drops the host-owned handle to the resource specified.
+    ///
+    /// This runs the guest's destructor for the resource, unless
+    /// the guest still holds indices for it or is poisoned.
+    pub fn drop_guest(
+        &self,
+        mut store: impl wasmtime::AsContextMut<Data = T>,
+        val: Guest,
+    ) -> Result<(), wasmtime::Trap> {
+        let mut store = store.as_context_mut();
+        let state = (self.get_state)(store.data_mut());
+        // A poisoned guest is not trusted to run its destructor;
+        // releasing the bookkeeping is enough. Any other guest
+        // must be ready before the handle is released.
+        let poisoned = state.lifecycle == Lifecycle::Poisoned;
+        if !poisoned {
+            state.check_ready()?;
+        }
+        state.borrowed.remove(&val.handle);
+        state.index_slab.remove(val.handle)?;
+        state.counts.indices -= 1;
+        let wasm = match state.resource_slab.drop(val.resource) {
+            Some(val) => val,
+            None => return Ok(()),
+        };
+        state.counts.resources -= 1;
+        if poisoned {
+            return Ok(());
+        }
+        let dtor = state
+            .dtor
+            .ok_or_else(|| wasmtime::Trap::new("destructor not set yet"))?;
+        dtor.call(&mut store, wasm)
+    }
+
+    fn ready(&self, caller: &mut impl wasmtime::AsContextMut<Data = T>) -> Result<(), wasmtime::Trap> {
+        (self.get_state)(caller.as_context_mut().data_mut()).check_ready()
+    }
+
+    /// Copies `args` into the guest's scratch buffer, returning
+    /// a pointer to each.
+    fn store_all(
+        &self,
+        caller: &mut impl wasmtime::AsContextMut<Data = T>,
+        args: &[&[u8]],
+    ) -> Result<Vec<i32>, wasmtime::Trap> {
+        let total = args.iter().try_fold(0usize, |total, arg| {
+            total
+                .checked_add(arg.len())
+                .ok_or_else(|| wasmtime::Trap::new("arguments too large"))
+        })?;
+        let mut ptr = self.scratch.call(&mut *caller, len_of(total)?)?;
+        let mut ptrs = Vec::with_capacity(args.len());
+        for arg in args {
+            self.memory.data_mut(&mut *caller).store_many(ptr, arg)?;
+            ptrs.push(ptr);
+            ptr = ptr
+                .checked_add(len(arg)?)
+                .ok_or_else(|| wasmtime::Trap::new("scratch buffer out of bounds"))?;
+        }
+        Ok(ptrs)
+    }
+}
+
+/// The length of `list` as the guest sees it.
+fn len<E>(list: &[E]) -> Result<i32, wasmtime::Trap> {
+    len_of(list.len())
+}
+
+fn len_of(len: usize) -> Result<i32, wasmtime::Trap> {
+    i32::try_from(len).map_err(|_| wasmtime::Trap::new("argument too large for the guest"))
+}